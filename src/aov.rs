@@ -0,0 +1,145 @@
+use crate::denoise::Guides;
+use crate::exr::save_exr;
+use crate::graphics::BLACK;
+use crate::graphics::Color;
+use crate::graphics::Image;
+use crate::graphics::ImageFormat;
+use crate::graphics::SaveOptions;
+use crate::scene::HitRecord;
+use crate::scene::SceneObject;
+use crate::tonemap::DisplayTransform;
+use crate::tonemap::ToneMap;
+use crate::tonemap::Transfer;
+use std::io;
+use std::path::Path;
+
+// Arbitrary output variables: images of what the camera rays hit first, rendered along
+// with the colors for compositing and denoising. Pixels average the values of their
+// camera rays, with zero for the rays that miss the scene.
+#[derive(Copy, Clone, PartialEq)]
+pub enum Aov {
+    // Surface color of the material
+    Albedo,
+    // Shading normal, in world space
+    Normal,
+    // Distance from the camera along its viewing direction
+    Depth,
+    // Hit point in world space
+    Position,
+    // Ids of the object and of its material, 0 for the background. Ids aren't averaged,
+    // a pixel takes the one of its first camera ray that hits.
+    ObjectId,
+    MaterialId,
+    // Number of samples taken in the pixel, which varies with adaptive sampling, and the
+    // variance of its color, that is the expected squared error. These come from all the
+    // samples rather than from what they hit.
+    Samples,
+    Variance,
+}
+
+impl Aov {
+    pub const ALL: [Aov; 8] = [
+        Aov::Albedo, Aov::Normal, Aov::Depth, Aov::Position, Aov::ObjectId, Aov::MaterialId, Aov::Samples, Aov::Variance,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Aov::Albedo => "albedo",
+            Aov::Normal => "normal",
+            Aov::Depth => "depth",
+            Aov::Position => "position",
+            Aov::ObjectId => "object_id",
+            Aov::MaterialId => "material_id",
+            Aov::Samples => "samples",
+            Aov::Variance => "variance",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Aov> {
+        Aov::ALL.into_iter().find(|aov| aov.name() == name)
+    }
+
+    // Channel names in multi-channel files, from the red, green and blue of the image.
+    // Single values are in all three, so that they show as gray in other files.
+    pub fn channels(&self) -> &'static [&'static str] {
+        match self {
+            Aov::Albedo | Aov::Variance => &["R", "G", "B"],
+            Aov::Normal | Aov::Position => &["X", "Y", "Z"],
+            Aov::Depth => &["Z"],
+            Aov::ObjectId | Aov::MaterialId => &["id"],
+            Aov::Samples => &["count"],
+        }
+    }
+
+    pub fn is_id(&self) -> bool {
+        matches!(self, Aov::ObjectId | Aov::MaterialId)
+    }
+
+    pub fn is_statistic(&self) -> bool {
+        matches!(self, Aov::Samples | Aov::Variance)
+    }
+
+    // Value for a camera ray that hit the object at the given depth
+    pub fn value(&self, object: &SceneObject, hit_record: &HitRecord, depth: f32) -> Color {
+        let single = |value: f32| Color { red: value, green: value, blue: value };
+        match self {
+            Aov::Albedo => object.material.albedo(hit_record),
+            Aov::Normal => {
+                let normal = object.material.shading_normal(hit_record);
+                Color { red: normal.0, green: normal.1, blue: normal.2 }
+            }
+            Aov::Depth => single(depth),
+            Aov::Position => Color { red: hit_record.hit_point.0, green: hit_record.hit_point.1, blue: hit_record.hit_point.2 },
+            Aov::ObjectId => single(object.id as f32),
+            Aov::MaterialId => single(object.material_id as f32),
+            // Computed by the camera from all the samples of the pixel
+            Aov::Samples | Aov::Variance => BLACK,
+        }
+    }
+
+    // File for the AOV when it isn't written into the image file, with its name before
+    // the extension
+    pub fn file_name(&self, image_file_name: &str) -> String {
+        let path = Path::new(image_file_name);
+        let extension = match path.extension() {
+            Some(extension) => format!("{}.{}", self.name(), extension.to_string_lossy()),
+            None => self.name().to_string(),
+        };
+        path.with_extension(extension).to_string_lossy().into_owned()
+    }
+}
+
+// Result of a render: the image and the requested AOVs
+pub struct Layers {
+    pub image: Image,
+    pub aovs: Vec<(Aov, Image)>,
+}
+
+impl Layers {
+    // The AOVs that guide the denoiser, of those rendered
+    pub fn guides(&self) -> Guides<'_> {
+        let find = |wanted: Aov| self.aovs.iter().find(|(aov, _)| *aov == wanted).map(|(_, image)| image);
+        Guides { albedo: find(Aov::Albedo), normal: find(Aov::Normal), depth: find(Aov::Depth) }
+    }
+
+    // Exr files hold the AOVs as more channels, other formats get a file for each AOV.
+    // Returns the files written.
+    pub fn save(&self, file_name: &str, format: ImageFormat, options: SaveOptions) -> io::Result<Vec<String>> {
+        if format == ImageFormat::Exr {
+            save_exr(&self.image, &self.aovs, file_name, format.bit_depth(options) == 32, options.alpha, options.compress)?;
+            return Ok(vec![file_name.to_string()])
+        }
+        self.image.save(file_name, format, options)?;
+        let mut files = vec![file_name.to_string()];
+        for (aov, image) in &self.aovs {
+            // The values are data rather than something to look at, so they are written
+            // as they are, only the albedo keeping the encoding of colors
+            let transfer = if *aov == Aov::Albedo { options.display.transfer } else { Transfer::Gamma(1.0) };
+            let display = DisplayTransform { exposure_stops: 0.0, auto_exposure: false, tone_map: ToneMap::Clip, transfer };
+            let aov_file_name = aov.file_name(file_name);
+            image.save(&aov_file_name, format, SaveOptions { alpha: false, display, ..options })?;
+            files.push(aov_file_name);
+        }
+        Ok(files)
+    }
+}
@@ -0,0 +1,326 @@
+use crate::graphics::BLACK;
+use crate::graphics::Color;
+use crate::graphics::INV_GAMMA;
+use crate::graphics::Image;
+use crate::tonemap::DisplayTransform;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+const FILE_HEADER_SIZE: usize = 14;
+const INFO_HEADER_SIZE: usize = 40;
+const V5_HEADER_SIZE: usize = 124;
+const CORE_HEADER_SIZE: usize = 12;
+const BI_RGB: u32 = 0;
+const BI_BITFIELDS: u32 = 3;
+const BI_ALPHABITFIELDS: u32 = 6;
+const LCS_SRGB: u32 = 0x73524742;
+// 72 DPI
+const PIXELS_PER_METER: u32 = 2835;
+
+fn set_u16(arr: &mut [u8], ind: usize, value: u16) {
+    arr[ind..ind+2].copy_from_slice(&value.to_le_bytes());
+}
+
+fn set_u32(arr: &mut [u8], ind: usize, value: u32) {
+    arr[ind..ind+4].copy_from_slice(&value.to_le_bytes());
+}
+
+fn get_u16(arr: &[u8], ind: usize) -> u16 {
+    u16::from_le_bytes([arr[ind], arr[ind+1]])
+}
+
+fn get_u32(arr: &[u8], ind: usize) -> u32 {
+    u32::from_le_bytes([arr[ind], arr[ind+1], arr[ind+2], arr[ind+3]])
+}
+
+fn float_to_u8(x: f32) -> u8 {
+    if x < 0.0 {
+        return 0
+    }
+    if x > 1.0 {
+        return 255
+    }
+    (255.0 * x).round() as u8
+}
+
+// Lines are padded to a multiple of 4 bytes
+fn line_bytes(width: usize, bits_per_pixel: usize) -> usize {
+    (width * bits_per_pixel).div_ceil(32) * 4
+}
+
+// Writes 24-bit BGR, or 32-bit BGRA with straight alpha, from the bottom line up unless
+// top_down is set
+pub fn save_bmp(image: &Image, file_name: &str, alpha: bool, top_down: bool, display: &DisplayTransform) -> io::Result<()> {
+    const HEADER_SIZE: usize = FILE_HEADER_SIZE + V5_HEADER_SIZE;
+    let bits_per_pixel = if alpha { 32 } else { 24 };
+    let pixel_bytes = bits_per_pixel / 8;
+    let line_bytes = line_bytes(image.width, bits_per_pixel);
+    let data_size = line_bytes * image.height;
+    let mut header = [0; HEADER_SIZE];
+    header[0] = b'B';
+    header[1] = b'M';
+    set_u32(&mut header, 2, (HEADER_SIZE + data_size) as u32);
+    set_u32(&mut header, 10, HEADER_SIZE as u32);
+    set_u32(&mut header, 14, V5_HEADER_SIZE as u32);
+    set_u32(&mut header, 18, image.width as u32);
+    // A negative height puts the top line first
+    let height = if top_down { -(image.height as i32) } else { image.height as i32 };
+    set_u32(&mut header, 22, height as u32);
+    set_u16(&mut header, 26, 1); // Color planes
+    set_u16(&mut header, 28, bits_per_pixel as u16);
+    set_u32(&mut header, 30, if alpha { BI_BITFIELDS } else { BI_RGB });
+    set_u32(&mut header, 34, data_size as u32);
+    set_u32(&mut header, 38, PIXELS_PER_METER);
+    set_u32(&mut header, 42, PIXELS_PER_METER);
+    if alpha {
+        set_u32(&mut header, 54, 0x00ff0000);
+        set_u32(&mut header, 58, 0x0000ff00);
+        set_u32(&mut header, 62, 0x000000ff);
+        set_u32(&mut header, 66, 0xff000000);
+    }
+    set_u32(&mut header, 70, LCS_SRGB);
+
+    let encode = display.encoder(image);
+    let mut data = vec![0; data_size];
+    for y in 0..image.height {
+        let line = if top_down { image.height - 1 - y } else { y };
+        for x in 0..image.width {
+            let mut color = image.pixel(x, y);
+            let coverage = image.alpha(x, y);
+            if alpha {
+                color = if coverage > 0.0 { color.scale(1.0 / coverage) } else { BLACK };
+            }
+            let pixel = encode(color);
+            let data_ind = line * line_bytes + x * pixel_bytes;
+            data[data_ind] = float_to_u8(pixel.blue);
+            data[data_ind + 1] = float_to_u8(pixel.green);
+            data[data_ind + 2] = float_to_u8(pixel.red);
+            if alpha {
+                data[data_ind + 3] = float_to_u8(coverage);
+            }
+        }
+    }
+    let mut f = File::create(file_name)?;
+    f.write_all(&header)?;
+    f.write_all(&data)?;
+    Ok(())
+}
+
+// Bits of a channel within a pixel value
+#[derive(Copy, Clone)]
+struct ChannelMask {
+    mask: u32,
+    shift: u32,
+    max: f32,
+}
+
+impl ChannelMask {
+    fn new(mask: u32) -> ChannelMask {
+        let shift = if mask == 0 { 0 } else { mask.trailing_zeros() };
+        ChannelMask { mask, shift, max: (mask >> shift) as f32 }
+    }
+
+    fn value(&self, pixel: u32) -> f32 {
+        if self.mask == 0 {
+            return 0.0
+        }
+        ((pixel & self.mask) >> self.shift) as f32 / self.max
+    }
+}
+
+// Reads uncompressed files with palettes of 1, 4 or 8 bits per pixel, or colors in 16,
+// 24 or 32 bits, converting the colors back to linear with colors premultiplied by the
+// alpha like rendered images
+pub fn load_bmp(file_name: &str) -> io::Result<Image> {
+    let mut bytes = vec![];
+    File::open(file_name)?.read_to_end(&mut bytes)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    if bytes.len() < FILE_HEADER_SIZE + CORE_HEADER_SIZE || &bytes[0..2] != b"BM" {
+        return Err(invalid("not a BMP file"))
+    }
+    let data_offset = get_u32(&bytes, 10) as usize;
+    let header_size = get_u32(&bytes, 14) as usize;
+    if bytes.len() < FILE_HEADER_SIZE + header_size {
+        return Err(invalid("truncated header"))
+    }
+
+    let (width, height, bits_per_pixel, compression, palette_entry_size, mut colors_used) = if header_size == CORE_HEADER_SIZE {
+        (get_u16(&bytes, 18) as i64, get_u16(&bytes, 20) as i64, get_u16(&bytes, 24) as usize, BI_RGB, 3, 0)
+    } else if header_size >= INFO_HEADER_SIZE {
+        let width = get_u32(&bytes, 18) as i32 as i64;
+        let height = get_u32(&bytes, 22) as i32 as i64;
+        (width, height, get_u16(&bytes, 28) as usize, get_u32(&bytes, 30), 4, get_u32(&bytes, 46) as usize)
+    } else {
+        return Err(invalid("unknown header"))
+    };
+    let top_down = height < 0;
+    let (width, height) = (width, height.abs());
+    if width <= 0 || height == 0 || width * height > 1 << 28 {
+        return Err(invalid("unsupported image size"))
+    }
+    let (width, height) = (width as usize, height as usize);
+
+    let (mut red, mut green, mut blue, mut alpha) = match bits_per_pixel {
+        16 => (0x7c00, 0x03e0, 0x001f, 0),
+        32 => (0x00ff0000, 0x0000ff00, 0x000000ff, 0),
+        _ => (0, 0, 0, 0),
+    };
+    match compression {
+        BI_RGB => {}
+        BI_BITFIELDS | BI_ALPHABITFIELDS if bits_per_pixel == 16 || bits_per_pixel == 32 => {
+            // Masks are part of newer headers, and follow the older one
+            let masks_at = FILE_HEADER_SIZE + INFO_HEADER_SIZE;
+            let has_alpha_mask = compression == BI_ALPHABITFIELDS || header_size >= INFO_HEADER_SIZE + 16;
+            if bytes.len() < masks_at + 16 {
+                return Err(invalid("truncated header"))
+            }
+            red = get_u32(&bytes, masks_at);
+            green = get_u32(&bytes, masks_at + 4);
+            blue = get_u32(&bytes, masks_at + 8);
+            if has_alpha_mask {
+                alpha = get_u32(&bytes, masks_at + 12);
+            }
+        }
+        _ => return Err(invalid("compressed images are not supported")),
+    }
+    let masks = [red, green, blue, alpha].map(ChannelMask::new);
+
+    let palette = if bits_per_pixel <= 8 {
+        if colors_used == 0 || colors_used > 1 << bits_per_pixel {
+            colors_used = 1 << bits_per_pixel;
+        }
+        let palette_at = FILE_HEADER_SIZE + header_size;
+        // Masks after the older header come before the palette
+        let palette_at = if compression == BI_RGB { palette_at } else { palette_at + 12 };
+        if bytes.len() < palette_at + colors_used * palette_entry_size {
+            return Err(invalid("truncated palette"))
+        }
+        (0..colors_used).map(|i| {
+            let entry = palette_at + i * palette_entry_size;
+            [bytes[entry + 2], bytes[entry + 1], bytes[entry]]
+        }).collect()
+    } else {
+        vec![]
+    };
+    if ![1, 4, 8, 16, 24, 32].contains(&bits_per_pixel) {
+        return Err(invalid("unsupported number of bits per pixel"))
+    }
+
+    let line_bytes = line_bytes(width, bits_per_pixel);
+    if bytes.len() < data_offset + line_bytes * height {
+        return Err(invalid("truncated file"))
+    }
+    let to_linear = |value: f32| value.powf(1.0 / INV_GAMMA);
+    let mut image = Image::new(width, height);
+    for line in 0..height {
+        let start = data_offset + line * line_bytes;
+        let line_data = &bytes[start..start + line_bytes];
+        let (colors, alphas) = (0..width).map(|x| {
+            let (rgb, a) = match bits_per_pixel {
+                1 | 4 | 8 => {
+                    let bit = x * bits_per_pixel;
+                    let byte = line_data[bit / 8];
+                    let index = (byte >> (8 - bits_per_pixel - bit % 8)) as usize & ((1 << bits_per_pixel) - 1);
+                    let [r, g, b] = palette.get(index).copied().unwrap_or([0, 0, 0]);
+                    ([r, g, b].map(|channel| channel as f32 / 255.0), 1.0)
+                }
+                24 => {
+                    let p = &line_data[x * 3..x * 3 + 3];
+                    ([p[2], p[1], p[0]].map(|channel| channel as f32 / 255.0), 1.0)
+                }
+                _ => {
+                    let pixel = if bits_per_pixel == 16 {
+                        get_u16(line_data, x * 2) as u32
+                    } else {
+                        get_u32(line_data, x * 4)
+                    };
+                    let a = if masks[3].mask == 0 { 1.0 } else { masks[3].value(pixel) };
+                    ([masks[0].value(pixel), masks[1].value(pixel), masks[2].value(pixel)], a)
+                }
+            };
+            let color = Color { red: to_linear(rgb[0]), green: to_linear(rgb[1]), blue: to_linear(rgb[2]) };
+            (color.scale(a), a)
+        }).unzip();
+        let y = if top_down { height - 1 - line } else { line };
+        image.set_line(colors, alphas, y);
+    }
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tonemap::ToneMap;
+    use crate::tonemap::Transfer;
+
+    const DISPLAY: DisplayTransform = DisplayTransform {
+        exposure_stops: 0.0,
+        auto_exposure: false,
+        tone_map: ToneMap::Clip,
+        transfer: Transfer::Gamma(INV_GAMMA),
+    };
+
+    // Colors from black to beyond white, and coverages from none to full when the alpha
+    // is saved
+    fn test_image(width: usize, height: usize, alpha: bool) -> Image {
+        let mut image = Image::new(width, height);
+        for y in 0..height {
+            let mut line = vec![];
+            let mut coverages = vec![];
+            for x in 0..width {
+                let channel = |c: usize| ((x * 7 + y * 13 + c * 5) % 17) as f32 / 14.0;
+                let color = Color { red: channel(0), green: channel(1), blue: channel(2) };
+                let coverage = if alpha { ((x + 2 * y) % 5) as f32 / 4.0 } else { 1.0 };
+                line.push(color.scale(coverage));
+                coverages.push(coverage);
+            }
+            image.set_line(line, coverages, y);
+        }
+        image
+    }
+
+    // The 8-bit values that save_bmp writes for the image
+    fn quantized(image: &Image, alpha: bool) -> Vec<[u8; 4]> {
+        let encode = DISPLAY.encoder(image);
+        let mut values = vec![];
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let coverage = image.alpha(x, y);
+                let color = if !alpha {
+                    image.pixel(x, y)
+                } else if coverage > 0.0 {
+                    image.pixel(x, y).scale(1.0 / coverage)
+                } else {
+                    BLACK
+                };
+                let pixel = encode(color);
+                let coverage = if alpha { float_to_u8(coverage) } else { 255 };
+                values.push([float_to_u8(pixel.red), float_to_u8(pixel.green), float_to_u8(pixel.blue), coverage]);
+            }
+        }
+        values
+    }
+
+    #[test]
+    fn round_trip() {
+        let height = 3;
+        for (alpha, top_down) in [(false, false), (true, false), (false, true), (true, true)] {
+            for width in 1..=9 {
+                let path = std::env::temp_dir().join(format!("bmp_round_trip_{}_{}_{}_{}.bmp", std::process::id(), alpha, top_down, width));
+                let path = path.to_str().unwrap();
+                let image = test_image(width, height, alpha);
+                save_bmp(&image, path, alpha, top_down, &DISPLAY).unwrap();
+                // Lines padded to 4 bytes after the headers
+                let pixel_bytes = if alpha { 4 } else { 3 };
+                let size = FILE_HEADER_SIZE + V5_HEADER_SIZE + (width * pixel_bytes).next_multiple_of(4) * height;
+                assert_eq!(std::fs::metadata(path).unwrap().len() as usize, size);
+                let loaded = load_bmp(path).unwrap();
+                std::fs::remove_file(path).unwrap();
+                assert_eq!((loaded.width, loaded.height), (width, height));
+                assert_eq!(quantized(&loaded, alpha), quantized(&image, alpha), "alpha {}, top down {}, width {}", alpha, top_down, width);
+            }
+        }
+    }
+}
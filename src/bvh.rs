@@ -0,0 +1,254 @@
+use crate::geometry::Ray;
+use crate::geometry::Vec3;
+
+#[derive(Copy, Clone)]
+pub struct Aabb {
+    pub min: Vec3,
+    pub max: Vec3,
+}
+
+impl Aabb {
+    pub fn empty() -> Aabb {
+        Aabb {
+            min: Vec3(f32::INFINITY, f32::INFINITY, f32::INFINITY),
+            max: Vec3(f32::NEG_INFINITY, f32::NEG_INFINITY, f32::NEG_INFINITY),
+        }
+    }
+
+    pub fn union(&self, other: &Aabb) -> Aabb {
+        Aabb {
+            min: self.min.min(other.min),
+            max: self.max.max(other.max),
+        }
+    }
+
+    pub fn include(&self, point: Vec3) -> Aabb {
+        Aabb {
+            min: self.min.min(point),
+            max: self.max.max(point),
+        }
+    }
+
+    pub fn centroid(&self) -> Vec3 {
+        0.5 * (self.min + self.max)
+    }
+
+    pub fn surface_area(&self) -> f32 {
+        let d = self.max - self.min;
+        if d.0 < 0.0 || d.1 < 0.0 || d.2 < 0.0 {
+            return 0.0
+        }
+        2.0 * (d.0 * d.1 + d.1 * d.2 + d.2 * d.0)
+    }
+
+    // Slab test, returns the entry distance if the ray crosses the box within [tmin, tmax]
+    pub fn hit(&self, ray: &Ray, inv_direction: Vec3, tmin: f32, tmax: f32) -> Option<f32> {
+        let mut tmin = tmin;
+        let mut tmax = tmax;
+        for axis in 0..3 {
+            let inv = inv_direction.axis(axis);
+            let t0 = (self.min.axis(axis) - ray.origin.axis(axis)) * inv;
+            let t1 = (self.max.axis(axis) - ray.origin.axis(axis)) * inv;
+            let (t0, t1) = if inv < 0.0 { (t1, t0) } else { (t0, t1) };
+            // NaN (0 * inf) compares false, leaving the bound untouched
+            if t0 > tmin {
+                tmin = t0;
+            }
+            if t1 < tmax {
+                tmax = t1;
+            }
+            if tmax < tmin {
+                return None
+            }
+        }
+        Some(tmin)
+    }
+}
+
+enum NodeKind {
+    Leaf { first: usize, count: usize },
+    Interior { left: usize, right: usize },
+}
+
+struct Node {
+    bounds: Aabb,
+    kind: NodeKind,
+}
+
+// Bounding volume hierarchy over a list of primitives, identified by their index
+pub struct Bvh {
+    nodes: Vec<Node>,
+    indices: Vec<usize>,
+}
+
+const SAH_BINS: usize = 12;
+const MAX_LEAF_SIZE: usize = 4;
+const TRAVERSAL_COST: f32 = 1.0;
+const INTERSECTION_COST: f32 = 1.0;
+
+struct BuildItem {
+    index: usize,
+    bounds: Aabb,
+    centroid: Vec3,
+}
+
+impl Bvh {
+    pub fn build(boxes: &[Aabb]) -> Bvh {
+        let mut items: Vec<BuildItem> = boxes.iter().enumerate().map(|(index, bounds)| BuildItem {
+            index,
+            bounds: *bounds,
+            centroid: bounds.centroid(),
+        }).collect();
+        let mut bvh = Bvh {
+            nodes: Vec::with_capacity(2 * boxes.len()),
+            indices: Vec::with_capacity(boxes.len()),
+        };
+        if !items.is_empty() {
+            bvh.build_node(&mut items);
+        }
+        bvh
+    }
+
+    pub fn bounds(&self) -> Aabb {
+        match self.nodes.first() {
+            None => Aabb::empty(),
+            Some(root) => root.bounds,
+        }
+    }
+
+    fn make_leaf(&mut self, items: &[BuildItem], bounds: Aabb) -> usize {
+        let first = self.indices.len();
+        self.indices.extend(items.iter().map(|item| item.index));
+        self.nodes.push(Node { bounds, kind: NodeKind::Leaf { first, count: items.len() } });
+        self.nodes.len() - 1
+    }
+
+    fn build_node(&mut self, items: &mut [BuildItem]) -> usize {
+        let bounds = items.iter().fold(Aabb::empty(), |acc, item| acc.union(&item.bounds));
+        if items.len() <= MAX_LEAF_SIZE {
+            return self.make_leaf(items, bounds)
+        }
+
+        let centroid_bounds = items.iter().fold(Aabb::empty(), |acc, item| acc.include(item.centroid));
+        let extent = centroid_bounds.max - centroid_bounds.min;
+        let axis = if extent.0 > extent.1 && extent.0 > extent.2 { 0 } else if extent.1 > extent.2 { 1 } else { 2 };
+        let axis_min = centroid_bounds.min.axis(axis);
+        let axis_extent = extent.axis(axis);
+        if axis_extent <= 0.0 {
+            // All centroids coincide, no split can separate them
+            return self.make_leaf(items, bounds)
+        }
+
+        let bin_of = |centroid: Vec3| {
+            let bin = ((centroid.axis(axis) - axis_min) / axis_extent * SAH_BINS as f32) as usize;
+            bin.min(SAH_BINS - 1)
+        };
+        let mut bin_bounds = [Aabb::empty(); SAH_BINS];
+        let mut bin_counts = [0usize; SAH_BINS];
+        for item in items.iter() {
+            let bin = bin_of(item.centroid);
+            bin_bounds[bin] = bin_bounds[bin].union(&item.bounds);
+            bin_counts[bin] += 1;
+        }
+
+        // Sweep from the right to get the cost of the right side of each split
+        let mut right_area = [0.0; SAH_BINS];
+        let mut right_count = [0usize; SAH_BINS];
+        let mut acc_bounds = Aabb::empty();
+        let mut acc_count = 0;
+        for bin in (1..SAH_BINS).rev() {
+            acc_bounds = acc_bounds.union(&bin_bounds[bin]);
+            acc_count += bin_counts[bin];
+            right_area[bin] = acc_bounds.surface_area();
+            right_count[bin] = acc_count;
+        }
+
+        let mut best_split = 0;
+        let mut best_cost = f32::INFINITY;
+        let mut acc_bounds = Aabb::empty();
+        let mut acc_count = 0;
+        for split in 1..SAH_BINS {
+            acc_bounds = acc_bounds.union(&bin_bounds[split - 1]);
+            acc_count += bin_counts[split - 1];
+            if acc_count == 0 || right_count[split] == 0 {
+                continue
+            }
+            let cost = acc_bounds.surface_area() * acc_count as f32 + right_area[split] * right_count[split] as f32;
+            if cost < best_cost {
+                best_cost = cost;
+                best_split = split;
+            }
+        }
+
+        let parent_area = bounds.surface_area();
+        let split_cost = TRAVERSAL_COST + INTERSECTION_COST * best_cost / parent_area;
+        let leaf_cost = INTERSECTION_COST * items.len() as f32;
+        if best_split == 0 || (parent_area > 0.0 && split_cost >= leaf_cost && items.len() <= 4 * MAX_LEAF_SIZE) {
+            return self.make_leaf(items, bounds)
+        }
+
+        let mut mid = 0;
+        for i in 0..items.len() {
+            if bin_of(items[i].centroid) < best_split {
+                items.swap(i, mid);
+                mid += 1;
+            }
+        }
+
+        let node = self.nodes.len();
+        self.nodes.push(Node { bounds, kind: NodeKind::Interior { left: 0, right: 0 } });
+        let (left_items, right_items) = items.split_at_mut(mid);
+        let left = self.build_node(left_items);
+        let right = self.build_node(right_items);
+        self.nodes[node].kind = NodeKind::Interior { left, right };
+        node
+    }
+
+    // Visits the primitives the ray may hit, nearest nodes first. The callback gets the
+    // primitive index and the current maximal distance, and returns the distance of a
+    // closer hit if it found one.
+    pub fn traverse(&self, ray: &Ray, tmin: f32, tmax: f32, mut hit: impl FnMut(usize, f32) -> Option<f32>) {
+        if self.nodes.is_empty() {
+            return
+        }
+        let inv_direction = Vec3(1.0 / ray.direction.0, 1.0 / ray.direction.1, 1.0 / ray.direction.2);
+        let mut tmax = tmax;
+        let mut stack: Vec<(usize, f32)> = Vec::with_capacity(64);
+        if let Some(t) = self.nodes[0].bounds.hit(ray, inv_direction, tmin, tmax) {
+            stack.push((0, t));
+        }
+        while let Some((node, entry)) = stack.pop() {
+            if entry > tmax {
+                continue
+            }
+            match self.nodes[node].kind {
+                NodeKind::Leaf { first, count } => {
+                    for &index in &self.indices[first..first + count] {
+                        if let Some(t) = hit(index, tmax) {
+                            tmax = t;
+                        }
+                    }
+                }
+                NodeKind::Interior { left, right } => {
+                    let left_hit = self.nodes[left].bounds.hit(ray, inv_direction, tmin, tmax);
+                    let right_hit = self.nodes[right].bounds.hit(ray, inv_direction, tmin, tmax);
+                    match (left_hit, right_hit) {
+                        (Some(tl), Some(tr)) => {
+                            // Push the farther child first so the nearer one is popped next
+                            if tl < tr {
+                                stack.push((right, tr));
+                                stack.push((left, tl));
+                            } else {
+                                stack.push((left, tl));
+                                stack.push((right, tr));
+                            }
+                        }
+                        (Some(tl), None) => stack.push((left, tl)),
+                        (None, Some(tr)) => stack.push((right, tr)),
+                        (None, None) => {}
+                    }
+                }
+            }
+        }
+    }
+}
@@ -1,17 +1,37 @@
+use crate::aov::Aov;
+use crate::aov::Layers;
+use crate::checkpoint::Checkpoint;
+use crate::checkpoint::Checkpointing;
+use crate::distributed::Worker;
 use crate::geometry::Vec3;
 use crate::geometry::Ray;
 use crate::geometry::cross_product;
+use crate::geometry::degrees_to_radians;
 use crate::geometry::dot;
-use crate::geometry::random_in_unit_circle;
+use crate::geometry::point_in_unit_circle;
 use crate::graphics::Color;
 use crate::graphics::BLACK;
 use crate::graphics::Image;
+use crate::progress::CancelToken;
+use crate::progress::Progress;
+use crate::progress::RenderObserver;
+use crate::progress::RenderSummary;
+use crate::progress::Silent;
+use crate::sampler::Sampler;
+use crate::sampler::SamplerKind;
+use crate::scene::HitRecord;
 use crate::scene::Scene;
-use std::io::Write;
-use std::sync::{Arc, Mutex, mpsc};
+use crate::scene::SceneObject;
+use crate::tiles::Tile;
+use crate::tiles::TileOrder;
+use crate::tiles::split_into_tiles;
+use crate::tonemap::luminance;
+use std::cell::Cell;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
 use std::thread;
+use std::time::Duration;
 use std::time::Instant;
-use num_cpus;
 
 #[derive(Copy, Clone)]
 pub struct Bearings {
@@ -30,18 +50,223 @@ pub struct ImageSettings {
 
 #[derive(Copy, Clone)]
 pub struct RenderSettings {
+    // The most samples per pixel when sampling adaptively or progressively
     pub samples_per_pixel: usize,
+    // Stop sampling a pixel once the standard error of its color is below this fraction
+    // of its brightness, if given
+    pub adaptive_threshold: Option<f32>,
+    // Samples taken in every pixel before checking whether it is done
+    pub min_samples_per_pixel: usize,
+    // Progressive renders take passes over the whole image, each with as many samples
+    // as all the ones before, until they reach samples_per_pixel or the time budget or
+    // the target noise below. They can show the image after each pass.
+    pub progressive: bool,
+    // Wall-clock time of a progressive render, in seconds
+    pub time_budget: Option<f64>,
+    // Relative standard error of the pixels, on average over the image, at which a
+    // progressive render stops
+    pub target_noise: Option<f32>,
     pub max_depth: usize,
+    // Zero to use all the CPUs
+    pub threads: usize,
+    // Side of the square tiles that the threads render, in pixels
+    pub tile_size: usize,
+    pub tile_order: TileOrder,
+    // Renders with the same seed and settings give the same image whatever the number of
+    // threads, a random seed is picked if missing
+    pub seed: Option<u64>,
+    // Where the random numbers of the samples come from
+    pub sampler: SamplerKind,
+    // Sample the lights directly at each bounce, instead of only finding them by chance
+    pub light_sampling: bool,
+    // Camera rays that miss the scene are black and uncovered instead of showing the sky
+    pub transparent_background: bool,
 }
 
-fn degrees_to_radians(x: f32) -> f32 {
-    x * std::f32::consts::TAU / 360.0
+// Settings of scene files that don't give them
+impl Default for RenderSettings {
+    fn default() -> RenderSettings {
+        RenderSettings {
+            samples_per_pixel: 100,
+            adaptive_threshold: None,
+            min_samples_per_pixel: 16,
+            progressive: false,
+            time_budget: None,
+            target_noise: None,
+            max_depth: 50,
+            threads: 0,
+            tile_size: 16,
+            tile_order: TileOrder::Hilbert,
+            seed: None,
+            sampler: SamplerKind::Independent,
+            light_sampling: true,
+            transparent_background: false,
+        }
+    }
+}
+
+// How a render is followed and controlled, and where it saves its state and renders
+// its tiles
+pub struct RenderOptions<'a> {
+    pub observer: &'a dyn RenderObserver,
+    pub cancel: CancelToken,
+    pub checkpointing: Option<Checkpointing>,
+    // Render the tiles instead of the threads of this process when given
+    pub workers: Vec<Worker>,
+}
+
+impl Default for RenderOptions<'_> {
+    fn default() -> Self {
+        RenderOptions {
+            observer: &Silent,
+            cancel: CancelToken::new(),
+            checkpointing: None,
+            workers: vec![],
+        }
+    }
+}
+
+// Brightness below which pixels count as this bright when checking their error, so that
+// the noise of dark pixels doesn't need to be vanishingly small
+const ADAPTIVE_MIN_BRIGHTNESS: f32 = 0.01;
+
+// Running mean and variance of the samples of a pixel, with Welford's algorithm
+#[derive(Clone)]
+pub struct PixelStatistics {
+    pub count: usize,
+    pub sum: Color,
+    pub mean: Color,
+    pub squared_deviations: Color,
+}
+
+impl PixelStatistics {
+    fn new() -> PixelStatistics {
+        PixelStatistics { count: 0, sum: BLACK, mean: BLACK, squared_deviations: BLACK }
+    }
+
+    fn add(&mut self, color: Color) {
+        self.count += 1;
+        self.sum = self.sum.add(color);
+        let deviation = color.add(self.mean.scale(-1.0));
+        self.mean = self.mean.add(deviation.scale(1.0 / self.count as f32));
+        self.squared_deviations = self.squared_deviations.add(deviation.attenuate(color.add(self.mean.scale(-1.0))));
+    }
+
+    // The average of the samples, summed rather than updated so that it doesn't drift
+    fn mean(&self) -> Color {
+        if self.count == 0 {
+            return BLACK
+        }
+        let count = self.count as f32;
+        Color { red: self.sum.red / count, green: self.sum.green / count, blue: self.sum.blue / count }
+    }
+
+    // Standard error relative to the brightness
+    fn relative_error(&self) -> f32 {
+        let error = luminance(self.error_variance()).max(0.0).sqrt();
+        error / luminance(self.mean()).max(ADAPTIVE_MIN_BRIGHTNESS)
+    }
+
+    // Variance of the mean, the expected squared error of the pixel
+    fn error_variance(&self) -> Color {
+        if self.count < 2 {
+            return BLACK
+        }
+        self.squared_deviations.scale(1.0 / ((self.count - 1) * self.count) as f32)
+    }
+}
+
+// Samples of a pixel gathered so far, over the passes of the render
+#[derive(Clone)]
+pub struct PixelAccumulator {
+    pub statistics: PixelStatistics,
+    // Camera rays that hit the scene
+    pub hits: usize,
+    // Sums of the AOVs that are averaged, values of the others
+    pub aovs: Vec<Color>,
+}
+
+impl PixelAccumulator {
+    fn new(aov_count: usize) -> PixelAccumulator {
+        PixelAccumulator { statistics: PixelStatistics::new(), hits: 0, aovs: vec![BLACK; aov_count] }
+    }
+
+    // Fraction of the camera rays that hit the scene
+    fn coverage(&self) -> f32 {
+        if self.statistics.count == 0 {
+            return 0.0
+        }
+        self.hits as f32 / self.statistics.count as f32
+    }
+
+    fn aov_value(&self, aov: Aov, index: usize) -> Color {
+        let samples = self.statistics.count;
+        match aov {
+            Aov::Samples => Color { red: samples as f32, green: samples as f32, blue: samples as f32 },
+            Aov::Variance => self.statistics.error_variance(),
+            _ if aov.is_id() || samples == 0 => self.aovs[index],
+            _ => self.aovs[index].scale(1.0 / samples as f32),
+        }
+    }
+}
+
+thread_local! {
+    // Rays traced by the thread since it last finished a tile
+    static RAYS_TRACED: Cell<u64> = const { Cell::new(0) };
+}
+
+fn trace_ray<'a>(scene: &'a Scene, ray: &Ray, tmin: f32, tmax: f32, sampler: &mut dyn Sampler) -> Option<(&'a SceneObject, HitRecord)> {
+    RAYS_TRACED.with(|rays| rays.set(rays.get() + 1));
+    scene.first_hit(ray, tmin, tmax, sampler)
+}
+
+// Work of a render pass: the number of samples each pixel must have at its end, on the
+// tiles that need more. The threads, or the workers, take the next tile and count the
+// finished ones with atomic counters, and each tile has its own pixels, so they don't
+// wait for each other.
+struct Pass<'a> {
+    number: usize,
+    start_time: Instant,
+    render_start_time: Instant,
+    // Rays traced since the start of the render
+    rays: &'a AtomicU64,
+    observer: &'a dyn RenderObserver,
+    cancel: &'a CancelToken,
+    tiles: &'a [Tile],
+    // The pixels of each tile
+    pixels: &'a [Mutex<Vec<PixelAccumulator>>],
+    targets: &'a [Vec<usize>],
+    // Indices of the tiles to render
+    active_tiles: Vec<usize>,
+    next_tile: AtomicUsize,
+    completed_tiles: AtomicUsize,
+    checkpointing: Option<&'a Checkpointing>,
+    // When a checkpoint was last saved, locked by the thread saving one
+    last_checkpoint: &'a Mutex<Instant>,
+}
+
+impl Pass<'_> {
+    fn next_tile(&self) -> Option<usize> {
+        self.active_tiles.get(self.next_tile.fetch_add(1, Ordering::Relaxed)).copied()
+    }
+
+    fn tile_targets(&self, index: usize) -> Vec<usize> {
+        self.tiles[index].pixels().map(|(x, y)| self.targets[y][x]).collect()
+    }
+}
+
+// Weight of a sample from one of two sampling strategies, given the densities of both
+fn power_heuristic(pdf: f32, other_pdf: f32) -> f32 {
+    let (a, b) = (pdf * pdf, other_pdf * other_pdf);
+    if a + b == 0.0 { 0.0 } else { a / (a + b) }
 }
 
 #[derive(Copy, Clone)]
 pub struct Camera {
     position: Vec3,
     lookat: Vec3,
+    // Unit vector the camera looks along
+    direction: Vec3,
     right_vector: Vec3,
     up_vector: Vec3,
     defocus_disk_right_vector: Vec3,
@@ -49,12 +274,19 @@ pub struct Camera {
     image_width: usize,
     image_height: usize,
     samples_per_pixel: usize,
+    adaptive_threshold: Option<f32>,
+    min_samples_per_pixel: usize,
+    progressive: bool,
+    time_budget: Option<Duration>,
+    target_noise: Option<f32>,
     max_depth: usize,
-}
-
-struct LineRenderingResult {
-    y: usize,
-    line: Vec<Color>,
+    threads: usize,
+    tile_size: usize,
+    tile_order: TileOrder,
+    seed: u64,
+    sampler: SamplerKind,
+    light_sampling: bool,
+    transparent_background: bool,
 }
 
 impl Camera {
@@ -70,6 +302,7 @@ impl Camera {
         Camera {
             position: bearings.lookfrom,
             lookat: bearings.lookat,
+            direction,
             right_vector: pixel_size * right_vector,
             up_vector: pixel_size * up_vector,
             defocus_disk_right_vector: defocus_radius * right_vector,
@@ -77,99 +310,528 @@ impl Camera {
             image_width: image_settings.image_width,
             image_height,
             samples_per_pixel: render_settings.samples_per_pixel,
+            adaptive_threshold: render_settings.adaptive_threshold,
+            // The variance needs two samples
+            min_samples_per_pixel: render_settings.min_samples_per_pixel.max(2).min(render_settings.samples_per_pixel),
+            progressive: render_settings.progressive,
+            time_budget: render_settings.time_budget.map(Duration::from_secs_f64),
+            target_noise: render_settings.target_noise,
             max_depth: render_settings.max_depth,
+            threads: if render_settings.threads == 0 { num_cpus::get() } else { render_settings.threads },
+            tile_size: render_settings.tile_size.max(1),
+            tile_order: render_settings.tile_order,
+            seed: render_settings.seed.unwrap_or_else(rand::random),
+            sampler: render_settings.sampler,
+            light_sampling: render_settings.light_sampling,
+            transparent_background: render_settings.transparent_background,
         }
     }
 
-    pub fn render(&self, scene: &Scene) -> Image {
-        let num_threads = num_cpus::get();
-        println!("Rendering on {} threads", num_threads);
-        let start_time = Instant::now();
-        let image = thread::scope(|scope| {
-            let (tx, rx) = mpsc::channel();
-            let line_cnt = Arc::new(Mutex::new(0));
-            for _ in 0..num_threads {
-                let tx = tx.clone();
-                let line_cnt = Arc::clone(&line_cnt);
-                scope.spawn(move || { self.rendering_thread(scene, tx, line_cnt); });
+    pub fn image_size(&self) -> (usize, usize) {
+        (self.image_width, self.image_height)
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Settings of the render that change its result, which a render continuing from a
+    // checkpoint must keep. The scene isn't part of them.
+    pub fn fingerprint(&self, aovs: &[Aov]) -> String {
+        let aov_names: Vec<&str> = aovs.iter().map(|aov| aov.name()).collect();
+        format!("{}x{}, view {:?} {:?} {:?} {:?} {:?} {:?}, {} samples, adaptive {:?}, min {}, progressive {} {:?}, \
+                depth {}, seed {}, sampler {:?}, light sampling {}, transparent {}, aovs {}",
+            self.image_width, self.image_height, self.position, self.lookat, self.right_vector, self.up_vector,
+            self.defocus_disk_right_vector, self.defocus_disk_up_vector, self.samples_per_pixel, self.adaptive_threshold,
+            self.min_samples_per_pixel, self.progressive, self.target_noise, self.max_depth, self.seed, self.sampler,
+            self.light_sampling, self.transparent_background, aov_names.join(","))
+    }
+
+    // Renders until done or cancelled, telling the observer how it goes. With
+    // checkpointing, saves the state of the render now and then, and continues from the
+    // checkpoint to resume, which must have the fingerprint of this render. With workers,
+    // renders the tiles on them rather than on the threads of this process.
+    pub fn render(&self, scene: &Scene, aovs: &[Aov], options: RenderOptions) -> Layers {
+        let RenderOptions { observer, cancel, checkpointing, mut workers } = options;
+        observer.started(self.threads, workers.len());
+        let tiles = split_into_tiles(self.image_width, self.image_height, self.tile_size, self.tile_order);
+        let resume = checkpointing.as_ref().and_then(|checkpointing| checkpointing.resume.as_ref());
+        let pixel = |x: usize, y: usize| match resume {
+            Some(checkpoint) => checkpoint.pixels[y * self.image_width + x].clone(),
+            None => PixelAccumulator::new(aovs.len()),
+        };
+        let pixels: Vec<Mutex<Vec<PixelAccumulator>>> = tiles.iter()
+            .map(|tile| Mutex::new(tile.pixels().map(|(x, y)| pixel(x, y)).collect()))
+            .collect();
+        // The render goes on with the time and rays of the checkpoint
+        let resumed_time = resume.map_or(Duration::ZERO, |checkpoint| checkpoint.elapsed);
+        let start_time = Instant::now().checked_sub(resumed_time).unwrap_or_else(Instant::now);
+        let rays = AtomicU64::new(resume.map_or(0, |checkpoint| checkpoint.rays));
+        let last_checkpoint = Mutex::new(Instant::now());
+        // Adaptive renders first take the fewest samples everywhere, then keep doubling
+        // the samples of the pixels that are still noisy. Progressive ones start with a
+        // quick pass of one sample.
+        let first_pass = if self.adaptive_threshold.is_some() {
+            self.min_samples_per_pixel
+        } else if self.progressive {
+            1
+        } else {
+            self.samples_per_pixel
+        };
+        let (first_number, mut targets) = match resume {
+            Some(checkpoint) => (checkpoint.pass, checkpoint.targets.chunks(self.image_width).map(<[usize]>::to_vec).collect()),
+            None => (1, vec![vec![first_pass; self.image_width]; self.image_height]),
+        };
+        for number in first_number.. {
+            let active_tiles = (0..tiles.len()).filter(|&index| {
+                let tile_pixels = pixels[index].lock().unwrap();
+                tiles[index].pixels().zip(tile_pixels.iter()).any(|((x, y), pixel)| targets[y][x] > pixel.statistics.count)
+            }).collect();
+            let pass = Pass {
+                number,
+                start_time: Instant::now(),
+                render_start_time: start_time,
+                rays: &rays,
+                observer,
+                cancel: &cancel,
+                tiles: &tiles,
+                pixels: &pixels,
+                targets: &targets,
+                active_tiles,
+                next_tile: AtomicUsize::new(0),
+                completed_tiles: AtomicUsize::new(0),
+                checkpointing: checkpointing.as_ref(),
+                last_checkpoint: &last_checkpoint,
+            };
+            self.render_pass(scene, aovs, &pass, &mut workers);
+            if let Some(checkpointing) = &checkpointing {
+                self.save_checkpoint(&pass, aovs, checkpointing);
+            }
+            observer.pass_finished(number, &|| self.layers(&tiles, &pixels, aovs));
+            if (self.adaptive_threshold.is_none() && !self.progressive) || cancel.is_cancelled() {
+                break
+            }
+            if self.progressive && self.target_noise.is_some_and(|target| self.image_noise(&tiles, &pixels) <= target) {
+                break
             }
-            self.collect_to_image(rx)
+            match self.next_targets(&tiles, &pixels, start_time) {
+                Some(next_targets) => targets = next_targets,
+                None => break,
+            }
+        }
+
+        let elapsed = start_time.elapsed();
+        let samples: usize = pixels.iter().map(|tile| tile.lock().unwrap().iter().map(|pixel| pixel.statistics.count).sum::<usize>()).sum();
+        observer.finished(&RenderSummary {
+            elapsed,
+            multipass: self.adaptive_threshold.is_some() || self.progressive,
+            average_samples_per_pixel: samples as f64 / (self.image_width * self.image_height) as f64,
+            rays_per_second: rays.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64(),
+            cancelled: cancel.is_cancelled(),
         });
-        let runtime = start_time.elapsed().as_nanos() as f64 * 1e-9;
-        println!("Finished after {:.1} seconds", runtime);
-        image
+        self.layers(&tiles, &pixels, aovs)
     }
 
-    fn rendering_thread(&self, scene: &Scene, channel: mpsc::Sender<LineRenderingResult>, line_cnt: Arc<Mutex<usize>>) {
-        loop {
-            let mut line_to_run = line_cnt.lock().unwrap();
-            let y = *line_to_run;
-            if y >= self.image_height {
+    // Sample counts of the pixels at the end of the next pass, if it has any samples to
+    // take: the adaptive ones, or twice as many as now for progressive renders, then
+    // fewer if the time budget wouldn't allow them
+    fn next_targets(&self, tiles: &[Tile], pixels: &[Mutex<Vec<PixelAccumulator>>], start_time: Instant) -> Option<Vec<Vec<usize>>> {
+        let mut counts = vec![vec![0; self.image_width]; self.image_height];
+        for (tile, tile_pixels) in tiles.iter().zip(pixels) {
+            for ((x, y), pixel) in tile.pixels().zip(tile_pixels.lock().unwrap().iter()) {
+                counts[y][x] = pixel.statistics.count;
+            }
+        }
+        let mut budgets = if self.adaptive_threshold.is_some() {
+            self.adaptive_budgets(tiles, pixels, &counts)
+        } else {
+            counts.iter().map(|line| line.iter().map(|&count| count.min(self.samples_per_pixel - count)).collect()).collect()
+        };
+        if let Some(time_budget) = self.time_budget {
+            // Assumes the samples to come take as long as the ones so far
+            let elapsed = start_time.elapsed();
+            let samples_so_far: usize = counts.iter().flatten().sum();
+            let planned: usize = budgets.iter().flatten().sum();
+            let affordable = time_budget.saturating_sub(elapsed).as_secs_f64() / elapsed.as_secs_f64() * samples_so_far as f64;
+            if (planned as f64) > affordable {
+                let fraction = affordable / planned as f64;
+                for budget in budgets.iter_mut().flatten() {
+                    *budget = (*budget as f64 * fraction) as usize;
+                }
+            }
+        }
+        if budgets.iter().flatten().all(|&budget| budget == 0) {
+            return None
+        }
+        Some(counts.iter().zip(&budgets).map(|(line, budget_line)| line.iter().zip(budget_line).map(|(count, budget)| count + budget).collect()).collect())
+    }
+
+    // Takes samples in each pixel until it has its target, on all the threads or on the
+    // workers that are still alive
+    fn render_pass(&self, scene: &Scene, aovs: &[Aov], pass: &Pass, workers: &mut Vec<Worker>) {
+        thread::scope(|scope| {
+            if workers.is_empty() {
+                for _ in 0..self.threads {
+                    scope.spawn(|| self.rendering_thread(scene, aovs, pass, None));
+                }
+                return
+            }
+            let threads: Vec<_> = workers.drain(..)
+                .map(|worker| scope.spawn(|| self.worker_thread(scene, aovs, pass, worker)))
+                .collect();
+            workers.extend(threads.into_iter().filter_map(|thread| thread.join().unwrap()));
+        });
+    }
+
+    // Starts with the given tile if any, then takes the next ones
+    fn rendering_thread(&self, scene: &Scene, aovs: &[Aov], pass: &Pass, first_tile: Option<usize>) {
+        let mut sampler = self.sampler.create(self.seed, self.samples_per_pixel);
+        for index in first_tile.into_iter().chain(std::iter::from_fn(|| pass.next_tile())) {
+            if pass.cancel.is_cancelled() {
                 break
             }
-            *line_to_run += 1;
-            drop(line_to_run);
-            let line = self.render_line(scene, y);
-            channel.send(LineRenderingResult{y, line}).unwrap()
+            // Renders a copy of the pixels, so that saving a checkpoint doesn't wait for
+            // the tile
+            let mut tile_pixels = pass.pixels[index].lock().unwrap().clone();
+            self.render_tile(scene, aovs, &pass.tiles[index], &mut tile_pixels, pass.targets, sampler.as_mut());
+            self.tile_finished(pass, aovs, index, tile_pixels, RAYS_TRACED.with(|rays| rays.replace(0)));
         }
     }
 
-    fn collect_to_image(&self, channel: mpsc::Receiver<LineRenderingResult>) -> Image {
-        let mut image = Image::new(self.image_width, self.image_height);
+    // Sends the tiles to the worker, and gives it back unless it died. The tile it was
+    // rendering then, and the next ones, are rendered in this thread instead.
+    fn worker_thread(&self, scene: &Scene, aovs: &[Aov], pass: &Pass, mut worker: Worker) -> Option<Worker> {
+        while let Some(index) = pass.next_tile() {
+            if pass.cancel.is_cancelled() {
+                break
+            }
+            let tile_pixels = pass.pixels[index].lock().unwrap().clone();
+            match worker.render_tile(&pass.tiles[index], &tile_pixels, &pass.tile_targets(index)) {
+                Ok((tile_pixels, rays)) => self.tile_finished(pass, aovs, index, tile_pixels, rays),
+                Err(error) => {
+                    pass.observer.warning(&format!("{} failed, rendering its tiles here instead: {}", worker.name, error));
+                    self.rendering_thread(scene, aovs, pass, Some(index));
+                    return None
+                }
+            }
+        }
+        Some(worker)
+    }
+
+    // Renders a tile for a coordinator on all the threads, and returns the number of rays
+    // traced
+    pub fn render_worker_tile(&self, scene: &Scene, aovs: &[Aov], tile: &Tile, pixels: &mut [PixelAccumulator], targets: &[usize]) -> u64 {
+        let coordinates: Vec<(usize, usize)> = tile.pixels().collect();
+        let chunk_size = pixels.len().div_ceil(self.threads).max(1);
+        let rays = AtomicU64::new(0);
+        thread::scope(|scope| {
+            let chunks = pixels.chunks_mut(chunk_size).zip(coordinates.chunks(chunk_size)).zip(targets.chunks(chunk_size));
+            for ((pixels, coordinates), targets) in chunks {
+                let rays = &rays;
+                scope.spawn(move || {
+                    let mut sampler = self.sampler.create(self.seed, self.samples_per_pixel);
+                    for ((pixel, &(x, y)), &target) in pixels.iter_mut().zip(coordinates).zip(targets) {
+                        self.render_pixel(scene, aovs, (x, y), target.saturating_sub(pixel.statistics.count), pixel, sampler.as_mut());
+                    }
+                    rays.fetch_add(RAYS_TRACED.with(|rays| rays.replace(0)), Ordering::Relaxed);
+                });
+            }
+        });
+        rays.into_inner()
+    }
+
+    // Stores the new pixels of the tile, and saves a checkpoint if it is time
+    fn tile_finished(&self, pass: &Pass, aovs: &[Aov], index: usize, tile_pixels: Vec<PixelAccumulator>, rays: u64) {
+        *pass.pixels[index].lock().unwrap() = tile_pixels;
+        pass.rays.fetch_add(rays, Ordering::Relaxed);
+        let completed = pass.completed_tiles.fetch_add(1, Ordering::Relaxed) + 1;
+        self.report_progress(pass, completed);
+        // Other threads keep rendering while one saves
+        if let (Some(checkpointing), Ok(mut last_checkpoint)) = (pass.checkpointing, pass.last_checkpoint.try_lock()) {
+            if last_checkpoint.elapsed() >= checkpointing.interval {
+                self.save_checkpoint(pass, aovs, checkpointing);
+                *last_checkpoint = Instant::now();
+            }
+        }
+    }
 
-        print!("\rCompleted 0 / {} lines", self.image_height);
-        for line_cnt in 0..self.image_height {
-            let LineRenderingResult{y, line} = channel.recv().unwrap();
-            image.set_line(line, y);
-            print!("\rCompleted {} / {} lines", line_cnt + 1, self.image_height);
-            std::io::stdout().flush().unwrap();
+    // Saves the pixels as they are, the tiles being rendered without their new samples,
+    // which the resumed render takes again
+    fn save_checkpoint(&self, pass: &Pass, aovs: &[Aov], checkpointing: &Checkpointing) {
+        let mut pixels = vec![PixelAccumulator::new(aovs.len()); self.image_width * self.image_height];
+        for (tile, tile_pixels) in pass.tiles.iter().zip(pass.pixels) {
+            for ((x, y), pixel) in tile.pixels().zip(tile_pixels.lock().unwrap().iter()) {
+                pixels[y * self.image_width + x] = pixel.clone();
+            }
+        }
+        let checkpoint = Checkpoint {
+            fingerprint: self.fingerprint(aovs),
+            seed: self.seed,
+            pass: pass.number,
+            targets: pass.targets.concat(),
+            elapsed: pass.render_start_time.elapsed(),
+            rays: pass.rays.load(Ordering::Relaxed),
+            width: self.image_width,
+            height: self.image_height,
+            pixels,
+        };
+        if let Err(error) = checkpoint.save(&checkpointing.path) {
+            pass.observer.warning(&format!("can't write the checkpoint {}: {}", checkpointing.path.display(), error));
         }
-        println!();
+    }
+
+    fn report_progress(&self, pass: &Pass, completed: usize) {
+        let tiles = pass.active_tiles.len();
+        let elapsed = pass.render_start_time.elapsed();
+        pass.observer.progress(&Progress {
+            pass: pass.number,
+            multipass: self.adaptive_threshold.is_some() || self.progressive,
+            completed_tiles: completed,
+            tiles,
+            elapsed,
+            remaining: pass.start_time.elapsed().mul_f64((tiles - completed) as f64 / completed as f64),
+            rays_per_second: pass.rays.load(Ordering::Relaxed) as f64 / elapsed.as_secs_f64(),
+        });
+    }
 
-        image
+    // More samples for the pixels whose error is still too large, and for their
+    // neighbors, which may have missed the rare bright light paths that made it large
+    fn adaptive_budgets(&self, tiles: &[Tile], pixels: &[Mutex<Vec<PixelAccumulator>>], counts: &[Vec<usize>]) -> Vec<Vec<usize>> {
+        let (width, height) = (self.image_width, self.image_height);
+        let mut converged = vec![vec![false; width]; height];
+        for (tile, tile_pixels) in tiles.iter().zip(pixels) {
+            for ((x, y), pixel) in tile.pixels().zip(tile_pixels.lock().unwrap().iter()) {
+                converged[y][x] = counts[y][x] >= self.samples_per_pixel || self.converged(&pixel.statistics);
+            }
+        }
+        (0..height).map(|y| (0..width).map(|x| {
+            let count = counts[y][x];
+            let noisy_around = (y.saturating_sub(1)..(y + 2).min(height))
+                .any(|ny| (x.saturating_sub(1)..(x + 2).min(width)).any(|nx| !converged[ny][nx]));
+            if noisy_around { count.min(self.samples_per_pixel - count) } else { 0 }
+        }).collect()).collect()
+    }
+
+    // Whether the pixel is known well enough to stop sampling it
+    fn converged(&self, statistics: &PixelStatistics) -> bool {
+        self.adaptive_threshold.is_some_and(|threshold| statistics.relative_error() <= threshold)
+    }
+
+    // Root mean square of the relative errors of the pixels, once they all have enough
+    // samples for their variance to tell
+    fn image_noise(&self, tiles: &[Tile], pixels: &[Mutex<Vec<PixelAccumulator>>]) -> f32 {
+        let mut sum = 0.0;
+        for tile_pixels in pixels {
+            for pixel in tile_pixels.lock().unwrap().iter() {
+                if pixel.statistics.count < self.min_samples_per_pixel {
+                    return f32::INFINITY
+                }
+                sum += pixel.statistics.relative_error().powi(2) as f64;
+            }
+        }
+        let count: usize = tiles.iter().map(|tile| tile.width * tile.height).sum();
+        (sum / count as f64).sqrt() as f32
+    }
+
+    fn layers(&self, tiles: &[Tile], pixels: &[Mutex<Vec<PixelAccumulator>>], aovs: &[Aov]) -> Layers {
+        let mut image = Image::new(self.image_width, self.image_height);
+        let mut aov_images: Vec<(Aov, Image)> = aovs.iter().map(|aov| (*aov, Image::new(self.image_width, self.image_height))).collect();
+        for (tile, tile_pixels) in tiles.iter().zip(pixels) {
+            for ((x, y), pixel) in tile.pixels().zip(tile_pixels.lock().unwrap().iter()) {
+                image.set_pixel(x, y, pixel.statistics.mean(), pixel.coverage());
+                for (index, (aov, aov_image)) in aov_images.iter_mut().enumerate() {
+                    aov_image.set_pixel(x, y, pixel.aov_value(*aov, index), 1.0);
+                }
+            }
+        }
+        Layers { image, aovs: aov_images }
     }
 
-    fn render_line(&self, scene: &Scene, y: usize) -> Vec<Color> {
-        (0..self.image_width).map(|x| self.render_pixel(scene, x, y)).collect()
+    fn render_tile(&self, scene: &Scene, aovs: &[Aov], tile: &Tile, tile_pixels: &mut [PixelAccumulator], targets: &[Vec<usize>], sampler: &mut dyn Sampler) {
+        for ((x, y), pixel) in tile.pixels().zip(tile_pixels) {
+            let samples = targets[y][x].saturating_sub(pixel.statistics.count);
+            self.render_pixel(scene, aovs, (x, y), samples, pixel, sampler);
+        }
     }
 
-    fn render_pixel(&self, scene: &Scene, x: usize, y: usize) -> Color {
-        Color::average((0..self.samples_per_pixel).map(|_| {
-            let ray = self.sample_ray_for_pixel(x, y);
-            self.ray_color(scene, 0, &ray)
-        }))
+    // Adds the given number of samples to the pixel
+    fn render_pixel(&self, scene: &Scene, aovs: &[Aov], (x, y): (usize, usize), samples: usize, pixel: &mut PixelAccumulator, sampler: &mut dyn Sampler) {
+        let first_index = pixel.statistics.count;
+        for index in first_index..first_index + samples {
+            sampler.start_sample(x, y, index);
+            let ray = self.sample_ray_for_pixel(x, y, sampler);
+            let color = match trace_ray(scene, &ray, 0.001, f32::INFINITY, sampler) {
+                None if self.transparent_background => BLACK,
+                None => (scene.sky)(ray.direction),
+                Some((object, hit_record)) => {
+                    pixel.hits += 1;
+                    let depth = dot(hit_record.hit_point - self.position, self.direction);
+                    for (aov, value) in aovs.iter().zip(pixel.aovs.iter_mut()) {
+                        if aov.is_statistic() {
+                            continue
+                        }
+                        if !aov.is_id() {
+                            *value = value.add(aov.value(object, &hit_record, depth));
+                        } else if pixel.hits == 1 {
+                            *value = aov.value(object, &hit_record, depth);
+                        }
+                    }
+                    self.hit_color(scene, 0, &ray, (object, &hit_record), None, sampler)
+                }
+            };
+            pixel.statistics.add(color);
+        }
     }
 
-    fn sample_ray_for_pixel(&self, x: usize, y: usize) -> Ray {
-        let (fx, fy) = random_in_unit_circle();
+    fn sample_ray_for_pixel(&self, x: usize, y: usize, sampler: &mut dyn Sampler) -> Ray {
+        // The position in the pixel takes the first dimensions of the sample, which are
+        // the most evenly spread
+        let (jitter_x, jitter_y) = sampler.next_2d();
+        let x = x as f32 + jitter_x - 0.5 * self.image_width as f32;
+        let y = y as f32 + jitter_y - 0.5 * self.image_height as f32;
+        let (fx, fy) = point_in_unit_circle(sampler.next_2d());
         let origin = self.position + fx * self.defocus_disk_right_vector + fy * self.defocus_disk_up_vector;
-        let x = x as f32 + rand::random::<f32>() - 0.5 * self.image_width as f32;
-        let y = y as f32 + rand::random::<f32>() - 0.5 * self.image_height as f32;
         let destination = self.lookat + x * self.right_vector + y * self.up_vector;
-        let ray = Ray {
+        Ray {
             origin,
             direction: (destination - origin).normalize(),
-        };
-        ray
+        }
     }
 
-    fn ray_color(&self, scene: &Scene, depth: usize, ray: &Ray) -> Color {
+    // The scatter pdf is the density of the scattering that produced the ray, when the
+    // material has a known BSDF
+    fn ray_color(&self, scene: &Scene, depth: usize, ray: &Ray, scatter_pdf: Option<f32>, sampler: &mut dyn Sampler) -> Color {
         if depth >= self.max_depth {
             return BLACK;
         }
-        match scene.first_hit(ray, 0.001, std::f32::INFINITY) {
+        match trace_ray(scene, ray, 0.001, f32::INFINITY, sampler) {
             None => {
                 (scene.sky)(ray.direction)
             }
-            Some((object, hit_record)) => {
-                match object.material.scatter(ray, &hit_record) {
-                    None => BLACK,
-                    Some((attenuation, scattered_ray)) => {
-                        let scattered_ray_color = self.ray_color(scene, depth + 1, &scattered_ray);
-                        attenuation.attenuate(scattered_ray_color)
-                    }
-                }
+            Some((object, hit_record)) => self.hit_color(scene, depth, ray, (object, &hit_record), scatter_pdf, sampler),
+        }
+    }
+
+    // Light leaving the hit point back along the ray
+    fn hit_color(&self, scene: &Scene, depth: usize, ray: &Ray, (object, hit_record): (&SceneObject, &HitRecord), scatter_pdf: Option<f32>, sampler: &mut dyn Sampler) -> Color {
+        let mut color = object.material.emitted(ray, hit_record);
+        if let Some(scatter_pdf) = scatter_pdf {
+            if !color.is_black() {
+                // The light could also have been reached by sampling it directly
+                let light_pdf = scene.light_pdf(object, ray.origin, ray.direction);
+                color = color.scale(power_heuristic(scatter_pdf, light_pdf));
+            }
+        }
+        if self.light_sampling {
+            color = color.add(self.direct_light(scene, ray, hit_record, object, sampler));
+        }
+        color = color.add(self.punctual_light(scene, ray, hit_record, object, sampler));
+        match object.material.scatter(ray, hit_record, sampler) {
+            None => color,
+            Some((attenuation, scattered_ray)) => {
+                let scattered_pdf = if self.light_sampling {
+                    object.material.evaluate(ray, hit_record, scattered_ray.direction).map(|(_, pdf)| pdf)
+                } else {
+                    None
+                };
+                let scattered_ray_color = self.ray_color(scene, depth + 1, &scattered_ray, scattered_pdf, sampler);
+                color.add(attenuation.attenuate(scattered_ray_color))
+            }
+        }
+    }
+
+    // Light reaching the hit point directly from a light picked at random, for materials
+    // with a known BSDF. Weighed by multiple importance sampling against finding the same
+    // light by scattering.
+    fn direct_light(&self, scene: &Scene, ray: &Ray, hit_record: &HitRecord, object: &SceneObject, sampler: &mut dyn Sampler) -> Color {
+        let Some((light, sample)) = scene.sample_light(hit_record.hit_point, sampler) else {
+            return BLACK
+        };
+        let to_light = sample.hit_record.hit_point - hit_record.hit_point;
+        let distance = to_light.norm();
+        let direction = to_light / distance;
+        let Some((bsdf, scatter_pdf)) = object.material.evaluate(ray, hit_record, direction) else {
+            return BLACK
+        };
+        if bsdf.is_black() {
+            return BLACK
+        }
+        let shadow_ray = Ray { origin: hit_record.hit_point, direction };
+        if trace_ray(scene, &shadow_ray, 0.001, distance * (1.0 - 1e-4), sampler).is_some() {
+            return BLACK
+        }
+        let emitted = light.material.emitted(&shadow_ray, &sample.hit_record);
+        emitted.attenuate(bsdf).scale(power_heuristic(sample.pdf, scatter_pdf) / sample.pdf)
+    }
+
+    // Light reaching the hit point from the lights that rays can't hit, for materials with
+    // a known BSDF. Only diffuse opaque surfaces have one, so polished, transparent and gas
+    // materials get no light from these, and mirrors don't reflect them.
+    fn punctual_light(&self, scene: &Scene, ray: &Ray, hit_record: &HitRecord, object: &SceneObject, sampler: &mut dyn Sampler) -> Color {
+        let mut color = BLACK;
+        for light in scene.lights() {
+            let Some(illumination) = light.illuminate(hit_record.hit_point, sampler) else {
+                continue
+            };
+            let Some((bsdf, _)) = object.material.evaluate(ray, hit_record, illumination.direction) else {
+                // Not a material with a known BSDF, so not for any light
+                return BLACK
+            };
+            if bsdf.is_black() || illumination.light.is_black() {
+                continue
             }
+            let shadow_ray = Ray { origin: hit_record.hit_point, direction: illumination.direction };
+            if trace_ray(scene, &shadow_ray, 0.001, illumination.distance * (1.0 - 1e-4), sampler).is_some() {
+                continue
+            }
+            color = color.add(bsdf.attenuate(illumination.light));
         }
+        color
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::WHITE;
+    use crate::material::DiffuseLight;
+    use crate::material::Opaque;
+    use crate::shapes::Sphere;
+    use std::sync::Arc;
+
+    // A lamp over a diffuse floor, next to a polished ball
+    fn lamp_scene() -> Scene {
+        let mut scene = Scene::new();
+        let gray = Color { red: 0.6, green: 0.6, blue: 0.6 };
+        scene.add_object(Sphere { center: Vec3(0.0, -100.0, 0.0), radius: 100.0 }, Opaque { albedo: Arc::new(gray), polish: 0.0, bump: None });
+        scene.add_object(Sphere { center: Vec3(-0.4, 0.8, 0.0), radius: 0.8 }, Opaque { albedo: Arc::new(gray), polish: 0.5, bump: None });
+        scene.add_object(Sphere { center: Vec3(1.0, 1.4, -0.6), radius: 0.3 }, DiffuseLight { color: Arc::new(WHITE), power: 4.0 });
+        scene
+    }
+
+    fn mean_radiance(light_sampling: bool) -> f32 {
+        let camera = Camera::new(
+            Bearings { lookfrom: Vec3(0.0, 1.5, -4.0), lookat: Vec3(0.0, 0.5, 0.0), up: Vec3(0.0, 1.0, 0.0), fov_degrees: 40.0, defocus_degrees: 0.0 },
+            ImageSettings { image_width: 16, aspect_ratio: 1.0 },
+            RenderSettings { samples_per_pixel: 1024, max_depth: 8, seed: Some(1), light_sampling, ..Default::default() },
+        );
+        let image = camera.render(&lamp_scene(), &[], RenderOptions::default()).image;
+        let mut sum = 0.0;
+        for y in 0..image.height {
+            for x in 0..image.width {
+                let color = image.pixel(x, y);
+                sum += color.red + color.green + color.blue;
+            }
+        }
+        sum / (3 * image.width * image.height) as f32
+    }
+
+    // Sampling the lights only lowers the noise, so both estimate the same image; from
+    // run to run they differ by well under 1%, so allow 2%
+    #[test]
+    fn light_sampling_agrees_with_scattering() {
+        let (sampled, scattered) = (mean_radiance(true), mean_radiance(false));
+        let difference = (sampled - scattered).abs() / scattered;
+        assert!(difference < 0.02, "mean radiance {} with light sampling, {} without", sampled, scattered);
     }
 }
@@ -0,0 +1,163 @@
+use crate::camera::PixelAccumulator;
+use crate::camera::PixelStatistics;
+use crate::graphics::Color;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+const MAGIC: &[u8; 8] = b"RTCKPT01";
+
+// State of an unfinished render, from which it can go on as if it had never stopped.
+// The random numbers of a sample only depend on the seed, the pixel and the index of the
+// sample, so the seed and the sample counts are all the state of the samplers.
+pub struct Checkpoint {
+    // Settings of the render that change its result, which the render continuing from
+    // the checkpoint must have too
+    pub fingerprint: String,
+    pub seed: u64,
+    // Pass being rendered, and the sample count of each pixel at the end of it
+    pub pass: usize,
+    pub targets: Vec<usize>,
+    pub elapsed: Duration,
+    pub rays: u64,
+    pub width: usize,
+    pub height: usize,
+    // Pixels line by line from the bottom
+    pub pixels: Vec<PixelAccumulator>,
+}
+
+// Where and how often a render saves its checkpoints, and the checkpoint it continues
+// from, if any
+pub struct Checkpointing {
+    pub path: PathBuf,
+    pub interval: Duration,
+    pub resume: Option<Checkpoint>,
+}
+
+// Numbers are little-endian, texts are preceded by their length, in this file and in the
+// messages of distributed renders
+pub fn write_u64(writer: &mut impl Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_f32(writer: &mut impl Write, value: f32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_text(writer: &mut impl Write, text: &str) -> io::Result<()> {
+    write_u64(writer, text.len() as u64)?;
+    writer.write_all(text.as_bytes())
+}
+
+fn write_color(writer: &mut impl Write, color: Color) -> io::Result<()> {
+    for value in [color.red, color.green, color.blue] {
+        write_f32(writer, value)?;
+    }
+    Ok(())
+}
+
+pub fn write_pixel(writer: &mut impl Write, pixel: &PixelAccumulator) -> io::Result<()> {
+    let statistics = &pixel.statistics;
+    write_u64(writer, statistics.count as u64)?;
+    write_color(writer, statistics.sum)?;
+    write_color(writer, statistics.mean)?;
+    write_color(writer, statistics.squared_deviations)?;
+    write_u64(writer, pixel.hits as u64)?;
+    for &value in &pixel.aovs {
+        write_color(writer, value)?;
+    }
+    Ok(())
+}
+
+pub fn read_u64(reader: &mut impl Read) -> io::Result<u64> {
+    let mut bytes = [0; 8];
+    reader.read_exact(&mut bytes)?;
+    Ok(u64::from_le_bytes(bytes))
+}
+
+pub fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut bytes = [0; 4];
+    reader.read_exact(&mut bytes)?;
+    Ok(f32::from_le_bytes(bytes))
+}
+
+fn read_color(reader: &mut impl Read) -> io::Result<Color> {
+    Ok(Color { red: read_f32(reader)?, green: read_f32(reader)?, blue: read_f32(reader)? })
+}
+
+pub fn read_text(reader: &mut impl Read) -> io::Result<String> {
+    let length = read_u64(reader)? as usize;
+    if length > 1 << 16 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "text too long"))
+    }
+    let mut bytes = vec![0; length];
+    reader.read_exact(&mut bytes)?;
+    String::from_utf8(bytes).map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid text"))
+}
+
+pub fn read_pixel(reader: &mut impl Read, aov_count: usize) -> io::Result<PixelAccumulator> {
+    let statistics = PixelStatistics {
+        count: read_u64(reader)? as usize,
+        sum: read_color(reader)?,
+        mean: read_color(reader)?,
+        squared_deviations: read_color(reader)?,
+    };
+    let hits = read_u64(reader)? as usize;
+    let aovs = (0..aov_count).map(|_| read_color(reader)).collect::<io::Result<_>>()?;
+    Ok(PixelAccumulator { statistics, hits, aovs })
+}
+
+impl Checkpoint {
+    // Writes a temporary file first and then renames it, so that a crash while writing
+    // leaves the previous checkpoint intact
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let mut temporary_path = path.as_os_str().to_owned();
+        temporary_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temporary_path)?);
+        writer.write_all(MAGIC)?;
+        write_text(&mut writer, &self.fingerprint)?;
+        for value in [self.seed, self.pass as u64, self.elapsed.as_nanos() as u64, self.rays, self.width as u64, self.height as u64] {
+            write_u64(&mut writer, value)?;
+        }
+        let aov_count = self.pixels.first().map_or(0, |pixel| pixel.aovs.len());
+        write_u64(&mut writer, aov_count as u64)?;
+        for (&target, pixel) in self.targets.iter().zip(&self.pixels) {
+            write_u64(&mut writer, target as u64)?;
+            write_pixel(&mut writer, pixel)?;
+        }
+        writer.into_inner()?.sync_all()?;
+        fs::rename(&temporary_path, path)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Checkpoint> {
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        let mut reader = BufReader::new(File::open(path)?);
+        let mut magic = [0; 8];
+        if reader.read_exact(&mut magic).is_err() || &magic != MAGIC {
+            return Err(invalid("not a checkpoint"))
+        }
+        let fingerprint = read_text(&mut reader)?;
+        let seed = read_u64(&mut reader)?;
+        let pass = read_u64(&mut reader)? as usize;
+        let elapsed = Duration::from_nanos(read_u64(&mut reader)?);
+        let rays = read_u64(&mut reader)?;
+        let width = read_u64(&mut reader)? as usize;
+        let height = read_u64(&mut reader)? as usize;
+        let aov_count = read_u64(&mut reader)? as usize;
+        let pixel_count = width.checked_mul(height).ok_or_else(|| invalid("corrupt checkpoint"))?;
+        let mut targets = Vec::new();
+        let mut pixels = Vec::new();
+        for _ in 0..pixel_count {
+            targets.push(read_u64(&mut reader)? as usize);
+            pixels.push(read_pixel(&mut reader, aov_count)?);
+        }
+        Ok(Checkpoint { fingerprint, seed, pass, targets, elapsed, rays, width, height, pixels })
+    }
+}
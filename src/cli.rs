@@ -0,0 +1,415 @@
+use crate::aov::Aov;
+use crate::graphics::INV_GAMMA;
+use crate::graphics::ImageFormat;
+use crate::graphics::SaveOptions;
+use crate::sampler::SamplerKind;
+use crate::tiles::TileOrder;
+use crate::tonemap::DisplayTransform;
+use crate::tonemap::ToneMap;
+use crate::tonemap::Transfer;
+
+pub const USAGE: &str = "\
+Usage: simple-raytracer [OPTIONS] SCENE_FILE
+       simple-raytracer [OPTIONS] --denoise-image IMAGE
+       simple-raytracer --benchmark
+
+Renders the scene described in SCENE_FILE to an image, or denoises a rendered image.
+
+Options:
+  -o, --output PATH       Output image file [default: pic.bmp]
+  -f, --format FORMAT     Output format (bmp, png, ppm, pgm, pfm, exr), by default from the output extension
+      --bit-depth BITS    Bits per color channel: 8 for bmp, 8 or 16 for png, ppm and pgm,
+                          32 for pfm, 16 (half floats) or 32 for exr
+                          [default: 8, 32 for pfm, 16 for exr]
+      --alpha             Make the background transparent and write an alpha channel (bmp, png, exr)
+      --top-down          Store the top line first in bmp images
+      --ascii             Write ppm and pgm images as plain text
+      --no-compression    Write exr images uncompressed
+      --aov NAMES         Also render comma separated layers of what the camera sees first:
+                          albedo, normal, depth, position, object_id, material_id, samples,
+                          variance or all.
+                          Written into exr images, or next to others as NAME.LAYER.EXT
+      --denoise           Denoise the image, guided by albedo, normal and depth layers
+                          rendered along with it
+      --denoise-strength S
+                          How much the denoiser smooths the image [default: 1]
+      --denoise-image IMAGE
+                          Denoise an image file (pfm, bmp, ppm or pgm) instead of rendering
+      --albedo IMAGE      Albedo, normal and depth layers guiding --denoise-image, best
+      --normal IMAGE      rendered with --aov as pfm files
+      --depth IMAGE
+      --exposure STOPS    Brighten or darken the image by a power of two before tone mapping
+      --auto-exposure     Expose the image for its average brightness, before --exposure
+      --tone-map NAME     Mapping of bright colors into the displayable range: clip,
+                          reinhard, reinhard-extended, aces or hable [default: clip]
+      --white-point VALUE Brightness mapped to white by reinhard-extended
+                          [default: the brightest value in the image]
+      --transfer NAME     Encoding of the displayed values: srgb, or a gamma such as 2.2
+                          [default: 2.22]
+  -w, --width PIXELS      Image width, overriding the scene file
+  -a, --aspect RATIO      Aspect ratio such as 1.5 or 16/9, overriding the scene file
+  -s, --samples N         Samples per pixel, overriding the scene file. The most samples
+                          when sampling adaptively
+      --adaptive THRESHOLD
+                          Stop sampling pixels once their standard error is below THRESHOLD
+                          times their brightness, such as 0.02
+      --min-samples N     Samples in every pixel before --adaptive or --target-noise stops
+                          any [default: 16]
+      --progressive       Render passes over the whole image, each doubling the samples,
+                          until --samples, --time-budget or --target-noise is reached
+      --time-budget SECONDS
+                          Render progressively for about this long
+      --target-noise E    Render progressively until the standard error of the pixels is
+                          E times their brightness on average, such as 0.01
+      --snapshots         Save the image after each pass of an adaptive or progressive
+                          render
+  -d, --max-depth N       Maximal number of bounces, overriding the scene file
+      --timeout SECONDS   Stop rendering after this long and save the image rendered so far,
+                          with the unrendered tiles left black
+      --checkpoint FILE   Save the state of the render to FILE after each pass, when stopped
+                          and every --checkpoint-interval seconds
+      --checkpoint-interval SECONDS
+                          Time between checkpoints [default: 60]
+      --resume FILE       Continue the render saved in FILE, which must have the same
+                          scene and settings. Goes on saving checkpoints to FILE unless
+                          --checkpoint is given
+  -j, --threads N         Number of rendering threads [default: number of CPUs]
+      --workers N         Render the tiles on N worker processes started on this machine
+      --connect ADDRESSES Render the tiles on the workers listening at the comma separated
+                          addresses, such as otherhost:7878
+      --worker-timeout SECONDS
+                          Render the tiles of a worker of --connect here when it takes
+                          longer than this to connect or to answer for a tile [default: 300]
+      --serve ADDRESS     Be a worker listening at ADDRESS, such as 0.0.0.0:7878, which
+                          renders the tiles of SCENE_FILE with the settings of the
+                          coordinators connecting to it
+      --worker            Be a worker for the coordinator at the other end of the standard
+                          input and output, as started by --workers
+      --tile-size PIXELS  Side of the square tiles that the threads render, overriding the
+                          scene file [default: 16]
+      --tile-order NAME   Order of the tiles: scanline, spiral or hilbert, overriding the
+                          scene file [default: hilbert]
+      --seed N            Random seed, overriding the scene file. Renders with the same
+                          seed give the same image, whatever the number of threads
+      --sampler NAME      Where the random numbers of the samples come from: independent,
+                          stratified, halton or sobol, overriding the scene file
+                          [default: independent]
+      --no-light-sampling Only find lights by scattering, without sampling them directly
+  -q, --quiet             Don't print progress
+  -v, --verbose           Print the scene and render settings
+  -h, --help              Print this help
+      --benchmark         Compare rendering with and without the BVH on a built-in scene
+";
+
+#[derive(Copy, Clone, PartialEq, PartialOrd)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+// Image to denoise, and the files of the layers guiding the denoiser
+pub struct DenoiseFiles {
+    pub image: String,
+    pub albedo: Option<String>,
+    pub normal: Option<String>,
+    pub depth: Option<String>,
+}
+
+pub struct Options {
+    pub scene_file: String,
+    pub output: String,
+    pub format: ImageFormat,
+    pub save_options: SaveOptions,
+    pub aovs: Vec<Aov>,
+    pub denoise: bool,
+    pub denoise_strength: f32,
+    pub width: Option<usize>,
+    pub aspect_ratio: Option<f32>,
+    pub samples_per_pixel: Option<usize>,
+    pub adaptive_threshold: Option<f32>,
+    pub min_samples_per_pixel: Option<usize>,
+    pub progressive: bool,
+    pub time_budget: Option<f64>,
+    pub target_noise: Option<f32>,
+    pub snapshots: bool,
+    pub max_depth: Option<usize>,
+    pub threads: Option<usize>,
+    pub timeout: Option<f64>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval: f64,
+    pub resume: Option<String>,
+    pub workers: usize,
+    pub connect: Vec<String>,
+    pub worker_timeout: f64,
+    pub tile_size: Option<usize>,
+    pub tile_order: Option<TileOrder>,
+    pub seed: Option<u64>,
+    pub sampler: Option<SamplerKind>,
+    pub no_light_sampling: bool,
+    pub verbosity: Verbosity,
+}
+
+pub enum Command {
+    Help,
+    Benchmark,
+    Render(Options),
+    Denoise(Options, DenoiseFiles),
+    // Rendering tiles for coordinators, connecting to the address if any, otherwise
+    // through the standard input and output
+    Worker(Options, Option<String>),
+}
+
+fn parse_value<T: std::str::FromStr>(option: &str, value: &str) -> Result<T, String> {
+    value.parse().map_err(|_| format!("invalid value '{}' for {}", value, option))
+}
+
+fn parse_positive(option: &str, value: &str) -> Result<usize, String> {
+    match parse_value(option, value)? {
+        0 => Err(format!("{} must be positive", option)),
+        n => Ok(n),
+    }
+}
+
+fn parse_ratio(option: &str, value: &str) -> Result<f32, String> {
+    let ratio = match value.split_once('/') {
+        Some((numerator, denominator)) => parse_value::<f32>(option, numerator)? / parse_value::<f32>(option, denominator)?,
+        None => parse_value(option, value)?,
+    };
+    if ratio.is_finite() && ratio > 0.0 {
+        Ok(ratio)
+    } else {
+        Err(format!("invalid value '{}' for {}", value, option))
+    }
+}
+
+fn parse_tone_map(option: &str, value: &str) -> Result<ToneMap, String> {
+    match value {
+        "clip" => Ok(ToneMap::Clip),
+        "reinhard" => Ok(ToneMap::Reinhard),
+        "reinhard-extended" => Ok(ToneMap::ExtendedReinhard { white: None }),
+        "aces" => Ok(ToneMap::Aces),
+        "hable" => Ok(ToneMap::Hable),
+        _ => Err(format!("invalid value '{}' for {}", value, option)),
+    }
+}
+
+fn parse_aovs(option: &str, value: &str) -> Result<Vec<Aov>, String> {
+    if value == "all" {
+        return Ok(Aov::ALL.to_vec())
+    }
+    value.split(',').map(|name| {
+        Aov::from_name(name).ok_or_else(|| format!("invalid value '{}' for {}", name, option))
+    }).collect()
+}
+
+fn parse_transfer(option: &str, value: &str) -> Result<Transfer, String> {
+    match value {
+        "srgb" => Ok(Transfer::Srgb),
+        _ => Ok(Transfer::Gamma(1.0 / parse_ratio(option, value)?)),
+    }
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    let mut scene_file = None;
+    let mut output = None;
+    let mut format = None;
+    let mut white_point = None;
+    let mut denoise_strength = None;
+    let mut denoise_image = None;
+    let mut checkpoint_interval = None;
+    let mut worker_timeout = None;
+    let mut worker = false;
+    let mut serve = None;
+    let mut guide_files = [None, None, None];
+    let mut options = Options {
+        scene_file: String::new(),
+        output: String::new(),
+        format: ImageFormat::Bmp,
+        save_options: SaveOptions {
+            bit_depth: None,
+            alpha: false,
+            compress: true,
+            top_down: false,
+            ascii: false,
+            display: DisplayTransform {
+                exposure_stops: 0.0,
+                auto_exposure: false,
+                tone_map: ToneMap::Clip,
+                transfer: Transfer::Gamma(INV_GAMMA),
+            },
+        },
+        aovs: vec![],
+        denoise: false,
+        denoise_strength: 1.0,
+        width: None,
+        aspect_ratio: None,
+        samples_per_pixel: None,
+        adaptive_threshold: None,
+        min_samples_per_pixel: None,
+        progressive: false,
+        time_budget: None,
+        target_noise: None,
+        snapshots: false,
+        max_depth: None,
+        threads: None,
+        timeout: None,
+        checkpoint: None,
+        checkpoint_interval: 60.0,
+        resume: None,
+        workers: 0,
+        connect: vec![],
+        worker_timeout: 300.0,
+        tile_size: None,
+        tile_order: None,
+        seed: None,
+        sampler: None,
+        no_light_sampling: false,
+        verbosity: Verbosity::Normal,
+    };
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with('-') || arg == "-" {
+            if scene_file.is_some() {
+                return Err(format!("unexpected argument '{}'", arg))
+            }
+            scene_file = Some(arg.clone());
+            continue
+        }
+
+        // Both "--option value" and "--option=value" are accepted
+        let (option, inline_value) = match arg.split_once('=') {
+            Some((option, value)) if arg.starts_with("--") => (option, Some(value.to_string())),
+            _ => (arg.as_str(), None),
+        };
+        let mut value = || match &inline_value {
+            Some(value) => Ok(value.clone()),
+            None => args.next().cloned().ok_or_else(|| format!("missing value for {}", option)),
+        };
+        match option {
+            "-h" | "--help" => return Ok(Command::Help),
+            "--benchmark" => return Ok(Command::Benchmark),
+            "-o" | "--output" => output = Some(value()?),
+            "-f" | "--format" => {
+                let name = value()?;
+                format = Some(ImageFormat::from_name(&name).ok_or_else(|| format!("unknown format '{}'", name))?);
+            }
+            "--bit-depth" => options.save_options.bit_depth = Some(parse_value(option, &value()?)?),
+            "--alpha" => options.save_options.alpha = true,
+            "--no-compression" => options.save_options.compress = false,
+            "--top-down" => options.save_options.top_down = true,
+            "--ascii" => options.save_options.ascii = true,
+            "--aov" => {
+                for aov in parse_aovs(option, &value()?)? {
+                    if !options.aovs.contains(&aov) {
+                        options.aovs.push(aov);
+                    }
+                }
+            }
+            "--denoise" => options.denoise = true,
+            "--denoise-strength" => denoise_strength = Some(parse_ratio(option, &value()?)?),
+            "--denoise-image" => denoise_image = Some(value()?),
+            "--albedo" => guide_files[0] = Some(value()?),
+            "--normal" => guide_files[1] = Some(value()?),
+            "--depth" => guide_files[2] = Some(value()?),
+            "--exposure" => options.save_options.display.exposure_stops = parse_value(option, &value()?)?,
+            "--auto-exposure" => options.save_options.display.auto_exposure = true,
+            "--tone-map" => options.save_options.display.tone_map = parse_tone_map(option, &value()?)?,
+            "--white-point" => white_point = Some(parse_ratio(option, &value()?)?),
+            "--transfer" => options.save_options.display.transfer = parse_transfer(option, &value()?)?,
+            "-w" | "--width" => options.width = Some(parse_positive(option, &value()?)?),
+            "-a" | "--aspect" => options.aspect_ratio = Some(parse_ratio(option, &value()?)?),
+            "-s" | "--samples" => options.samples_per_pixel = Some(parse_positive(option, &value()?)?),
+            "--adaptive" => options.adaptive_threshold = Some(parse_ratio(option, &value()?)?),
+            "--min-samples" => options.min_samples_per_pixel = Some(parse_positive(option, &value()?)?),
+            "--progressive" => options.progressive = true,
+            "--time-budget" => options.time_budget = Some(parse_ratio(option, &value()?)? as f64),
+            "--target-noise" => options.target_noise = Some(parse_ratio(option, &value()?)?),
+            "--snapshots" => options.snapshots = true,
+            "-d" | "--max-depth" => options.max_depth = Some(parse_positive(option, &value()?)?),
+            "-j" | "--threads" => options.threads = Some(parse_positive(option, &value()?)?),
+            "--timeout" => options.timeout = Some(parse_ratio(option, &value()?)? as f64),
+            "--checkpoint" => options.checkpoint = Some(value()?),
+            "--checkpoint-interval" => checkpoint_interval = Some(parse_ratio(option, &value()?)? as f64),
+            "--resume" => options.resume = Some(value()?),
+            "--workers" => options.workers = parse_positive(option, &value()?)?,
+            "--worker-timeout" => worker_timeout = Some(parse_ratio(option, &value()?)? as f64),
+            "--connect" => options.connect.extend(value()?.split(',').filter(|address| !address.is_empty()).map(str::to_string)),
+            "--serve" => serve = Some(value()?),
+            "--worker" => worker = true,
+            "--tile-size" => options.tile_size = Some(parse_positive(option, &value()?)?),
+            "--tile-order" => {
+                let name = value()?;
+                options.tile_order = Some(TileOrder::from_name(&name).ok_or_else(|| format!("unknown tile order '{}'", name))?);
+            }
+            "--seed" => options.seed = Some(parse_value(option, &value()?)?),
+            "--sampler" => {
+                let name = value()?;
+                options.sampler = Some(SamplerKind::from_name(&name).ok_or_else(|| format!("unknown sampler '{}'", name))?);
+            }
+            "--no-light-sampling" => options.no_light_sampling = true,
+            "-q" | "--quiet" => options.verbosity = Verbosity::Quiet,
+            "-v" | "--verbose" => options.verbosity = Verbosity::Verbose,
+            _ => return Err(format!("unknown option '{}'", option)),
+        }
+    }
+
+    let mut denoise_files = None;
+    if let Some(image) = denoise_image {
+        if let Some(scene_file) = scene_file {
+            return Err(format!("unexpected argument '{}'", scene_file))
+        }
+        let [albedo, normal, depth] = guide_files;
+        denoise_files = Some(DenoiseFiles { image, albedo, normal, depth });
+    } else {
+        if guide_files.iter().any(Option::is_some) {
+            return Err("--albedo, --normal and --depth are only for --denoise-image".to_string())
+        }
+        options.scene_file = scene_file.ok_or("missing scene file")?;
+    }
+    if let Some(strength) = denoise_strength {
+        if !options.denoise && denoise_files.is_none() {
+            return Err("--denoise-strength is only for --denoise or --denoise-image".to_string())
+        }
+        options.denoise_strength = strength;
+    }
+    if let Some(interval) = checkpoint_interval {
+        if options.checkpoint.is_none() && options.resume.is_none() {
+            return Err("--checkpoint-interval is only for --checkpoint or --resume".to_string())
+        }
+        options.checkpoint_interval = interval;
+    }
+    if let Some(timeout) = worker_timeout {
+        if options.connect.is_empty() {
+            return Err("--worker-timeout is only for --connect".to_string())
+        }
+        options.worker_timeout = timeout;
+    }
+    options.output = output.unwrap_or_else(|| "pic.bmp".to_string());
+    options.format = match format {
+        Some(format) => format,
+        None => ImageFormat::from_file_name(&options.output)
+            .ok_or_else(|| format!("can't tell the image format of '{}', use --format", options.output))?,
+    };
+    options.format.supports(options.save_options)?;
+    if let Some(white) = white_point {
+        if options.save_options.display.tone_map != (ToneMap::ExtendedReinhard { white: None }) {
+            return Err("--white-point is only for --tone-map reinhard-extended".to_string())
+        }
+        options.save_options.display.tone_map = ToneMap::ExtendedReinhard { white: Some(white) };
+    }
+    if worker && serve.is_some() {
+        return Err("--worker and --serve can't be combined".to_string())
+    }
+    if worker || serve.is_some() {
+        if denoise_files.is_some() {
+            return Err("--denoise-image can't be combined with --worker or --serve".to_string())
+        }
+        return Ok(Command::Worker(options, serve))
+    }
+    match denoise_files {
+        Some(files) => Ok(Command::Denoise(options, files)),
+        None => Ok(Command::Render(options)),
+    }
+}
@@ -0,0 +1,179 @@
+use crate::graphics::BLACK;
+use crate::graphics::Color;
+use crate::graphics::Image;
+use std::thread;
+
+// Images of the first hits that tell the edges of the scene from noise, any of which may
+// be missing
+#[derive(Copy, Clone)]
+pub struct Guides<'a> {
+    pub albedo: Option<&'a Image>,
+    pub normal: Option<&'a Image>,
+    pub depth: Option<&'a Image>,
+}
+
+#[derive(Copy, Clone)]
+pub struct DenoiseSettings {
+    // How different neighbors may be and still count as similar, 1 by default
+    pub strength: f32,
+    // Zero to use all the CPUs
+    pub threads: usize,
+}
+
+// Pixels are averaged with the similar ones up to this far away
+const SEARCH_RADIUS: i64 = 7;
+// Half the size of the patches compared for telling how similar two pixels are
+const PATCH_RADIUS: i64 = 1;
+// Relative difference of patches that is put down to noise at a strength of 1
+const NOISE_LEVEL: f32 = 0.4;
+// Spread of the guides within which pixels are on the same surface
+const ALBEDO_SIGMA: f32 = 0.1;
+const NORMAL_SIGMA: f32 = 0.25;
+// Relative to the depth of the pixel
+const DEPTH_SIGMA: f32 = 0.02;
+// Albedo below which the colors aren't divided by it
+const MIN_ALBEDO: f32 = 0.01;
+
+fn distance2(a: Color, b: Color) -> f32 {
+    let (red, green, blue) = (a.red - b.red, a.green - b.green, a.blue - b.blue);
+    red * red + green * green + blue * blue
+}
+
+fn demodulate(value: f32, albedo: f32) -> f32 {
+    if albedo < MIN_ALBEDO { value } else { value / albedo }
+}
+
+fn remodulate(value: f32, albedo: f32) -> f32 {
+    if albedo < MIN_ALBEDO { value } else { value * albedo }
+}
+
+// Pixels of an image padded with copies of its border, as a flat grid
+struct Grid {
+    width: usize,
+    height: usize,
+    values: Vec<Color>,
+}
+
+impl Grid {
+    fn new(width: usize, height: usize, value: impl Fn(usize, usize) -> Color) -> Grid {
+        let values = (0..height).flat_map(|y| (0..width).map(move |x| (x, y))).map(|(x, y)| value(x, y)).collect();
+        Grid { width, height, values }
+    }
+
+    fn at(&self, x: i64, y: i64) -> Color {
+        let x = x.clamp(0, self.width as i64 - 1) as usize;
+        let y = y.clamp(0, self.height as i64 - 1) as usize;
+        self.values[y * self.width + x]
+    }
+
+    // Clamps each pixel to the brightest of its neighbors, taking out the lone bright
+    // pixels of rare light paths, which would otherwise spread into blotches
+    fn without_fireflies(&self) -> Grid {
+        Grid::new(self.width, self.height, |x, y| {
+            let (x, y) = (x as i64, y as i64);
+            let mut brightest = BLACK;
+            for (dx, dy) in [(-1, -1), (0, -1), (1, -1), (-1, 0), (1, 0), (-1, 1), (0, 1), (1, 1)] {
+                let neighbor = self.at(x + dx, y + dy);
+                brightest = Color {
+                    red: brightest.red.max(neighbor.red),
+                    green: brightest.green.max(neighbor.green),
+                    blue: brightest.blue.max(neighbor.blue),
+                };
+            }
+            let color = self.at(x, y);
+            Color {
+                red: color.red.min(brightest.red),
+                green: color.green.min(brightest.green),
+                blue: color.blue.min(brightest.blue),
+            }
+        })
+    }
+}
+
+// Joint non-local means: each pixel becomes a weighted average of its neighbors, weighing
+// more those whose surrounding patch looks the same and which the guides show to be on
+// the same surface. The colors are divided by the albedo while filtering, so that the
+// texture of the surfaces isn't blurred with the noise of the lighting.
+pub fn denoise(image: &Image, guides: Guides, settings: &DenoiseSettings) -> Image {
+    let (width, height) = (image.width, image.height);
+    let albedo = |x: usize, y: usize| guides.albedo.map_or(BLACK, |albedo| albedo.pixel(x, y));
+    let lighting = Grid::new(width, height, |x, y| {
+        let (color, albedo) = (image.pixel(x, y), albedo(x, y));
+        Color {
+            red: demodulate(color.red, albedo.red),
+            green: demodulate(color.green, albedo.green),
+            blue: demodulate(color.blue, albedo.blue),
+        }
+    }).without_fireflies();
+    let albedo_grid = guides.albedo.map(|albedo| Grid::new(width, height, |x, y| albedo.pixel(x, y)));
+    let normal_grid = guides.normal.map(|normal| Grid::new(width, height, |x, y| normal.pixel(x, y)));
+    let depth_grid = guides.depth.map(|depth| Grid::new(width, height, |x, y| depth.pixel(x, y)));
+
+    let patch_size = ((2 * PATCH_RADIUS + 1) * (2 * PATCH_RADIUS + 1)) as f32;
+    let noise2 = (NOISE_LEVEL * settings.strength).powi(2);
+    let filter_pixel = |x: i64, y: i64| -> Color {
+        let mut sum = BLACK;
+        let mut total_weight = 0.0;
+        for dy in -SEARCH_RADIUS..=SEARCH_RADIUS {
+            for dx in -SEARCH_RADIUS..=SEARCH_RADIUS {
+                let (nx, ny) = (x + dx, y + dy);
+                if nx < 0 || ny < 0 || nx >= width as i64 || ny >= height as i64 {
+                    continue
+                }
+                // Distance between the patches relative to their brightness, so that the
+                // same noise counts the same in dark and bright parts
+                let mut patch_distance = 0.0;
+                for py in -PATCH_RADIUS..=PATCH_RADIUS {
+                    for px in -PATCH_RADIUS..=PATCH_RADIUS {
+                        let (a, b) = (lighting.at(x + px, y + py), lighting.at(nx + px, ny + py));
+                        patch_distance += distance2(a, b) / (1e-4 + distance2(a, BLACK) + distance2(b, BLACK));
+                    }
+                }
+                let mut exponent = patch_distance / (patch_size * noise2);
+                if let Some(albedo) = &albedo_grid {
+                    exponent += distance2(albedo.at(x, y), albedo.at(nx, ny)) / (ALBEDO_SIGMA * ALBEDO_SIGMA);
+                }
+                if let Some(normal) = &normal_grid {
+                    exponent += distance2(normal.at(x, y), normal.at(nx, ny)) / (NORMAL_SIGMA * NORMAL_SIGMA);
+                }
+                if let Some(depth) = &depth_grid {
+                    let (z, neighbor_z) = (depth.at(x, y).red, depth.at(nx, ny).red);
+                    let relative = (z - neighbor_z) / (DEPTH_SIGMA * z.abs().max(1e-3));
+                    exponent += relative * relative;
+                }
+                let weight = (-exponent).exp();
+                sum = sum.add(lighting.at(nx, ny).scale(weight));
+                total_weight += weight;
+            }
+        }
+        sum.scale(1.0 / total_weight)
+    };
+
+    let threads = if settings.threads == 0 { num_cpus::get() } else { settings.threads };
+    let lines: Vec<(usize, Vec<Color>)> = thread::scope(|scope| {
+        let workers: Vec<_> = (0..threads).map(|thread| {
+            let filter_pixel = &filter_pixel;
+            let albedo = &albedo;
+            scope.spawn(move || {
+                (thread..height).step_by(threads).map(|y| {
+                    let line = (0..width).map(|x| {
+                        let (lighting, albedo) = (filter_pixel(x as i64, y as i64), albedo(x, y));
+                        Color {
+                            red: remodulate(lighting.red, albedo.red),
+                            green: remodulate(lighting.green, albedo.green),
+                            blue: remodulate(lighting.blue, albedo.blue),
+                        }
+                    }).collect();
+                    (y, line)
+                }).collect::<Vec<_>>()
+            })
+        }).collect();
+        workers.into_iter().flat_map(|worker| worker.join().unwrap()).collect()
+    });
+
+    let mut denoised = Image::new(width, height);
+    for (y, line) in lines {
+        denoised.set_line(line, (0..width).map(|x| image.alpha(x, y)).collect(), y);
+    }
+    denoised
+}
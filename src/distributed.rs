@@ -0,0 +1,256 @@
+// Rendering on worker processes, on this machine or others. The coordinator renders the
+// passes as usual, but sends the tiles to the workers instead of rendering them itself.
+// Each worker loads the scene from its own scene file, and gets the settings of the render
+// from the coordinator:
+//
+//   coordinator: hello, image and render settings, seed, AOVs
+//   worker:      fingerprint of its camera, which must be the one of the coordinator
+//   coordinator: tile, and the pixels of the tile with the sample count each must reach
+//   worker:      rays traced, and the pixels with their new samples
+//   ...
+//
+// The coordinator ends the session by closing the connection. The samples of a pixel
+// only depend on the seed and their index, so the image is the same as rendered by the
+// coordinator alone.
+
+use crate::aov::Aov;
+use crate::camera::Camera;
+use crate::camera::ImageSettings;
+use crate::camera::PixelAccumulator;
+use crate::camera::RenderSettings;
+use crate::checkpoint::read_f32;
+use crate::checkpoint::read_pixel;
+use crate::checkpoint::read_text;
+use crate::checkpoint::read_u64;
+use crate::checkpoint::write_f32;
+use crate::checkpoint::write_pixel;
+use crate::checkpoint::write_text;
+use crate::checkpoint::write_u64;
+use crate::sampler::SamplerKind;
+use crate::scene_file::SceneDescription;
+use crate::tiles::Tile;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::process::Child;
+use std::process::Command;
+use std::process::Stdio;
+use std::time::Duration;
+
+const HELLO: &[u8; 8] = b"RTWORK01";
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+// Reading from a worker that died fails at the end of its output, and from a connected
+// worker that hangs when the timeout runs out
+fn stopped_answering(error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof | io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock => {
+            io::Error::new(error.kind(), "it stopped answering")
+        }
+        _ => error,
+    }
+}
+
+fn write_option(writer: &mut impl Write, value: Option<f32>) -> io::Result<()> {
+    write_u64(writer, value.is_some() as u64)?;
+    write_f32(writer, value.unwrap_or(0.0))
+}
+
+fn read_option(reader: &mut impl Read) -> io::Result<Option<f32>> {
+    let present = read_u64(reader)? != 0;
+    let value = read_f32(reader)?;
+    Ok(if present { Some(value) } else { None })
+}
+
+// A worker as seen by the coordinator
+pub struct Worker {
+    pub name: String,
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+    // Worker process started by the coordinator, which it stops when done
+    process: Option<Child>,
+    aov_count: usize,
+}
+
+impl Worker {
+    // Starts this program as a worker on this machine, talking through its standard input
+    // and output
+    pub fn spawn(name: String, scene_file: &str, threads: usize) -> io::Result<Worker> {
+        let mut process = Command::new(std::env::current_exe()?)
+            .arg(scene_file)
+            .arg("--worker")
+            .arg("--threads")
+            .arg(threads.to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = process.stdin.take().unwrap();
+        let output = process.stdout.take().unwrap();
+        Ok(Worker {
+            name,
+            reader: BufReader::new(Box::new(output)),
+            writer: BufWriter::new(Box::new(input)),
+            process: Some(process),
+            aov_count: 0,
+        })
+    }
+
+    // Connects to a worker started with --serve, which must connect and then answer each
+    // message within the timeout
+    pub fn connect(name: String, address: &str, timeout: Duration) -> io::Result<Worker> {
+        let mut stream = Err(io::Error::new(io::ErrorKind::NotFound, "unknown address"));
+        for address in address.to_socket_addrs()? {
+            stream = TcpStream::connect_timeout(&address, timeout);
+            if stream.is_ok() {
+                break
+            }
+        }
+        let stream = stream?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+        Ok(Worker {
+            name,
+            reader: BufReader::new(Box::new(stream.try_clone()?)),
+            writer: BufWriter::new(Box::new(stream)),
+            process: None,
+            aov_count: 0,
+        })
+    }
+
+    // Gives the worker the settings of the render, and checks that it renders like the
+    // coordinator's camera
+    pub fn start(&mut self, image_settings: &ImageSettings, render_settings: &RenderSettings, aovs: &[Aov], camera: &Camera) -> io::Result<()> {
+        self.say_hello(image_settings, render_settings, aovs, camera).map_err(stopped_answering)
+    }
+
+    fn say_hello(&mut self, image_settings: &ImageSettings, render_settings: &RenderSettings, aovs: &[Aov], camera: &Camera) -> io::Result<()> {
+        let writer = &mut self.writer;
+        writer.write_all(HELLO)?;
+        write_u64(writer, image_settings.image_width as u64)?;
+        write_f32(writer, image_settings.aspect_ratio)?;
+        write_u64(writer, render_settings.samples_per_pixel as u64)?;
+        write_option(writer, render_settings.adaptive_threshold)?;
+        write_u64(writer, render_settings.min_samples_per_pixel as u64)?;
+        write_u64(writer, render_settings.progressive as u64)?;
+        write_option(writer, render_settings.target_noise)?;
+        write_u64(writer, render_settings.max_depth as u64)?;
+        write_u64(writer, camera.seed())?;
+        write_text(writer, render_settings.sampler.name())?;
+        write_u64(writer, render_settings.light_sampling as u64)?;
+        write_u64(writer, render_settings.transparent_background as u64)?;
+        write_u64(writer, aovs.len() as u64)?;
+        for aov in aovs {
+            write_text(writer, aov.name())?;
+        }
+        writer.flush()?;
+        if read_text(&mut self.reader)? != camera.fingerprint(aovs) {
+            return Err(invalid("it has another camera in its scene file"))
+        }
+        self.aov_count = aovs.len();
+        Ok(())
+    }
+
+    // Has the worker take samples in the pixels of the tile until they have as many as
+    // their targets, and returns them with the number of rays traced
+    pub fn render_tile(&mut self, tile: &Tile, pixels: &[PixelAccumulator], targets: &[usize]) -> io::Result<(Vec<PixelAccumulator>, u64)> {
+        self.exchange_tile(tile, pixels, targets).map_err(stopped_answering)
+    }
+
+    fn exchange_tile(&mut self, tile: &Tile, pixels: &[PixelAccumulator], targets: &[usize]) -> io::Result<(Vec<PixelAccumulator>, u64)> {
+        for value in [tile.x, tile.y, tile.width, tile.height] {
+            write_u64(&mut self.writer, value as u64)?;
+        }
+        for (&target, pixel) in targets.iter().zip(pixels) {
+            write_u64(&mut self.writer, target as u64)?;
+            write_pixel(&mut self.writer, pixel)?;
+        }
+        self.writer.flush()?;
+        let rays = read_u64(&mut self.reader)?;
+        let pixels = pixels.iter().map(|_| read_pixel(&mut self.reader, self.aov_count)).collect::<io::Result<_>>()?;
+        Ok((pixels, rays))
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if let Some(process) = &mut self.process {
+            let _ = process.kill();
+            let _ = process.wait();
+        }
+    }
+}
+
+// Reads the settings of the coordinator, on top of the ones of the scene file
+fn read_hello(reader: &mut impl Read, description: &SceneDescription) -> io::Result<(Camera, Vec<Aov>)> {
+    let mut hello = [0; 8];
+    reader.read_exact(&mut hello)?;
+    if &hello != HELLO {
+        return Err(invalid("not a coordinator"))
+    }
+    let image_settings = ImageSettings {
+        image_width: read_u64(reader)? as usize,
+        aspect_ratio: read_f32(reader)?,
+    };
+    let mut render_settings = description.render_settings;
+    render_settings.samples_per_pixel = read_u64(reader)? as usize;
+    render_settings.adaptive_threshold = read_option(reader)?;
+    render_settings.min_samples_per_pixel = read_u64(reader)? as usize;
+    render_settings.progressive = read_u64(reader)? != 0;
+    render_settings.target_noise = read_option(reader)?;
+    render_settings.max_depth = read_u64(reader)? as usize;
+    render_settings.seed = Some(read_u64(reader)?);
+    render_settings.sampler = SamplerKind::from_name(&read_text(reader)?).ok_or_else(|| invalid("unknown sampler"))?;
+    render_settings.light_sampling = read_u64(reader)? != 0;
+    render_settings.transparent_background = read_u64(reader)? != 0;
+    let aov_count = read_u64(reader)? as usize;
+    let aovs = (0..aov_count).map(|_| {
+        Aov::from_name(&read_text(reader)?).ok_or_else(|| invalid("unknown AOV"))
+    }).collect::<io::Result<_>>()?;
+    Ok((Camera::new(description.bearings, image_settings, render_settings), aovs))
+}
+
+// Renders the tiles a coordinator sends until it ends the session
+pub fn serve(description: &SceneDescription, input: impl Read, output: impl Write) -> io::Result<()> {
+    let mut reader = BufReader::new(input);
+    let mut writer = BufWriter::new(output);
+    let (camera, aovs) = read_hello(&mut reader, description)?;
+    write_text(&mut writer, &camera.fingerprint(&aovs))?;
+    writer.flush()?;
+    let (width, height) = camera.image_size();
+    loop {
+        let x = match read_u64(&mut reader) {
+            Ok(x) => x as usize,
+            Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(error) => return Err(error),
+        };
+        let tile = Tile {
+            x,
+            y: read_u64(&mut reader)? as usize,
+            width: read_u64(&mut reader)? as usize,
+            height: read_u64(&mut reader)? as usize,
+        };
+        if tile.x.saturating_add(tile.width) > width || tile.y.saturating_add(tile.height) > height {
+            return Err(invalid("tile outside the image"))
+        }
+        let mut targets = Vec::new();
+        let mut pixels = Vec::new();
+        for _ in 0..tile.width * tile.height {
+            targets.push(read_u64(&mut reader)? as usize);
+            pixels.push(read_pixel(&mut reader, aovs.len())?);
+        }
+        let rays = camera.render_worker_tile(&description.scene, &aovs, &tile, &mut pixels, &targets);
+        write_u64(&mut writer, rays)?;
+        for pixel in &pixels {
+            write_pixel(&mut writer, pixel)?;
+        }
+        writer.flush()?;
+    }
+}
@@ -0,0 +1,276 @@
+use crate::aov::Aov;
+use crate::graphics::Image;
+use crate::zlib;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+const MAGIC: u32 = 20000630;
+const VERSION: u32 = 2;
+const HALF: u32 = 1;
+const FLOAT: u32 = 2;
+const NO_COMPRESSION: u8 = 0;
+const ZIP_COMPRESSION: u8 = 3;
+// Lines compressed together by ZIP compression
+const ZIP_LINES: usize = 16;
+
+// Nearest half precision float, rounding ties to even
+fn to_half(value: f32) -> u16 {
+    let bits = value.to_bits();
+    let sign = ((bits >> 16) & 0x8000) as u16;
+    let exponent = ((bits >> 23) & 0xff) as i32;
+    let mantissa = bits & 0x7fffff;
+    if exponent == 0xff {
+        // Infinity stays infinity, NaN stays NaN
+        let nan = if mantissa != 0 { 0x200 } else { 0 };
+        return sign | 0x7c00 | nan
+    }
+    let half_exponent = exponent - 127 + 15;
+    if half_exponent >= 0x1f {
+        return sign | 0x7c00
+    }
+    if half_exponent <= 0 {
+        // Subnormal, or too small and rounding to zero
+        if half_exponent < -10 {
+            return sign
+        }
+        let mantissa = mantissa | 0x800000;
+        let shift = (14 - half_exponent) as u32;
+        let half_mantissa = mantissa >> shift;
+        let remainder = mantissa & ((1 << shift) - 1);
+        let halfway = 1 << (shift - 1);
+        let round_up = remainder > halfway || (remainder == halfway && half_mantissa & 1 == 1);
+        return sign | (half_mantissa + round_up as u32) as u16
+    }
+    let half = ((half_exponent as u32) << 10) | (mantissa >> 13);
+    let remainder = mantissa & 0x1fff;
+    let round_up = remainder > 0x1000 || (remainder == 0x1000 && half & 1 == 1);
+    // Rounding up may carry into the exponent, up to infinity, which is what we want
+    sign | (half + round_up as u32) as u16
+}
+
+fn attribute(header: &mut Vec<u8>, name: &str, kind: &str, value: &[u8]) {
+    header.extend_from_slice(name.as_bytes());
+    header.push(0);
+    header.extend_from_slice(kind.as_bytes());
+    header.push(0);
+    header.extend_from_slice(&(value.len() as u32).to_le_bytes());
+    header.extend_from_slice(value);
+}
+
+fn box2i(width: usize, height: usize) -> Vec<u8> {
+    [0, 0, width as i32 - 1, height as i32 - 1].iter().flat_map(|value| value.to_le_bytes()).collect()
+}
+
+// Preprocessing of ZIP compression, which helps deflate with the bytes of the values:
+// the even bytes then the odd ones, stored as differences from the byte before them
+fn zip_predictor(data: &[u8]) -> Vec<u8> {
+    let mut reordered: Vec<u8> = data.iter().step_by(2).copied().collect();
+    reordered.extend(data.iter().skip(1).step_by(2));
+    let mut previous = reordered.first().copied().unwrap_or(0);
+    for byte in reordered.iter_mut().skip(1) {
+        let current = *byte;
+        *byte = current.wrapping_sub(previous).wrapping_add(128);
+        previous = current;
+    }
+    reordered
+}
+
+// Channel of the file, taken from the red, green, blue or alpha of an image
+struct Channel<'a> {
+    name: String,
+    image: &'a Image,
+    component: usize,
+}
+
+impl Channel<'_> {
+    fn value(&self, x: usize, y: usize) -> f32 {
+        let pixel = self.image.pixel(x, y);
+        match self.component {
+            0 => pixel.red,
+            1 => pixel.green,
+            2 => pixel.blue,
+            _ => self.image.alpha(x, y),
+        }
+    }
+}
+
+// Writes a scanline OpenEXR file with half or full floats, uncompressed or with ZIP
+// compression. Colors are linear and premultiplied by the alpha, as EXR expects. AOVs
+// are layers of channels named after them, such as "normal.X".
+pub fn save_exr(image: &Image, aovs: &[(Aov, Image)], file_name: &str, full_float: bool, alpha: bool, compress: bool) -> io::Result<()> {
+    let mut channels = vec![];
+    for (component, name) in ["R", "G", "B"].into_iter().enumerate() {
+        channels.push(Channel { name: name.to_string(), image, component });
+    }
+    if alpha {
+        channels.push(Channel { name: "A".to_string(), image, component: 3 });
+    }
+    for (aov, aov_image) in aovs {
+        for (component, name) in aov.channels().iter().enumerate() {
+            channels.push(Channel { name: format!("{}.{}", aov.name(), name), image: aov_image, component });
+        }
+    }
+    // Channels are stored in alphabetical order
+    channels.sort_by(|a, b| a.name.cmp(&b.name));
+    let pixel_type = if full_float { FLOAT } else { HALF };
+
+    let mut channel_list = vec![];
+    for Channel { name, .. } in &channels {
+        channel_list.extend_from_slice(name.as_bytes());
+        channel_list.push(0);
+        channel_list.extend_from_slice(&pixel_type.to_le_bytes());
+        channel_list.extend_from_slice(&[0, 0, 0, 0]); // Linear, reserved
+        channel_list.extend_from_slice(&1i32.to_le_bytes()); // Sampling in x
+        channel_list.extend_from_slice(&1i32.to_le_bytes()); // Sampling in y
+    }
+    channel_list.push(0);
+
+    let mut output = vec![];
+    output.extend_from_slice(&MAGIC.to_le_bytes());
+    output.extend_from_slice(&VERSION.to_le_bytes());
+    attribute(&mut output, "channels", "chlist", &channel_list);
+    attribute(&mut output, "compression", "compression", &[if compress { ZIP_COMPRESSION } else { NO_COMPRESSION }]);
+    attribute(&mut output, "dataWindow", "box2i", &box2i(image.width, image.height));
+    attribute(&mut output, "displayWindow", "box2i", &box2i(image.width, image.height));
+    attribute(&mut output, "lineOrder", "lineOrder", &[0]); // Increasing y
+    attribute(&mut output, "pixelAspectRatio", "float", &1.0f32.to_le_bytes());
+    attribute(&mut output, "screenWindowCenter", "v2f", &[0; 8]);
+    attribute(&mut output, "screenWindowWidth", "float", &1.0f32.to_le_bytes());
+    output.push(0);
+
+    // Lines of a chunk, with each channel of a line after the previous one
+    let lines_per_chunk = if compress { ZIP_LINES } else { 1 };
+    let chunks: Vec<(usize, Vec<u8>)> = (0..image.height).step_by(lines_per_chunk).map(|first_line| {
+        let mut data = vec![];
+        for line in first_line..(first_line + lines_per_chunk).min(image.height) {
+            // The first line of an EXR is the top one
+            let y = image.height - 1 - line;
+            for channel in &channels {
+                for x in 0..image.width {
+                    let value = channel.value(x, y);
+                    if full_float {
+                        data.extend_from_slice(&value.to_le_bytes());
+                    } else {
+                        data.extend_from_slice(&to_half(value).to_le_bytes());
+                    }
+                }
+            }
+        }
+        if compress {
+            let compressed = zlib::compress(&zip_predictor(&data));
+            // Chunks that don't get smaller are stored as they are
+            if compressed.len() < data.len() {
+                data = compressed;
+            }
+        }
+        (first_line, data)
+    }).collect();
+
+    // Offsets of the chunks from the start of the file
+    let mut offset = output.len() + 8 * chunks.len();
+    for (_, data) in &chunks {
+        output.extend_from_slice(&(offset as u64).to_le_bytes());
+        offset += 8 + data.len();
+    }
+    for (first_line, data) in &chunks {
+        output.extend_from_slice(&(*first_line as i32).to_le_bytes());
+        output.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        output.extend_from_slice(data);
+    }
+    File::create(file_name)?.write_all(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::Color;
+
+    // Name, type and value of each header attribute, and the position after the header
+    fn attributes(bytes: &[u8]) -> (Vec<(String, String, Vec<u8>)>, usize) {
+        let mut attributes = vec![];
+        let mut position = 8;
+        let string = |position: &mut usize| {
+            let end = *position + bytes[*position..].iter().position(|&byte| byte == 0).unwrap();
+            let string = String::from_utf8(bytes[*position..end].to_vec()).unwrap();
+            *position = end + 1;
+            string
+        };
+        while bytes[position] != 0 {
+            let name = string(&mut position);
+            let kind = string(&mut position);
+            let size = u32::from_le_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+            attributes.push((name, kind, bytes[position + 4..position + 4 + size].to_vec()));
+            position += 4 + size;
+        }
+        (attributes, position + 1)
+    }
+
+    #[test]
+    fn half_conversion() {
+        assert_eq!(to_half(0.0), 0x0000);
+        assert_eq!(to_half(-0.0), 0x8000);
+        assert_eq!(to_half(1.0), 0x3c00);
+        assert_eq!(to_half(-2.0), 0xc000);
+        assert_eq!(to_half(0.1), 0x2e66);
+        // Largest finite half, and the values that round to it or past it
+        assert_eq!(to_half(65504.0), 0x7bff);
+        assert_eq!(to_half(65519.0), 0x7bff);
+        assert_eq!(to_half(65520.0), 0x7c00);
+        assert_eq!(to_half(f32::INFINITY), 0x7c00);
+        assert_eq!(to_half(f32::NAN), 0x7e00);
+        // Smallest normal half, then subnormals down to the smallest one
+        assert_eq!(to_half(2f32.powi(-14)), 0x0400);
+        assert_eq!(to_half(2f32.powi(-15)), 0x0200);
+        assert_eq!(to_half(3.0 * 2f32.powi(-20)), 0x0030);
+        assert_eq!(to_half(2f32.powi(-24)), 0x0001);
+        // Halfway between zero and the smallest subnormal rounds to even, above it rounds up
+        assert_eq!(to_half(2f32.powi(-25)), 0x0000);
+        assert_eq!(to_half(1.5 * 2f32.powi(-25)), 0x0001);
+        assert_eq!(to_half(2f32.powi(-30)), 0x0000);
+    }
+
+    #[test]
+    fn header_attributes() {
+        let (width, height) = (3, 2);
+        let mut image = Image::new(width, height);
+        image.set_line(vec![Color { red: 1.0, green: 0.5, blue: 0.25 }; width], vec![0.75; width], height - 1);
+        let path = std::env::temp_dir().join(format!("exr_header_{}.exr", std::process::id()));
+        let path = path.to_str().unwrap();
+        save_exr(&image, &[], path, false, true, false).unwrap();
+        let bytes = std::fs::read(path).unwrap();
+        std::fs::remove_file(path).unwrap();
+
+        assert_eq!(bytes[..8], [0x76, 0x2f, 0x31, 0x01, 2, 0, 0, 0]);
+        let (attributes, header_end) = attributes(&bytes);
+        let names: Vec<(&str, &str)> = attributes.iter().map(|(name, kind, _)| (name.as_str(), kind.as_str())).collect();
+        assert_eq!(names, [
+            ("channels", "chlist"),
+            ("compression", "compression"),
+            ("dataWindow", "box2i"),
+            ("displayWindow", "box2i"),
+            ("lineOrder", "lineOrder"),
+            ("pixelAspectRatio", "float"),
+            ("screenWindowCenter", "v2f"),
+            ("screenWindowWidth", "float"),
+        ]);
+        let mut channels = vec![];
+        for name in [b'A', b'B', b'G', b'R'] {
+            channels.extend_from_slice(&[name, 0, 1, 0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0]);
+        }
+        channels.push(0);
+        assert_eq!(attributes[0].2, channels);
+        assert_eq!(attributes[1].2, [NO_COMPRESSION]);
+        assert_eq!(attributes[2].2, [0, 0, 0, 0, 0, 0, 0, 0, 2, 0, 0, 0, 1, 0, 0, 0]);
+        assert_eq!(attributes[3].2, attributes[2].2);
+
+        // One chunk per line, the top one first, with the channels of its pixels in halves
+        let first_chunk = u64::from_le_bytes(bytes[header_end..header_end + 8].try_into().unwrap()) as usize;
+        assert_eq!(first_chunk, header_end + 8 * height);
+        assert_eq!(bytes[first_chunk..first_chunk + 8], [0, 0, 0, 0, 24, 0, 0, 0]);
+        let values: Vec<u16> = bytes[first_chunk + 8..first_chunk + 32].chunks(2)
+            .map(|pair| u16::from_le_bytes([pair[0], pair[1]]))
+            .collect();
+        assert_eq!(values, [0x3a00, 0x3a00, 0x3a00, 0x3400, 0x3400, 0x3400, 0x3800, 0x3800, 0x3800, 0x3c00, 0x3c00, 0x3c00]);
+    }
+}
@@ -1,4 +1,4 @@
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug)]
 pub struct Vec3(pub f32, pub f32, pub f32);
 
 impl std::ops::Add for Vec3 {
@@ -15,6 +15,13 @@ impl std::ops::Sub for Vec3 {
     }
 }
 
+impl std::ops::Neg for Vec3 {
+    type Output = Vec3;
+    fn neg(self) -> Vec3 {
+        Vec3(-self.0, -self.1, -self.2)
+    }
+}
+
 impl std::ops::Mul<f32> for Vec3 {
     type Output = Vec3;
     fn mul(self, x: f32) -> Vec3 {
@@ -56,27 +63,58 @@ impl Vec3 {
     pub fn normalize(self) -> Vec3 {
         self / self.norm()
     }
-}
 
-pub fn random_unit_vector() -> Vec3 {
-    loop {
-        let vec = Vec3(rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5, rand::random::<f32>() - 0.5) * 2.0;
-        let norm2 = vec.norm2();
-        if norm2 < 1.0 {
-            return vec / norm2.sqrt()
+    pub fn axis(self, axis: usize) -> f32 {
+        match axis {
+            0 => self.0,
+            1 => self.1,
+            _ => self.2,
         }
     }
+
+    pub fn min(self, other: Vec3) -> Vec3 {
+        Vec3(self.0.min(other.0), self.1.min(other.1), self.2.min(other.2))
+    }
+
+    pub fn max(self, other: Vec3) -> Vec3 {
+        Vec3(self.0.max(other.0), self.1.max(other.1), self.2.max(other.2))
+    }
 }
 
-pub fn random_in_unit_circle() -> (f32, f32) {
-    loop {
-        let x = 2.0 * rand::random::<f32>() - 1.0;
-        let y = 2.0 * rand::random::<f32>() - 1.0;
-        let r2 = x * x + y * y;
-        if r2 < 1.0 {
-            return (x, y)
-        }
+pub fn degrees_to_radians(x: f32) -> f32 {
+    x * std::f32::consts::TAU / 360.0
+}
+
+// Two unit vectors completing the unit vector w to an orthonormal basis
+pub fn orthonormal_basis(w: Vec3) -> (Vec3, Vec3) {
+    let a = if w.0.abs() > 0.9 { Vec3(0.0, 1.0, 0.0) } else { Vec3(1.0, 0.0, 0.0) };
+    let u = cross_product(w, a).normalize();
+    let v = cross_product(w, u);
+    (u, v)
+}
+
+// Mapped from a pair of sample dimensions rather than rejection sampled, so that evenly
+// spread samples give evenly spread directions
+pub fn uniform_unit_vector((u, v): (f32, f32)) -> Vec3 {
+    let z = 1.0 - 2.0 * u;
+    let r = (1.0 - z * z).max(0.0).sqrt();
+    let phi = std::f32::consts::TAU * v;
+    Vec3(r * phi.cos(), r * phi.sin(), z)
+}
+
+// Shirley and Chiu's concentric mapping of the square onto the disk, which keeps nearby
+// points nearby
+pub fn point_in_unit_circle((u, v): (f32, f32)) -> (f32, f32) {
+    let (a, b) = (2.0 * u - 1.0, 2.0 * v - 1.0);
+    if a == 0.0 && b == 0.0 {
+        return (0.0, 0.0)
     }
+    let (r, theta) = if a.abs() > b.abs() {
+        (a, std::f32::consts::FRAC_PI_4 * (b / a))
+    } else {
+        (b, std::f32::consts::FRAC_PI_2 - std::f32::consts::FRAC_PI_4 * (a / b))
+    };
+    (r * theta.cos(), r * theta.sin())
 }
 
 pub struct Ray {
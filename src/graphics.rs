@@ -1,4 +1,12 @@
+use crate::bmp::load_bmp;
+use crate::bmp::save_bmp;
+use crate::exr::save_exr;
+use crate::png::save_png;
+use crate::ppm::load_ppm;
+use crate::ppm::save_ppm;
+use crate::tonemap::DisplayTransform;
 use std::io;
+use std::io::Read;
 use std::io::Write;
 use std::fs::File;
 
@@ -18,29 +26,31 @@ impl Color {
         }
     }
 
-    pub fn attenuate(&self, other: Color) -> Color {
+    pub fn add(&self, other: Color) -> Color {
         Color {
-            red: self.red * other.red,
-            green: self.green * other.green,
-            blue: self.blue * other.blue,
+            red: self.red + other.red,
+            green: self.green + other.green,
+            blue: self.blue + other.blue,
         }
     }
 
-    pub fn average(iter: impl Iterator<Item=Color>) -> Color {
-        let mut count = 0;
-        let mut red = 0.0;
-        let mut green = 0.0;
-        let mut blue = 0.0;
-        for color in iter {
-            count += 1;
-            red += color.red;
-            green += color.green;
-            blue += color.blue;
+    pub fn scale(&self, factor: f32) -> Color {
+        Color {
+            red: self.red * factor,
+            green: self.green * factor,
+            blue: self.blue * factor,
         }
+    }
+
+    pub fn is_black(&self) -> bool {
+        self.red == 0.0 && self.green == 0.0 && self.blue == 0.0
+    }
+
+    pub fn attenuate(&self, other: Color) -> Color {
         Color {
-            red: red / count as f32,
-            green: green / count as f32,
-            blue: blue / count as f32,
+            red: self.red * other.red,
+            green: self.green * other.green,
+            blue: self.blue * other.blue,
         }
     }
 }
@@ -48,35 +58,96 @@ impl Color {
 pub const BLACK: Color = Color { red: 0.0, green: 0.0, blue: 0.0 };
 pub const WHITE: Color = Color { red: 1.0, green: 1.0, blue: 1.0 };
 
-pub struct Image {
-    pub width: usize,
-    pub height: usize,
-    pixels: Vec<Color>,
+#[derive(Copy, Clone, PartialEq)]
+pub enum ImageFormat {
+    Bmp,
+    Png,
+    // Netpbm color and grayscale images
+    Ppm,
+    Pgm,
+    // Linear floating point formats, keeping values above 1
+    Pfm,
+    Exr,
 }
 
-fn set_u32(arr: &mut [u8], ind: usize, value: u32) {
-    arr[ind] = (value & 0xFF) as u8;
-    arr[ind+1] = ((value>>8) & 0xFF) as u8;
-    arr[ind+2] = ((value>>16) & 0xFF) as u8;
-    arr[ind+3] = ((value>>24) & 0xFF) as u8;
+// Settings for writing an image, not all supported by every format
+#[derive(Copy, Clone)]
+pub struct SaveOptions {
+    // Bits per color channel, the default of the format if missing
+    pub bit_depth: Option<u8>,
+    // Write the alpha channel too
+    pub alpha: bool,
+    // Formats with optional compression use it
+    pub compress: bool,
+    // Store the top line first in formats that can go either way
+    pub top_down: bool,
+    // Write plain text in formats that have it
+    pub ascii: bool,
+    // From linear colors to display values, for the formats that aren't linear
+    pub display: DisplayTransform,
 }
 
-fn float_to_u8(x: f32) -> u8 {
-    if x < 0.0 {
-        return 0
+impl ImageFormat {
+    pub fn from_name(name: &str) -> Option<ImageFormat> {
+        match name.to_ascii_lowercase().as_str() {
+            "bmp" => Some(ImageFormat::Bmp),
+            "png" => Some(ImageFormat::Png),
+            "ppm" => Some(ImageFormat::Ppm),
+            "pgm" => Some(ImageFormat::Pgm),
+            "pfm" => Some(ImageFormat::Pfm),
+            "exr" => Some(ImageFormat::Exr),
+            _ => None,
+        }
+    }
+
+    pub fn bit_depth(&self, options: SaveOptions) -> u8 {
+        options.bit_depth.unwrap_or(match self {
+            ImageFormat::Bmp | ImageFormat::Png | ImageFormat::Ppm | ImageFormat::Pgm => 8,
+            ImageFormat::Pfm => 32,
+            ImageFormat::Exr => 16,
+        })
+    }
+
+    pub fn supports(&self, options: SaveOptions) -> Result<(), String> {
+        let bit_depth = self.bit_depth(options);
+        match self {
+            ImageFormat::Bmp if bit_depth != 8 => Err("bmp images only have a bit depth of 8".to_string()),
+            ImageFormat::Png if bit_depth != 8 && bit_depth != 16 => Err("png images have a bit depth of 8 or 16".to_string()),
+            ImageFormat::Ppm | ImageFormat::Pgm if bit_depth != 8 && bit_depth != 16 => Err("ppm and pgm images have a bit depth of 8 or 16".to_string()),
+            ImageFormat::Pfm if bit_depth != 32 => Err("pfm images only have a bit depth of 32".to_string()),
+            ImageFormat::Exr if bit_depth != 16 && bit_depth != 32 => Err("exr images have a bit depth of 16 or 32".to_string()),
+            ImageFormat::Ppm | ImageFormat::Pgm | ImageFormat::Pfm if options.alpha => Err("ppm, pgm and pfm images have no alpha channel".to_string()),
+            ImageFormat::Bmp | ImageFormat::Png | ImageFormat::Ppm | ImageFormat::Pgm | ImageFormat::Pfm if !options.compress => Err("only exr images can be written without compression".to_string()),
+            ImageFormat::Png | ImageFormat::Ppm | ImageFormat::Pgm | ImageFormat::Pfm | ImageFormat::Exr if options.top_down => Err("only bmp images can be written top-down".to_string()),
+            ImageFormat::Bmp | ImageFormat::Png | ImageFormat::Pfm | ImageFormat::Exr if options.ascii => Err("only ppm and pgm images can be written as text".to_string()),
+            _ => Ok(()),
+        }
     }
-    if x > 1.0 {
-        return 255
+
+    pub fn from_file_name(file_name: &str) -> Option<ImageFormat> {
+        let (_, extension) = file_name.rsplit_once('.')?;
+        ImageFormat::from_name(extension)
     }
-    return (255.0 * x).round() as u8
 }
 
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pixels: Vec<Color>,
+    // Coverage of the pixels, with colors premultiplied by it
+    alpha: Vec<f32>,
+}
+
+// Default gamma of the 8-bit image files
+pub const INV_GAMMA: f32 = 0.45;
+
 impl Image {
     pub fn new(width: usize, height: usize) -> Image {
         Image {
-            width: width,
-            height: height,
+            width,
+            height,
             pixels: vec![BLACK; width * height],
+            alpha: vec![1.0; width * height],
         }
     }
 
@@ -84,39 +155,98 @@ impl Image {
     //     &mut self.pixels[y * self.width + x]
     // }
 
-    pub fn set_line(&mut self, line: Vec<Color>, y: usize) {
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: Color, alpha: f32) {
+        self.pixels[y * self.width + x] = color;
+        self.alpha[y * self.width + x] = alpha;
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> Color {
+        self.pixels[y * self.width + x]
+    }
+
+    pub fn alpha(&self, x: usize, y: usize) -> f32 {
+        self.alpha[y * self.width + x]
+    }
+
+    pub fn set_line(&mut self, line: Vec<Color>, alpha: Vec<f32>, y: usize) {
         assert_eq!(self.width, line.len());
+        assert_eq!(self.width, alpha.len());
         self.pixels[y * self.width .. (y+1) * self.width].copy_from_slice(&line);
+        self.alpha[y * self.width .. (y+1) * self.width].copy_from_slice(&alpha);
+    }
+
+    pub fn save(&self, file_name: &str, format: ImageFormat, options: SaveOptions) -> io::Result<()> {
+        match format {
+            ImageFormat::Bmp => save_bmp(self, file_name, options.alpha, options.top_down, &options.display),
+            ImageFormat::Png => save_png(self, file_name, format.bit_depth(options), options.alpha, &options.display),
+            ImageFormat::Ppm => save_ppm(self, file_name, false, format.bit_depth(options), options.ascii, &options.display),
+            ImageFormat::Pgm => save_ppm(self, file_name, true, format.bit_depth(options), options.ascii, &options.display),
+            ImageFormat::Pfm => self.save_pfm(file_name),
+            ImageFormat::Exr => save_exr(self, &[], file_name, format.bit_depth(options) == 32, options.alpha, options.compress),
+        }
+    }
+
+    // Reads an image by the extension of the file name
+    pub fn load(file_name: &str) -> io::Result<Image> {
+        match ImageFormat::from_file_name(file_name) {
+            Some(ImageFormat::Bmp) => load_bmp(file_name),
+            Some(ImageFormat::Ppm | ImageFormat::Pgm) => load_ppm(file_name),
+            Some(ImageFormat::Pfm) => Image::load_pfm(file_name),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidInput, "can't read this image format")),
+        }
     }
 
-    pub fn save(&self, file_name: &str) -> io::Result<()> {
-        let mut f = File::create(file_name)?;
-        const HEADER_SIZE: usize = 140;
-        let line_bytes = self.width * 3 + self.width % 4;
-        let data_size = line_bytes * self.height;
-        let mut header: [u8; HEADER_SIZE] = [0; HEADER_SIZE];
-        header[0] = b'B';
-        header[1] = b'M';
-        set_u32(&mut header, 2, (HEADER_SIZE + data_size) as u32);
-        set_u32(&mut header, 10, HEADER_SIZE as u32);
-        set_u32(&mut header, 14, 0x7c); // Win5xBitmapHeader
-        set_u32(&mut header, 18, self.width as u32);
-        set_u32(&mut header, 22, self.height as u32);
-        header[26] = 1;  // numColorPlanes
-        header[28] = 24;  // bitsPerPixel
-        f.write(&header)?;
-        let mut data = vec![0; data_size];
-        const INV_GAMMA: f32 = 0.45;
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let pixel_ind = y * self.width + x;
-                let data_ind = y * line_bytes + x * 3;
-                data[data_ind] = float_to_u8(f32::powf(self.pixels[pixel_ind].blue, INV_GAMMA));
-                data[data_ind + 1] = float_to_u8(f32::powf(self.pixels[pixel_ind].green, INV_GAMMA));
-                data[data_ind + 2] = float_to_u8(f32::powf(self.pixels[pixel_ind].red, INV_GAMMA));
+    // Little endian floats, with the lines from bottom to top like the image
+    fn save_pfm(&self, file_name: &str) -> io::Result<()> {
+        let mut data = format!("PF\n{} {}\n-1.0\n", self.width, self.height).into_bytes();
+        for pixel in &self.pixels {
+            for value in [pixel.red, pixel.green, pixel.blue] {
+                data.extend_from_slice(&value.to_le_bytes());
             }
         }
-        f.write(&data)?;
-        Ok(())
+        File::create(file_name)?.write_all(&data)
+    }
+
+    // Color or grayscale files in either byte order
+    fn load_pfm(file_name: &str) -> io::Result<Image> {
+        let mut bytes = vec![];
+        File::open(file_name)?.read_to_end(&mut bytes)?;
+        let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+        // The header is three lines: the kind of file, the size, and the scale whose
+        // sign gives the byte order
+        let mut header = vec![];
+        let mut position = 0;
+        while header.len() < 3 {
+            let end = bytes[position..].iter().position(|&byte| byte == b'\n').ok_or_else(|| invalid("invalid header"))?;
+            header.push(String::from_utf8_lossy(&bytes[position..position + end]).trim().to_string());
+            position += end + 1;
+        }
+        let channels = match header[0].as_str() {
+            "PF" => 3,
+            "Pf" => 1,
+            _ => return Err(invalid("not a PFM file")),
+        };
+        let size: Vec<usize> = header[1].split_whitespace().filter_map(|value| value.parse().ok()).collect();
+        let scale: f32 = header[2].parse().map_err(|_| invalid("invalid header"))?;
+        let [width, height] = size[..] else {
+            return Err(invalid("invalid header"))
+        };
+        if width == 0 || height == 0 || width * height > 1 << 28 {
+            return Err(invalid("unsupported image size"))
+        }
+        let data = bytes.get(position..position + width * height * channels * 4).ok_or_else(|| invalid("truncated file"))?;
+        let values: Vec<f32> = data.chunks_exact(4).map(|value| {
+            let value = [value[0], value[1], value[2], value[3]];
+            if scale < 0.0 { f32::from_le_bytes(value) } else { f32::from_be_bytes(value) }
+        }).collect();
+        let mut image = Image::new(width, height);
+        for (y, line) in values.chunks_exact(width * channels).enumerate() {
+            let line = line.chunks_exact(channels).map(|pixel| match pixel {
+                [red, green, blue] => Color { red: *red, green: *green, blue: *blue },
+                _ => Color { red: pixel[0], green: pixel[0], blue: pixel[0] },
+            }).collect();
+            image.set_line(line, vec![1.0; width], y);
+        }
+        Ok(image)
     }
 }
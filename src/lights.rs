@@ -0,0 +1,117 @@
+use crate::geometry::Vec3;
+use crate::geometry::degrees_to_radians;
+use crate::geometry::dot;
+use crate::geometry::orthonormal_basis;
+use crate::graphics::Color;
+use crate::sampler::Sampler;
+use std::f32::consts::TAU;
+
+// Light arriving at a point from a punctual light
+pub struct Illumination {
+    // Unit vector from the point towards the light
+    pub direction: Vec3,
+    // Infinite for lights far away
+    pub distance: f32,
+    // Irradiance on a surface facing the light
+    pub light: Color,
+}
+
+// Lights that rays can't hit, only reached by shadow rays from the points they light.
+// They light diffuse surfaces, with polish 0, and no other materials.
+pub trait Light {
+    // Lights with a size pick a point on them with the sampler
+    fn illuminate(&self, point: Vec3, sampler: &mut dyn Sampler) -> Option<Illumination>;
+}
+
+// Emits the same intensity in all directions
+pub struct PointLight {
+    pub position: Vec3,
+    pub color: Color,
+    pub intensity: f32,
+}
+
+impl Light for PointLight {
+    fn illuminate(&self, point: Vec3, _sampler: &mut dyn Sampler) -> Option<Illumination> {
+        let to_light = self.position - point;
+        let distance2 = to_light.norm2();
+        let distance = distance2.sqrt();
+        Some(Illumination {
+            direction: to_light / distance,
+            distance,
+            light: self.color.scale(self.intensity / distance2),
+        })
+    }
+}
+
+// Point light restricted to a cone, with the intensity fading out towards its edge
+pub struct SpotLight {
+    pub position: Vec3,
+    // Unit vector along the axis of the cone
+    pub direction: Vec3,
+    pub color: Color,
+    pub intensity: f32,
+    // Half the opening angle of the cone
+    pub angle_degrees: f32,
+    // Width of the band at the edge of the cone where the light fades out
+    pub falloff_degrees: f32,
+}
+
+impl Light for SpotLight {
+    fn illuminate(&self, point: Vec3, _sampler: &mut dyn Sampler) -> Option<Illumination> {
+        let to_light = self.position - point;
+        let distance2 = to_light.norm2();
+        let distance = distance2.sqrt();
+        let direction = to_light / distance;
+        let cos_angle = -dot(direction, self.direction);
+        let cos_outer = degrees_to_radians(self.angle_degrees).cos();
+        if cos_angle <= cos_outer {
+            return None
+        }
+        let cos_inner = degrees_to_radians((self.angle_degrees - self.falloff_degrees).max(0.0)).cos();
+        let fade = if cos_angle >= cos_inner {
+            1.0
+        } else {
+            // Smoothstep between the edge and the start of the falloff band
+            let x = (cos_angle - cos_outer) / (cos_inner - cos_outer);
+            x * x * (3.0 - 2.0 * x)
+        };
+        Some(Illumination {
+            direction,
+            distance,
+            light: self.color.scale(self.intensity * fade / distance2),
+        })
+    }
+}
+
+// Light from far away like the sun, a disk of the given angular diameter in the sky, or
+// a single direction giving hard shadows when the diameter is zero
+pub struct DirectionalLight {
+    // Unit vector in which the light travels
+    pub direction: Vec3,
+    pub color: Color,
+    pub intensity: f32,
+    pub angular_diameter_degrees: f32,
+}
+
+impl Light for DirectionalLight {
+    fn illuminate(&self, _point: Vec3, sampler: &mut dyn Sampler) -> Option<Illumination> {
+        let axis = -self.direction;
+        let direction = if self.angular_diameter_degrees > 0.0 {
+            // Uniform direction in the cone subtended by the disk
+            let cos_max = degrees_to_radians(0.5 * self.angular_diameter_degrees).cos();
+            let (s, t) = sampler.next_2d();
+            let cos_theta = 1.0 - s * (1.0 - cos_max);
+            let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+            let phi = TAU * t;
+            let (u, v) = orthonormal_basis(axis);
+            sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * axis
+        } else {
+            axis
+        };
+        Some(Illumination {
+            direction,
+            distance: f32::INFINITY,
+            light: self.color.scale(self.intensity),
+        })
+    }
+}
@@ -1,14 +1,62 @@
 mod graphics;
 mod geometry;
+mod bvh;
 mod camera;
 mod scene;
 mod shapes;
 mod material;
+mod lights;
+mod texture;
+mod obj;
+mod cli;
+mod scene_file;
+mod png;
+mod exr;
+mod tonemap;
+mod zlib;
+mod bmp;
+mod ppm;
+mod aov;
+mod denoise;
+mod sampler;
+mod tiles;
+mod progress;
+mod checkpoint;
+mod distributed;
 
+use aov::Aov;
+use aov::Layers;
+use checkpoint::Checkpoint;
+use checkpoint::Checkpointing;
+use camera::Camera;
+use camera::RenderOptions;
+use distributed::Worker;
+use denoise::DenoiseSettings;
+use denoise::Guides;
 use geometry::Vec3;
+use graphics::Image;
 use graphics::Color;
+use progress::CancelToken;
+use progress::ConsoleProgress;
+use progress::Progress;
+use progress::RenderObserver;
+use progress::RenderSummary;
+use progress::Silent;
 use shapes::Sphere;
-// use shapes::Medium;
+use shapes::Medium;
+use shapes::Triangle;
+use shapes::TriangleMesh;
+use rand::Rng;
+use rand::SeedableRng;
+use scene_file::SceneDescription;
+use std::io;
+use std::net::TcpListener;
+use std::path::Path;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+use std::time::Instant;
 
 const SKY_BLUE: Color = Color{ red: 0.5, green: 0.7, blue: 1.0 };
 
@@ -17,45 +65,459 @@ fn sky_color(direction: Vec3) -> Color {
     Color::mix(graphics::WHITE, SKY_BLUE, a)
 }
 
-fn main() -> std::io::Result<()> {
+// Sphere approximated by a latitude/longitude grid of 2 * rings * segments triangles
+fn tessellated_sphere(center: Vec3, radius: f32, rings: u32, segments: u32) -> TriangleMesh {
+    let mut vertices = vec![];
+    let mut normals = vec![];
+    let mut texcoords = vec![];
+    for ring in 0..=rings {
+        let theta = std::f32::consts::PI * ring as f32 / rings as f32;
+        for segment in 0..=segments {
+            let phi = std::f32::consts::TAU * segment as f32 / segments as f32;
+            let normal = Vec3(theta.sin() * phi.cos(), theta.cos(), theta.sin() * phi.sin());
+            vertices.push(center + radius * normal);
+            normals.push(normal);
+            texcoords.push([segment as f32 / segments as f32, 1.0 - ring as f32 / rings as f32]);
+        }
+    }
+    let mut triangles = vec![];
+    for ring in 0..rings {
+        for segment in 0..segments {
+            let top = ring * (segments + 1) + segment;
+            let bottom = top + segments + 1;
+            triangles.push([top, bottom, top + 1]);
+            triangles.push([top + 1, bottom, bottom + 1]);
+        }
+    }
+    TriangleMesh::new(vertices, triangles, Some(normals), Some(texcoords))
+}
+
+// Many small random spheres, to compare the BVH against a linear scan of the objects
+fn benchmark_scene() -> scene::Scene {
     let mut scene = scene::Scene::new();
     scene.sky = Box::new(&sky_color);
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
 
-    let material_ground = material::Opaque{albedo: Color{red: 0.8, green: 0.8, blue: 0.0}, polish: 0.0};
-    let material_center = material::Opaque{albedo: Color{red: 0.1, green: 0.2, blue: 0.5}, polish: 0.0};
-    let material_left   = material::Transparent{refraction_index: 1.5};
-    let material_left2   = material::Transparent{refraction_index: 1.5};
-    let material_right  = material::Opaque{albedo: Color{red: 0.8, green: 0.6, blue: 0.2}, polish: 0.9};
-
-    scene.add_object(Sphere{ center: Vec3(0.0, -100.5, 1.0), radius: 100.0 }, material_ground);
-    scene.add_object(Sphere{ center: Vec3(0.0, 0.0, 1.0), radius: 0.5 }, material_center);
-    scene.add_object(Sphere{ center: Vec3(-1.0, 0.0, 1.0), radius: 0.5 }, material_left);
-    scene.add_object(Sphere{ center: Vec3(-1.0, 0.0, 1.0), radius: -0.4 }, material_left2);
-    scene.add_object(Sphere{ center: Vec3(1.0, 0.0, 1.0), radius: 0.5 }, material_right);
-    // scene.add_object(Medium{ shape: Box::new(Sphere{ center: Vec3(-1.0, 0.0, 1.0), radius: 0.5 }), density: 5.0 }, material::Gas{albedo: graphics::Color{red:0.9,green:0.9,blue:0.9}, isotropy: 0.2});
+    scene.add_object(Sphere{ center: Vec3(0.0, -1000.0, 0.0), radius: 1000.0 }, material::Opaque{albedo: Arc::new(Color{red: 0.5, green: 0.5, blue: 0.5}), polish: 0.0, bump: None});
+    for a in -40..40 {
+        for b in -40..40 {
+            let center = Vec3(a as f32 * 0.5 + 0.4 * rng.gen::<f32>(), 0.1, b as f32 * 0.5 + 0.4 * rng.gen::<f32>());
+            let sphere = Sphere{ center, radius: 0.1 };
+            let albedo = Color{red: rng.gen(), green: rng.gen(), blue: rng.gen()};
+            match rng.gen_range(0..10) {
+                0 => scene.add_object(sphere, material::Transparent{refraction_index: 1.5}),
+                1..=3 => scene.add_object(sphere, material::Opaque{albedo: Arc::new(albedo), polish: 0.8, bump: None}),
+                _ => scene.add_object(sphere, material::Opaque{albedo: Arc::new(albedo), polish: 0.0, bump: None}),
+            }
+        }
+    }
+    scene.add_object(Sphere{ center: Vec3(0.0, 1.0, 0.0), radius: 1.0 }, material::Transparent{refraction_index: 1.5});
+    scene.add_object(tessellated_sphere(Vec3(3.0, 1.0, 0.0), 1.0, 500, 1000), material::Opaque{albedo: Arc::new(Color{red: 0.7, green: 0.6, blue: 0.5}), polish: 1.0, bump: None});
+    scene.add_object(Triangle{ vertices: [Vec3(-20.0, 0.0, -8.0), Vec3(-20.0, 0.0, 8.0), Vec3(-20.0, 12.0, 0.0)] }, material::Opaque{albedo: Arc::new(Color{red: 0.9, green: 0.9, blue: 0.9}), polish: 1.0, bump: None});
+    scene.add_object(Medium{ shape: Box::new(Sphere{ center: Vec3(-3.0, 1.0, 0.0), radius: 1.0 }), density: 5.0 }, material::Gas{albedo: Arc::new(Color{red: 0.9, green: 0.9, blue: 0.9}), isotropy: 0.2});
+    scene
+}
 
+fn benchmark() {
+    let mut scene = benchmark_scene();
     let camera = camera::Camera::new(
         camera::Bearings {
-            // lookfrom: Vec3(0.0, 0.0, -3.0),
-            lookat: Vec3(0.0, 0.0, 1.0),
+            lookfrom: Vec3(13.0, 2.0, 3.0),
+            lookat: Vec3(0.0, 0.0, 0.0),
             up: Vec3(0.0, 1.0, 0.0),
-            // fov_degrees: 28.1,
-            lookfrom: Vec3(-2.0, 2.0, -1.0),
             fov_degrees: 20.0,
-            defocus_degrees: 10.0,
+            defocus_degrees: 0.6,
         },
         camera::ImageSettings {
-            image_width: 400,
+            image_width: 200,
             aspect_ratio: 16.0 / 9.0,
         },
         camera::RenderSettings {
-            samples_per_pixel: 100,
-            max_depth: 50,
+            samples_per_pixel: 4,
+            min_samples_per_pixel: 4,
+            max_depth: 10,
+            ..Default::default()
         },
     );
-    let image = camera.render(&scene);
+    println!("Linear scan:");
+    scene.set_linear_scan(true);
+    camera.render(&scene, &[], RenderOptions { observer: &ConsoleProgress, ..Default::default() });
+    scene.set_linear_scan(false);
+    println!("BVH:");
+    camera.render(&scene, &[], RenderOptions { observer: &ConsoleProgress, ..Default::default() });
+}
+
+// Exit codes
+const EXIT_USAGE: u8 = 2;
+const EXIT_SCENE: u8 = 3;
+const EXIT_OUTPUT: u8 = 4;
+
+fn render(options: &cli::Options) -> ExitCode {
+    let mut description = match scene_file::load_scene(Path::new(&options.scene_file)) {
+        Ok(description) => description,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            return ExitCode::from(EXIT_SCENE)
+        }
+    };
+    apply_options(options, &mut description);
+    let resume = match load_resume(options, &mut description) {
+        Ok(resume) => resume,
+        Err(code) => return code,
+    };
+    let camera = description.camera();
+    if options.verbosity == cli::Verbosity::Verbose {
+        print_settings(options, &description, &camera);
+    }
+    let aovs = render_aovs(options);
+    let checkpointing = match checkpointing(options, resume, &camera.fingerprint(&aovs)) {
+        Ok(checkpointing) => checkpointing,
+        Err(code) => return code,
+    };
+    let observer: &dyn RenderObserver = if options.verbosity == cli::Verbosity::Quiet { &Silent } else { &ConsoleProgress };
+    let snapshots = Snapshots { observer, options };
+    let render_options = RenderOptions {
+        observer: if options.snapshots { &snapshots } else { observer },
+        cancel: cancel_after(options.timeout),
+        checkpointing,
+        workers: start_workers(options, &description, &camera, &aovs),
+    };
+    let mut layers = camera.render(&description.scene, &aovs, render_options);
+    if options.denoise {
+        let settings = DenoiseSettings { strength: options.denoise_strength, threads: description.render_settings.threads };
+        layers.image = timed_denoise(&layers.image, layers.guides(), &settings, options.verbosity);
+        layers.aovs.retain(|(aov, _)| options.aovs.contains(aov));
+    }
+    let files = match layers.save(&options.output, options.format, options.save_options) {
+        Ok(files) => files,
+        Err(error) => {
+            eprintln!("Error: can't write {}: {}", options.output, error);
+            return ExitCode::from(EXIT_OUTPUT)
+        }
+    };
+    if options.verbosity == cli::Verbosity::Verbose {
+        println!("Saved {}", files.join(", "));
+    }
+    ExitCode::SUCCESS
+}
+
+// Settings given on the command line, over the ones of the scene file
+fn apply_options(options: &cli::Options, description: &mut SceneDescription) {
+    if let Some(width) = options.width {
+        description.image_settings.image_width = width;
+    }
+    if let Some(aspect_ratio) = options.aspect_ratio {
+        description.image_settings.aspect_ratio = aspect_ratio;
+    }
+    if let Some(samples_per_pixel) = options.samples_per_pixel {
+        description.render_settings.samples_per_pixel = samples_per_pixel;
+    }
+    if options.adaptive_threshold.is_some() {
+        description.render_settings.adaptive_threshold = options.adaptive_threshold;
+    }
+    if let Some(min_samples_per_pixel) = options.min_samples_per_pixel {
+        description.render_settings.min_samples_per_pixel = min_samples_per_pixel;
+    }
+    if options.progressive {
+        description.render_settings.progressive = true;
+    }
+    if options.time_budget.is_some() {
+        description.render_settings.time_budget = options.time_budget;
+        description.render_settings.progressive = true;
+    }
+    if options.target_noise.is_some() {
+        description.render_settings.target_noise = options.target_noise;
+        description.render_settings.progressive = true;
+    }
+    if let Some(max_depth) = options.max_depth {
+        description.render_settings.max_depth = max_depth;
+    }
+    if let Some(threads) = options.threads {
+        description.render_settings.threads = threads;
+    }
+    if let Some(tile_size) = options.tile_size {
+        description.render_settings.tile_size = tile_size;
+    }
+    if let Some(tile_order) = options.tile_order {
+        description.render_settings.tile_order = tile_order;
+    }
+    if options.seed.is_some() {
+        description.render_settings.seed = options.seed;
+    }
+    if let Some(sampler) = options.sampler {
+        description.render_settings.sampler = sampler;
+    }
+    if options.no_light_sampling {
+        description.render_settings.light_sampling = false;
+    }
+    if options.save_options.alpha {
+        description.render_settings.transparent_background = true;
+    }
+}
+
+// The checkpoint to resume from, if any. A render without a seed picked a random one,
+// which it must keep.
+fn load_resume(options: &cli::Options, description: &mut SceneDescription) -> Result<Option<Checkpoint>, ExitCode> {
+    let Some(path) = &options.resume else {
+        return Ok(None)
+    };
+    match Checkpoint::load(Path::new(path)) {
+        Ok(checkpoint) => {
+            description.render_settings.seed = description.render_settings.seed.or(Some(checkpoint.seed));
+            Ok(Some(checkpoint))
+        }
+        Err(error) => {
+            eprintln!("Error: can't read {}: {}", path, error);
+            Err(ExitCode::from(EXIT_SCENE))
+        }
+    }
+}
+
+// Where to save checkpoints, with the checkpoint to resume from, which must come from a
+// render with the same fingerprint
+fn checkpointing(options: &cli::Options, resume: Option<Checkpoint>, fingerprint: &str) -> Result<Option<Checkpointing>, ExitCode> {
+    if let (Some(path), Some(checkpoint)) = (&options.resume, &resume) {
+        if checkpoint.fingerprint != fingerprint {
+            eprintln!("Error: {} was saved by a render with other settings", path);
+            return Err(ExitCode::from(EXIT_USAGE))
+        }
+    }
+    let checkpoint_path = options.checkpoint.as_ref().or(options.resume.as_ref());
+    Ok(checkpoint_path.map(|path| Checkpointing {
+        path: path.into(),
+        interval: Duration::from_secs_f64(options.checkpoint_interval),
+        resume,
+    }))
+}
+
+fn print_settings(options: &cli::Options, description: &SceneDescription, camera: &Camera) {
+    let (width, height) = camera.image_size();
+    println!("Scene: {} ({} objects)", options.scene_file, description.scene.object_count());
+    let settings = &description.render_settings;
+    let samples = match settings.adaptive_threshold {
+        Some(threshold) => format!("{} to {} samples per pixel (adaptive, threshold {})",
+            settings.min_samples_per_pixel.min(settings.samples_per_pixel), settings.samples_per_pixel, threshold),
+        None => format!("{} samples per pixel", settings.samples_per_pixel),
+    };
+    println!("Image: {}x{}, {}, max depth {}, seed {}", width, height, samples, settings.max_depth, camera.seed());
+    if settings.progressive {
+        let mut budgets = vec![];
+        if let Some(time_budget) = settings.time_budget {
+            budgets.push(format!("after {} seconds", time_budget));
+        }
+        if let Some(target_noise) = settings.target_noise {
+            budgets.push(format!("at noise {}", target_noise));
+        }
+        budgets.push("at the most samples".to_string());
+        println!("Progressive: stopping {}", budgets.join(" or "));
+    }
+}
+
+// The denoiser needs layers that may not have been asked for
+fn render_aovs(options: &cli::Options) -> Vec<Aov> {
+    let mut aovs = options.aovs.clone();
+    if options.denoise {
+        for aov in [Aov::Albedo, Aov::Normal, Aov::Depth] {
+            if !aovs.contains(&aov) {
+                aovs.push(aov);
+            }
+        }
+    }
+    aovs
+}
+
+// Token cancelling the render after the timeout, if any
+fn cancel_after(timeout: Option<f64>) -> CancelToken {
+    let cancel = CancelToken::new();
+    if let Some(timeout) = timeout {
+        let cancel = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_secs_f64(timeout));
+            cancel.cancel();
+        });
+    }
+    cancel
+}
 
-    image.save("pic.bmp")?;
+// The workers that answer, with a warning for the others. The render falls back to the
+// threads of this process if none does.
+fn start_workers(options: &cli::Options, description: &SceneDescription, camera: &Camera, aovs: &[Aov]) -> Vec<Worker> {
+    let mut workers = vec![];
+    let mut start = |name: String, worker: io::Result<Worker>| {
+        let worker = worker.and_then(|mut worker| {
+            worker.start(&description.image_settings, &description.render_settings, aovs, camera)?;
+            Ok(worker)
+        });
+        match worker {
+            Ok(worker) => workers.push(worker),
+            Err(error) => eprintln!("Warning: can't render on {}: {}", name, error),
+        }
+    };
+    // The workers on this machine share its CPUs
+    let threads = options.threads.unwrap_or((num_cpus::get() / options.workers.max(1)).max(1));
+    for number in 1..=options.workers {
+        let name = format!("worker {}", number);
+        start(name.clone(), Worker::spawn(name, &options.scene_file, threads));
+    }
+    let timeout = Duration::from_secs_f64(options.worker_timeout);
+    for address in &options.connect {
+        let name = format!("worker at {}", address);
+        start(name.clone(), Worker::connect(name, address, timeout));
+    }
+    workers
+}
+
+// Renders tiles for coordinators, over the standard input and output, or for each
+// connection to the address
+fn serve(options: &cli::Options, address: Option<&str>) -> ExitCode {
+    let mut description = match scene_file::load_scene(Path::new(&options.scene_file)) {
+        Ok(description) => description,
+        Err(error) => {
+            eprintln!("Error: {}", error);
+            return ExitCode::from(EXIT_SCENE)
+        }
+    };
+    if let Some(threads) = options.threads {
+        description.render_settings.threads = threads;
+    }
+    let Some(address) = address else {
+        if let Err(error) = distributed::serve(&description, io::stdin().lock(), io::stdout().lock()) {
+            eprintln!("Error: the coordinator went away: {}", error);
+            return ExitCode::from(EXIT_OUTPUT)
+        }
+        return ExitCode::SUCCESS
+    };
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(error) => {
+            eprintln!("Error: can't listen on {}: {}", address, error);
+            return ExitCode::from(EXIT_USAGE)
+        }
+    };
+    if options.verbosity != cli::Verbosity::Quiet {
+        let address = listener.local_addr().map_or(address.to_string(), |address| address.to_string());
+        println!("Rendering {} for coordinators connecting to {}", options.scene_file, address);
+    }
+    let description = &description;
+    thread::scope(|scope| {
+        for stream in listener.incoming() {
+            scope.spawn(move || {
+                let session = stream.and_then(|stream| distributed::serve(description, stream.try_clone()?, stream));
+                if let Err(error) = session {
+                    eprintln!("Warning: a coordinator went away: {}", error);
+                }
+            });
+        }
+    });
+    ExitCode::SUCCESS
+}
+
+// Saves the image to the output after each pass, so that it can be looked at while the
+// render goes on
+struct Snapshots<'a> {
+    observer: &'a dyn RenderObserver,
+    options: &'a cli::Options,
+}
+
+impl RenderObserver for Snapshots<'_> {
+    fn started(&self, threads: usize, workers: usize) {
+        self.observer.started(threads, workers);
+    }
+
+    fn progress(&self, progress: &Progress) {
+        self.observer.progress(progress);
+    }
+
+    fn pass_finished(&self, pass: usize, snapshot: &dyn Fn() -> Layers) {
+        self.observer.pass_finished(pass, snapshot);
+        let mut layers = snapshot();
+        layers.aovs.retain(|(aov, _)| self.options.aovs.contains(aov));
+        if let Err(error) = layers.save(&self.options.output, self.options.format, self.options.save_options) {
+            eprintln!("Warning: can't write {}: {}", self.options.output, error);
+        }
+    }
+
+    fn finished(&self, summary: &RenderSummary) {
+        self.observer.finished(summary);
+    }
+
+    fn warning(&self, message: &str) {
+        self.observer.warning(message);
+    }
+}
+
+fn timed_denoise(image: &Image, guides: Guides, settings: &DenoiseSettings, verbosity: cli::Verbosity) -> Image {
+    let start_time = Instant::now();
+    let denoised = denoise::denoise(image, guides, settings);
+    if verbosity != cli::Verbosity::Quiet {
+        println!("Denoised in {:.1} seconds", start_time.elapsed().as_secs_f64());
+    }
+    denoised
+}
+
+fn load_image(file_name: &str) -> Result<Image, String> {
+    Image::load(file_name).map_err(|error| format!("can't read {}: {}", file_name, error))
+}
+
+// The image to denoise and the guiding layers that are given
+fn load_denoise_files(files: &cli::DenoiseFiles) -> Result<(Image, [Option<Image>; 3]), String> {
+    let image = load_image(&files.image)?;
+    let mut guides = [None, None, None];
+    for (guide, file_name) in guides.iter_mut().zip([&files.albedo, &files.normal, &files.depth]) {
+        if let Some(file_name) = file_name {
+            *guide = Some(load_image(file_name)?);
+        }
+    }
+    Ok((image, guides))
+}
+
+// Denoises an image file, with the guiding layers from other files
+fn denoise_file(options: &cli::Options, files: &cli::DenoiseFiles) -> ExitCode {
+    let (image, [albedo, normal, depth]) = match load_denoise_files(files) {
+        Ok(images) => images,
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            return ExitCode::from(EXIT_SCENE)
+        }
+    };
+    for guide in [&albedo, &normal, &depth].into_iter().flatten() {
+        if (guide.width, guide.height) != (image.width, image.height) {
+            eprintln!("Error: the layers must have the size of the image, {}x{}", image.width, image.height);
+            return ExitCode::from(EXIT_USAGE)
+        }
+    }
+
+    let guides = Guides { albedo: albedo.as_ref(), normal: normal.as_ref(), depth: depth.as_ref() };
+    let settings = DenoiseSettings { strength: options.denoise_strength, threads: options.threads.unwrap_or(0) };
+    let denoised = timed_denoise(&image, guides, &settings, options.verbosity);
+    if let Err(error) = denoised.save(&options.output, options.format, options.save_options) {
+        eprintln!("Error: can't write {}: {}", options.output, error);
+        return ExitCode::from(EXIT_OUTPUT)
+    }
+    if options.verbosity == cli::Verbosity::Verbose {
+        println!("Saved {}", options.output);
+    }
+    ExitCode::SUCCESS
+}
 
-    Ok(())
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match cli::parse_args(&args) {
+        Ok(cli::Command::Help) => {
+            print!("{}", cli::USAGE);
+            ExitCode::SUCCESS
+        }
+        Ok(cli::Command::Benchmark) => {
+            benchmark();
+            ExitCode::SUCCESS
+        }
+        Ok(cli::Command::Render(options)) => render(&options),
+        Ok(cli::Command::Denoise(options, files)) => denoise_file(&options, &files),
+        Ok(cli::Command::Worker(options, address)) => serve(&options, address.as_deref()),
+        Err(message) => {
+            eprintln!("Error: {}", message);
+            eprintln!("Run with --help for usage");
+            ExitCode::from(EXIT_USAGE)
+        }
+    }
 }
@@ -1,23 +1,59 @@
 use crate::geometry::Ray;
 use crate::geometry::Vec3;
+use crate::geometry::cross_product;
 use crate::geometry::dot;
-use crate::geometry::random_unit_vector;
+use crate::geometry::uniform_unit_vector;
+use crate::graphics::BLACK;
 use crate::graphics::Color;
 use crate::graphics::WHITE;
+use crate::sampler::Sampler;
 use crate::scene::HitRecord;
 use crate::scene::Material;
+use crate::texture::SharedTexture;
+use crate::texture::Texture;
 
 pub struct Opaque {
-    pub albedo: Color,
+    pub albedo: SharedTexture,
     pub polish: f32,
+    pub bump: Option<Bump>,
+}
+
+// Bumps on the surface given by a height map, the average of a texture, which only
+// change the shading normal and not the shape
+pub struct Bump {
+    pub height: SharedTexture,
+    pub scale: f32,
+}
+
+impl Bump {
+    fn normal(&self, hit_record: &HitRecord) -> Vec3 {
+        let height = |du: f32, dv: f32| {
+            let point = hit_record.hit_point + du * hit_record.dpdu + dv * hit_record.dpdv;
+            let color = self.height.value(hit_record.u + du, hit_record.v + dv, point);
+            self.scale * (color.red + color.green + color.blue) / 3.0
+        };
+        let (dpdu_norm, dpdv_norm) = (hit_record.dpdu.norm(), hit_record.dpdv.norm());
+        if dpdu_norm == 0.0 || dpdv_norm == 0.0 {
+            return hit_record.normal
+        }
+        // Finite differences over a short distance on the surface
+        const STEP: f32 = 1e-3;
+        let (du, dv) = (STEP / dpdu_norm, STEP / dpdv_norm);
+        let base = height(0.0, 0.0);
+        let normal = hit_record.normal;
+        let dpdu = hit_record.dpdu + ((height(du, 0.0) - base) / du) * normal;
+        let dpdv = hit_record.dpdv + ((height(0.0, dv) - base) / dv) * normal;
+        let bumped = cross_product(dpdu, dpdv).normalize();
+        if dot(bumped, normal) < 0.0 { -bumped } else { bumped }
+    }
 }
 
 fn reflect(vec: Vec3, plane_normal: Vec3) -> Vec3 {
     vec - 2.0 * dot(vec, plane_normal) * plane_normal
 }
 
-fn lambertian(normal: Vec3) -> Vec3 {
-    let direction = normal + random_unit_vector();
+fn lambertian(normal: Vec3, sampler: &mut dyn Sampler) -> Vec3 {
+    let direction = normal + uniform_unit_vector(sampler.next_2d());
     if direction.norm2() < 1e-8 {
         // Edge case, avoid scattering in near-zero direction
         normal
@@ -26,28 +62,54 @@ fn lambertian(normal: Vec3) -> Vec3 {
     }
 }
 
-fn scatter_direction(incoming_ray: Vec3, hit_record: &HitRecord, polish: f32) -> Vec3 {
-    let diffusion = lambertian(hit_record.normal);
-    let reflection = reflect(incoming_ray, hit_record.normal);
+fn scatter_direction(incoming_ray: Vec3, normal: Vec3, polish: f32, sampler: &mut dyn Sampler) -> Vec3 {
+    let diffusion = lambertian(normal, sampler);
+    let reflection = reflect(incoming_ray, normal);
     let direction = (1.0 - polish) * diffusion + polish * reflection;
     direction.normalize()
 }
 
 impl Material for Opaque {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
-        if dot(ray.direction, hit_record.normal) > 0.0 {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
+        if !hit_record.front_face {
             // ray is coming from inside the body
             return None
         }
 
-        return Some((
-            self.albedo,
+        Some((
+            self.albedo.value(hit_record.u, hit_record.v, hit_record.hit_point),
             Ray {
                 origin: hit_record.hit_point,
-                direction: scatter_direction(ray.direction, hit_record, self.polish),
+                direction: scatter_direction(ray.direction, self.shading_normal(hit_record), self.polish, sampler),
             },
         ))
     }
+
+    // Only the purely diffuse surface has a known BSDF
+    fn evaluate(&self, _ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<(Color, f32)> {
+        if self.polish != 0.0 || !hit_record.front_face {
+            return None
+        }
+        // Lambertian scattering picks directions with density cos / pi
+        let cosine = dot(direction, self.shading_normal(hit_record));
+        if cosine <= 0.0 {
+            return Some((BLACK, 0.0))
+        }
+        let pdf = cosine / std::f32::consts::PI;
+        let albedo = self.albedo.value(hit_record.u, hit_record.v, hit_record.hit_point);
+        Some((albedo.scale(pdf), pdf))
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo.value(hit_record.u, hit_record.v, hit_record.hit_point)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> Vec3 {
+        match &self.bump {
+            Some(bump) => bump.normal(hit_record),
+            None => hit_record.normal,
+        }
+    }
 }
 
 pub struct Transparent {
@@ -58,17 +120,17 @@ fn reflectance(cos_theta: f32, refraction_ratio: f32) -> f32 {
     // Use Schlick's approximation for reflectance
     let r0 = (1.0 - refraction_ratio) / (1.0 + refraction_ratio);
     let r0 = r0 * r0;
-    return r0 + (1.0 - r0) * (1.0 - cos_theta.abs()).powf(5.0);
+    r0 + (1.0 - r0) * (1.0 - cos_theta.abs()).powf(5.0)
 }
 
-fn refraction_direction(incoming_ray: Vec3, hit_record: &HitRecord, refraction_index: f32) -> Vec3 {
+fn refraction_direction(incoming_ray: Vec3, hit_record: &HitRecord, refraction_index: f32, sampler: &mut dyn Sampler) -> Vec3 {
     let cos_theta = -dot(incoming_ray, hit_record.normal);
     if cos_theta.abs() < 1e-6 {
         // Close to parallel ray, just reflect to avoid numerical issues
         return reflect(incoming_ray, hit_record.normal)
     }
 
-    let refraction_ratio = if cos_theta > 0.0 { 1.0 / refraction_index } else { refraction_index };
+    let refraction_ratio = if hit_record.front_face { 1.0 / refraction_index } else { refraction_index };
 
     let r2 = refraction_ratio * refraction_ratio;
     let parallel_factor_sq = r2 + (1.0 - r2) / (cos_theta * cos_theta);
@@ -77,7 +139,7 @@ fn refraction_direction(incoming_ray: Vec3, hit_record: &HitRecord, refraction_i
         return reflect(incoming_ray, hit_record.normal)
     }
 
-    if reflectance(cos_theta, refraction_ratio) > rand::random() {
+    if reflectance(cos_theta, refraction_ratio) > sampler.next_1d() {
         // Partial reflection
         return reflect(incoming_ray, hit_record.normal)
     }
@@ -87,31 +149,62 @@ fn refraction_direction(incoming_ray: Vec3, hit_record: &HitRecord, refraction_i
 }
 
 impl Material for Transparent {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
         Some((
             WHITE,
             Ray {
                 origin: hit_record.hit_point,
-                direction: refraction_direction(ray.direction, hit_record, self.refraction_index),
+                direction: refraction_direction(ray.direction, hit_record, self.refraction_index, sampler),
             }
         ))
     }
 }
 
 pub struct Gas {
-    pub albedo: Color,
+    pub albedo: SharedTexture,
     pub isotropy: f32,
 }
 
 impl Material for Gas {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)> {
-        let direction = (1.0 - self.isotropy) * ray.direction + self.isotropy * random_unit_vector();
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
+        let direction = (1.0 - self.isotropy) * ray.direction + self.isotropy * uniform_unit_vector(sampler.next_2d());
         Some((
-            self.albedo,
+            self.albedo.value(hit_record.u, hit_record.v, hit_record.hit_point),
             Ray {
                 origin: hit_record.hit_point,
                 direction: direction.normalize(),
             }
         ))
     }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.albedo.value(hit_record.u, hit_record.v, hit_record.hit_point)
+    }
+}
+
+// Emits light from the front side of the surface, turning any shape into an area light
+pub struct DiffuseLight {
+    pub color: SharedTexture,
+    pub power: f32,
+}
+
+impl Material for DiffuseLight {
+    fn scatter(&self, _: &Ray, _: &HitRecord, _: &mut dyn Sampler) -> Option<(Color, Ray)> {
+        None
+    }
+
+    fn emitted(&self, _ray: &Ray, hit_record: &HitRecord) -> Color {
+        if !hit_record.front_face {
+            return BLACK
+        }
+        self.color.value(hit_record.u, hit_record.v, hit_record.hit_point).scale(self.power)
+    }
+
+    fn is_emissive(&self) -> bool {
+        true
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        self.color.value(hit_record.u, hit_record.v, hit_record.hit_point)
+    }
 }
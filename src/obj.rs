@@ -0,0 +1,440 @@
+use crate::geometry::Vec3;
+use crate::geometry::cross_product;
+use crate::geometry::dot;
+use crate::graphics::Color;
+use crate::material::DiffuseLight;
+use crate::material::Opaque;
+use crate::material::Transparent;
+use crate::scene::Scene;
+use crate::scene::SharedMaterial;
+use crate::shapes::TriangleMesh;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct ObjError {
+    pub file: PathBuf,
+    // Zero when the error is not related to a specific line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ObjError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        } else {
+            write!(f, "{}:{}: {}", self.file.display(), self.line, self.message)
+        }
+    }
+}
+
+impl std::error::Error for ObjError {}
+
+impl From<ObjError> for std::io::Error {
+    fn from(error: ObjError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+// Reads the file and runs parse_line on each non-empty line with the comment removed
+fn for_each_line(
+    path: &Path,
+    mut parse_line: impl FnMut(&str, &[&str]) -> Result<(), String>,
+) -> Result<(), ObjError> {
+    let text = fs::read_to_string(path).map_err(|error| ObjError {
+        file: path.to_path_buf(),
+        line: 0,
+        message: error.to_string(),
+    })?;
+    for (line_index, line) in text.lines().enumerate() {
+        let line = match line.find('#') {
+            Some(comment) => &line[..comment],
+            None => line,
+        };
+        let mut tokens = line.split_whitespace();
+        let Some(keyword) = tokens.next() else { continue };
+        let args: Vec<&str> = tokens.collect();
+        parse_line(keyword, &args).map_err(|message| ObjError {
+            file: path.to_path_buf(),
+            line: line_index + 1,
+            message,
+        })?;
+    }
+    Ok(())
+}
+
+fn parse_f32(token: &str) -> Result<f32, String> {
+    token.parse().map_err(|_| format!("invalid number '{}'", token))
+}
+
+fn parse_floats(args: &[&str], min: usize, max: usize) -> Result<Vec<f32>, String> {
+    if args.len() < min || args.len() > max {
+        return Err(format!("expected {} to {} numbers, found {}", min, max, args.len()))
+    }
+    args.iter().map(|token| parse_f32(token)).collect()
+}
+
+fn parse_color(args: &[&str]) -> Result<Color, String> {
+    // A single value means a gray color
+    let values = parse_floats(args, 1, 3)?;
+    match values[..] {
+        [gray] => Ok(Color { red: gray, green: gray, blue: gray }),
+        [red, green, blue] => Ok(Color { red, green, blue }),
+        _ => Err("expected 1 or 3 color components".to_string()),
+    }
+}
+
+struct MtlEntry {
+    diffuse: Color,
+    specular: Color,
+    emission: Color,
+    dissolve: f32,
+    refraction_index: Option<f32>,
+    illumination: u32,
+}
+
+impl MtlEntry {
+    fn new() -> MtlEntry {
+        MtlEntry {
+            diffuse: Color { red: 0.8, green: 0.8, blue: 0.8 },
+            specular: Color { red: 0.0, green: 0.0, blue: 0.0 },
+            emission: Color { red: 0.0, green: 0.0, blue: 0.0 },
+            dissolve: 1.0,
+            refraction_index: None,
+            illumination: 2,
+        }
+    }
+
+    // Emissive entries become lights, transparent entries glass, and others a mix of
+    // diffuse and mirror reflection weighted by the specular color
+    fn to_material(&self) -> SharedMaterial {
+        if self.emission.red > 0.0 || self.emission.green > 0.0 || self.emission.blue > 0.0 {
+            return Arc::new(DiffuseLight { color: Arc::new(self.emission), power: 1.0 })
+        }
+        let transparent = self.dissolve < 1.0 || matches!(self.illumination, 4 | 6 | 7 | 9);
+        if transparent {
+            Arc::new(Transparent {
+                refraction_index: self.refraction_index.unwrap_or(1.5),
+            })
+        } else {
+            let polish = (self.specular.red + self.specular.green + self.specular.blue) / 3.0;
+            Arc::new(Opaque {
+                albedo: Arc::new(self.diffuse),
+                polish: polish.clamp(0.0, 1.0),
+                bump: None,
+            })
+        }
+    }
+}
+
+fn load_mtl(path: &Path, materials: &mut HashMap<String, SharedMaterial>) -> Result<(), ObjError> {
+    let mut entries: Vec<(String, MtlEntry)> = vec![];
+    for_each_line(path, |keyword, args| {
+        if keyword == "newmtl" {
+            if args.len() != 1 {
+                return Err("expected a single material name".to_string())
+            }
+            entries.push((args[0].to_string(), MtlEntry::new()));
+            return Ok(())
+        }
+        let Some((_, entry)) = entries.last_mut() else {
+            return Err(format!("'{}' before any newmtl", keyword))
+        };
+        match keyword {
+            "Kd" => entry.diffuse = parse_color(args)?,
+            "Ks" => entry.specular = parse_color(args)?,
+            "Ke" => entry.emission = parse_color(args)?,
+            "d" => entry.dissolve = parse_floats(args, 1, 1)?[0],
+            "Tr" => entry.dissolve = 1.0 - parse_floats(args, 1, 1)?[0],
+            "Ni" => entry.refraction_index = Some(parse_floats(args, 1, 1)?[0]),
+            "illum" => {
+                entry.illumination = args.first()
+                    .and_then(|token| token.parse().ok())
+                    .ok_or("expected an illumination model number".to_string())?;
+            }
+            // Ambient color, exponents, texture maps and so on are not supported
+            _ => {}
+        }
+        Ok(())
+    })?;
+    for (name, entry) in entries {
+        materials.insert(name, entry.to_material());
+    }
+    Ok(())
+}
+
+// Parses one vertex of a face, v, v/vt, v//vn or v/vt/vn, into zero-based
+// indices. Negative indices count back from the last element read so far.
+fn parse_face_vertex(token: &str, counts: [usize; 3]) -> Result<[Option<usize>; 3], String> {
+    let mut indices = [None; 3];
+    let parts: Vec<&str> = token.split('/').collect();
+    if parts.len() > 3 || parts[0].is_empty() {
+        return Err(format!("invalid face vertex '{}'", token))
+    }
+    for (i, part) in parts.iter().enumerate() {
+        if part.is_empty() {
+            continue
+        }
+        let index: i64 = part.parse().map_err(|_| format!("invalid index '{}'", part))?;
+        let count = counts[i] as i64;
+        let resolved = if index > 0 { index - 1 } else { count + index };
+        if index == 0 || resolved < 0 || resolved >= count {
+            return Err(format!("index {} out of range", index))
+        }
+        indices[i] = Some(resolved as usize);
+    }
+    Ok(indices)
+}
+
+// Splits a polygon into triangles by ear clipping in its dominant plane, so that concave
+// polygons are handled too. Returns indices into the polygon.
+fn triangulate(polygon: &[Vec3]) -> Vec<[usize; 3]> {
+    let n = polygon.len();
+    if n == 3 {
+        return vec![[0, 1, 2]]
+    }
+
+    // Newell's method for the polygon normal
+    let mut normal = Vec3(0.0, 0.0, 0.0);
+    for i in 0..n {
+        let a = polygon[i];
+        let b = polygon[(i + 1) % n];
+        normal = normal + Vec3((a.1 - b.1) * (a.2 + b.2), (a.2 - b.2) * (a.0 + b.0), (a.0 - b.0) * (a.1 + b.1));
+    }
+    let fan = || (1..n - 1).map(|i| [0, i, i + 1]).collect();
+    if normal.norm2() == 0.0 {
+        return fan()
+    }
+
+    let is_convex = |a: Vec3, b: Vec3, c: Vec3| dot(cross_product(b - a, c - b), normal) < 0.0;
+    let inside = |p: Vec3, a: Vec3, b: Vec3, c: Vec3| {
+        dot(cross_product(b - a, p - a), normal) <= 0.0
+            && dot(cross_product(c - b, p - b), normal) <= 0.0
+            && dot(cross_product(a - c, p - c), normal) <= 0.0
+    };
+
+    let mut remaining: Vec<usize> = (0..n).collect();
+    let mut triangles = vec![];
+    while remaining.len() > 3 {
+        let m = remaining.len();
+        let ear = (0..m).find(|&i| {
+            let (ia, ib, ic) = (remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]);
+            let (a, b, c) = (polygon[ia], polygon[ib], polygon[ic]);
+            is_convex(a, b, c) && remaining.iter()
+                .filter(|&&j| j != ia && j != ib && j != ic)
+                .all(|&j| !inside(polygon[j], a, b, c))
+        });
+        let Some(i) = ear else {
+            // Degenerate or self-intersecting polygon
+            return fan()
+        };
+        triangles.push([remaining[(i + m - 1) % m], remaining[i], remaining[(i + 1) % m]]);
+        remaining.remove(i);
+    }
+    triangles.push([remaining[0], remaining[1], remaining[2]]);
+    triangles
+}
+
+// Vertex buffers for the faces sharing a group and a material
+#[derive(Default)]
+struct MeshBuilder {
+    vertices: Vec<Vec3>,
+    normals: Vec<Vec3>,
+    has_all_normals: bool,
+    texcoords: Vec<[f32; 2]>,
+    has_all_texcoords: bool,
+    vertex_map: HashMap<[Option<usize>; 3], u32>,
+    triangles: Vec<[u32; 3]>,
+}
+
+impl MeshBuilder {
+    fn new() -> MeshBuilder {
+        MeshBuilder { has_all_normals: true, has_all_texcoords: true, ..Default::default() }
+    }
+
+    fn vertex(&mut self, face_vertex: [Option<usize>; 3], positions: &[Vec3], texcoords: &[[f32; 2]], normals: &[Vec3]) -> u32 {
+        if let Some(&index) = self.vertex_map.get(&face_vertex) {
+            return index
+        }
+        let [position, texcoord, normal] = face_vertex;
+        let index = self.vertices.len() as u32;
+        self.vertices.push(positions[position.unwrap()]);
+        match texcoord {
+            Some(texcoord) => self.texcoords.push(texcoords[texcoord]),
+            None => {
+                self.has_all_texcoords = false;
+                self.texcoords.push([0.0, 0.0]);
+            }
+        }
+        match normal {
+            Some(normal) => self.normals.push(normals[normal]),
+            None => {
+                self.has_all_normals = false;
+                self.normals.push(Vec3(0.0, 0.0, 0.0));
+            }
+        }
+        self.vertex_map.insert(face_vertex, index);
+        index
+    }
+
+    fn build(self) -> TriangleMesh {
+        let normals = if self.has_all_normals { Some(self.normals) } else { None };
+        let texcoords = if self.has_all_texcoords { Some(self.texcoords) } else { None };
+        TriangleMesh::new(self.vertices, self.triangles, normals, texcoords)
+    }
+}
+
+// OBJ files use right-handed coordinates while the scene is left-handed
+fn to_scene_coordinates(values: &[f32]) -> Vec3 {
+    Vec3(values[0], values[1], -values[2])
+}
+
+// Loads the meshes of an OBJ file into the scene, one object for each group and material.
+// The model is scaled by a positive factor and then moved by the offset.
+pub fn load_obj(scene: &mut Scene, path: &Path, scale: f32, offset: Vec3) -> Result<(), ObjError> {
+    let directory = path.parent().unwrap_or(Path::new("")).to_path_buf();
+    let mut positions: Vec<Vec3> = vec![];
+    let mut normals: Vec<Vec3> = vec![];
+    let mut texcoords: Vec<[f32; 2]> = vec![];
+    let mut materials: HashMap<String, SharedMaterial> = HashMap::new();
+    let mut group = String::new();
+    let mut material_name: Option<String> = None;
+    let mut builders: Vec<((String, Option<String>), MeshBuilder)> = vec![];
+    // Builder of the current group and material, looked up again when either changes
+    let mut current: Option<usize> = None;
+
+    for_each_line(path, |keyword, args| {
+        match keyword {
+            "v" => positions.push(scale * to_scene_coordinates(&parse_floats(args, 3, 4)?) + offset),
+            "vn" => normals.push(to_scene_coordinates(&parse_floats(args, 3, 3)?).normalize()),
+            "vt" => {
+                let values = parse_floats(args, 1, 3)?;
+                texcoords.push([values[0], values.get(1).copied().unwrap_or(0.0)]);
+            }
+            "f" => {
+                if args.len() < 3 {
+                    return Err("a face needs at least 3 vertices".to_string())
+                }
+                let counts = [positions.len(), texcoords.len(), normals.len()];
+                let face = args.iter()
+                    .map(|token| parse_face_vertex(token, counts))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let index = *current.get_or_insert_with(|| {
+                    let key = (group.clone(), material_name.clone());
+                    builders.iter().position(|(k, _)| *k == key).unwrap_or_else(|| {
+                        builders.push((key, MeshBuilder::new()));
+                        builders.len() - 1
+                    })
+                });
+                let builder = &mut builders[index].1;
+                let polygon: Vec<Vec3> = face.iter().map(|[v, _, _]| positions[v.unwrap()]).collect();
+                let corners: Vec<u32> = face.iter()
+                    .map(|&face_vertex| builder.vertex(face_vertex, &positions, &texcoords, &normals))
+                    .collect();
+                for [a, b, c] in triangulate(&polygon) {
+                    builder.triangles.push([corners[a], corners[b], corners[c]]);
+                }
+            }
+            "g" | "o" => {
+                group = args.join(" ");
+                current = None;
+            }
+            "usemtl" => {
+                let name = args.join(" ");
+                if !materials.contains_key(&name) {
+                    return Err(format!("unknown material '{}'", name))
+                }
+                material_name = Some(name);
+                current = None;
+            }
+            "mtllib" => {
+                for file in args {
+                    load_mtl(&directory.join(file), &mut materials).map_err(|error| error.to_string())?;
+                }
+            }
+            // Smoothing groups, lines, points, free-form geometry and so on are not supported
+            _ => {}
+        }
+        Ok(())
+    })?;
+
+    let default_material: SharedMaterial = Arc::new(Opaque {
+        albedo: Arc::new(Color { red: 0.8, green: 0.8, blue: 0.8 }),
+        polish: 0.0,
+        bump: None,
+    });
+    for ((_, material_name), builder) in builders {
+        if builder.triangles.is_empty() {
+            continue
+        }
+        let material = match &material_name {
+            Some(name) => materials[name].clone(),
+            None => default_material.clone(),
+        };
+        scene.add_shared_object(Box::new(builder.build()), material);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn face_vertices() {
+        let counts = [4, 3, 2];
+        assert_eq!(parse_face_vertex("3", counts), Ok([Some(2), None, None]));
+        assert_eq!(parse_face_vertex("1/3", counts), Ok([Some(0), Some(2), None]));
+        assert_eq!(parse_face_vertex("2//1", counts), Ok([Some(1), None, Some(0)]));
+        assert_eq!(parse_face_vertex("4/1/2", counts), Ok([Some(3), Some(0), Some(1)]));
+        // Negative indices count back from the last element
+        assert_eq!(parse_face_vertex("-1", counts), Ok([Some(3), None, None]));
+        assert_eq!(parse_face_vertex("-4/-3", counts), Ok([Some(0), Some(0), None]));
+        assert_eq!(parse_face_vertex("-2//-1", counts), Ok([Some(2), None, Some(1)]));
+        for token in ["0", "5", "-5", "1/4", "1//3", "", "/1", "1/2/1/1", "a", "1/b"] {
+            assert!(parse_face_vertex(token, counts).is_err(), "accepted '{}'", token);
+        }
+    }
+
+    fn area(a: Vec3, b: Vec3, c: Vec3) -> Vec3 {
+        0.5 * cross_product(b - a, c - a)
+    }
+
+    // Checks that the triangles cover the polygon once, all facing the same way as it
+    fn assert_covers(polygon: &[Vec3], polygon_area: f32) {
+        let triangles = triangulate(polygon);
+        assert_eq!(triangles.len(), polygon.len() - 2);
+        let areas: Vec<Vec3> = triangles.iter().map(|&[a, b, c]| area(polygon[a], polygon[b], polygon[c])).collect();
+        let total = areas.iter().map(|area| area.norm()).sum::<f32>();
+        assert!((total - polygon_area).abs() < 1e-5, "triangles cover {} instead of {}", total, polygon_area);
+        assert!(areas.iter().all(|&area| dot(area, areas[0]) > 0.0), "triangles facing both ways: {:?}", triangles);
+    }
+
+    #[test]
+    fn convex_polygons() {
+        let square = [Vec3(0.0, 0.0, 0.0), Vec3(1.0, 0.0, 0.0), Vec3(1.0, 1.0, 0.0), Vec3(0.0, 1.0, 0.0)];
+        assert_covers(&square, 1.0);
+        let hexagon: Vec<Vec3> = (0..6).map(|i| {
+            let angle = i as f32 * std::f32::consts::PI / 3.0;
+            Vec3(0.0, angle.cos(), angle.sin())
+        }).collect();
+        assert_covers(&hexagon, 1.5 * 3.0f32.sqrt());
+    }
+
+    // A fan from the first corner would reach across the notch of the U
+    #[test]
+    fn concave_polygon() {
+        let u_shape: Vec<Vec3> = [(0.0, 0.0), (3.0, 0.0), (3.0, 3.0), (2.0, 3.0), (2.0, 1.0), (1.0, 1.0), (1.0, 3.0), (0.0, 3.0)]
+            .iter()
+            .map(|&(x, z)| Vec3(x, 0.0, z))
+            .collect();
+        assert_covers(&u_shape, 7.0);
+        let reversed: Vec<Vec3> = u_shape.iter().rev().copied().collect();
+        assert_covers(&reversed, 7.0);
+    }
+}
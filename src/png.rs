@@ -0,0 +1,190 @@
+use crate::graphics::BLACK;
+use crate::graphics::Image;
+use crate::tonemap::DisplayTransform;
+use crate::tonemap::Transfer;
+use crate::zlib;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { 0xedb88320 ^ (crc >> 1) } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn write_chunk(output: &mut Vec<u8>, kind: &[u8; 4], data: &[u8]) {
+    output.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = output.len();
+    output.extend_from_slice(kind);
+    output.extend_from_slice(data);
+    let crc = crc32(&output[start..]);
+    output.extend_from_slice(&crc.to_be_bytes());
+}
+
+fn paeth(left: u8, up: u8, up_left: u8) -> u8 {
+    let estimate = left as i16 + up as i16 - up_left as i16;
+    let (distance_left, distance_up, distance_up_left) =
+        ((estimate - left as i16).abs(), (estimate - up as i16).abs(), (estimate - up_left as i16).abs());
+    if distance_left <= distance_up && distance_left <= distance_up_left {
+        left
+    } else if distance_up <= distance_up_left {
+        up
+    } else {
+        up_left
+    }
+}
+
+// Applies each of the filters to the line, keeping the one with the smallest residuals
+// which usually compresses best
+fn filter_line(line: &[u8], previous: &[u8], pixel_bytes: usize, output: &mut Vec<u8>) {
+    let mut best: Option<(u64, u8, Vec<u8>)> = None;
+    for filter in 0..5u8 {
+        let filtered: Vec<u8> = (0..line.len()).map(|i| {
+            let left = if i >= pixel_bytes { line[i - pixel_bytes] } else { 0 };
+            let up = previous[i];
+            let up_left = if i >= pixel_bytes { previous[i - pixel_bytes] } else { 0 };
+            let prediction = match filter {
+                0 => 0,
+                1 => left,
+                2 => up,
+                3 => ((left as u16 + up as u16) / 2) as u8,
+                _ => paeth(left, up, up_left),
+            };
+            line[i].wrapping_sub(prediction)
+        }).collect();
+        let cost = filtered.iter().map(|&byte| (byte as i8).unsigned_abs() as u64).sum();
+        if best.as_ref().is_none_or(|(best_cost, _, _)| cost < *best_cost) {
+            best = Some((cost, filter, filtered));
+        }
+    }
+    let (_, filter, filtered) = best.unwrap();
+    output.push(filter);
+    output.extend_from_slice(&filtered);
+}
+
+// Writes RGB or RGBA with straight alpha, 8 or 16 bits per channel
+pub fn save_png(image: &Image, file_name: &str, bit_depth: u8, alpha: bool, display: &DisplayTransform) -> io::Result<()> {
+    let channels = if alpha { 4 } else { 3 };
+    let sample_bytes = bit_depth as usize / 8;
+    let pixel_bytes = channels * sample_bytes;
+    let max_value = if bit_depth == 16 { 65535.0 } else { 255.0 };
+    let push_sample = |line: &mut Vec<u8>, value: f32| {
+        let value = (value.clamp(0.0, 1.0) * max_value).round() as u16;
+        if sample_bytes == 2 {
+            line.extend_from_slice(&value.to_be_bytes());
+        } else {
+            line.push(value as u8);
+        }
+    };
+
+    let encode = display.encoder(image);
+    let mut filtered = vec![];
+    let mut previous = vec![0; image.width * pixel_bytes];
+    // The first line of a PNG is the top one
+    for y in (0..image.height).rev() {
+        let mut line = Vec::with_capacity(image.width * pixel_bytes);
+        for x in 0..image.width {
+            let mut color = image.pixel(x, y);
+            let coverage = image.alpha(x, y);
+            if alpha {
+                color = if coverage > 0.0 { color.scale(1.0 / coverage) } else { BLACK };
+            }
+            let color = encode(color);
+            for value in [color.red, color.green, color.blue] {
+                push_sample(&mut line, value);
+            }
+            if alpha {
+                push_sample(&mut line, coverage);
+            }
+        }
+        filter_line(&line, &previous, pixel_bytes, &mut filtered);
+        previous = line;
+    }
+
+    let mut header = vec![];
+    header.extend_from_slice(&(image.width as u32).to_be_bytes());
+    header.extend_from_slice(&(image.height as u32).to_be_bytes());
+    header.push(bit_depth);
+    header.push(if alpha { 6 } else { 2 }); // Truecolor, with or without alpha
+    header.extend_from_slice(&[0, 0, 0]); // Deflate compression, adaptive filters, no interlacing
+
+    let mut output = SIGNATURE.to_vec();
+    write_chunk(&mut output, b"IHDR", &header);
+    match display.transfer {
+        // Gamma times 100000
+        Transfer::Gamma(inverse_gamma) => write_chunk(&mut output, b"gAMA", &((inverse_gamma * 100000.0).round() as u32).to_be_bytes()),
+        // Perceptual rendering intent
+        Transfer::Srgb => write_chunk(&mut output, b"sRGB", &[0]),
+    }
+    write_chunk(&mut output, b"IDAT", &zlib::compress(&filtered));
+    write_chunk(&mut output, b"IEND", &[]);
+    File::create(file_name)?.write_all(&output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graphics::INV_GAMMA;
+    use crate::tonemap::ToneMap;
+
+    // Type and data of each chunk in the file, after checking its CRC
+    fn chunks(bytes: &[u8]) -> Vec<([u8; 4], Vec<u8>)> {
+        let mut chunks = vec![];
+        let mut position = SIGNATURE.len();
+        while position < bytes.len() {
+            let length = u32::from_be_bytes(bytes[position..position + 4].try_into().unwrap()) as usize;
+            let end = position + 8 + length;
+            let crc = u32::from_be_bytes(bytes[end..end + 4].try_into().unwrap());
+            assert_eq!(crc, crc32(&bytes[position + 4..end]));
+            chunks.push((bytes[position + 4..position + 8].try_into().unwrap(), bytes[position + 8..end].to_vec()));
+            position = end + 4;
+        }
+        chunks
+    }
+
+    #[test]
+    fn crc32_vectors() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xcbf43926);
+        assert_eq!(crc32(b"IEND"), 0xae426082);
+    }
+
+    #[test]
+    fn signature_and_header() {
+        let image = Image::new(300, 2);
+        for (bit_depth, alpha, color_type) in [(8, false, 2), (16, false, 2), (8, true, 6), (16, true, 6)] {
+            for transfer in [Transfer::Gamma(INV_GAMMA), Transfer::Srgb] {
+                let display = DisplayTransform { exposure_stops: 0.0, auto_exposure: false, tone_map: ToneMap::Clip, transfer };
+                let path = std::env::temp_dir().join(format!("png_header_{}_{}_{}.png", std::process::id(), bit_depth, alpha));
+                let path = path.to_str().unwrap();
+                save_png(&image, path, bit_depth, alpha, &display).unwrap();
+                let bytes = std::fs::read(path).unwrap();
+                std::fs::remove_file(path).unwrap();
+                assert_eq!(bytes[..8], [0x89, 0x50, 0x4e, 0x47, 0x0d, 0x0a, 0x1a, 0x0a]);
+                let chunks = chunks(&bytes);
+                let kinds: Vec<&[u8; 4]> = chunks.iter().map(|(kind, _)| kind).collect();
+                // Width 300 and height 2, big-endian, then the format
+                assert_eq!(chunks[0].1, [0, 0, 1, 44, 0, 0, 0, 2, bit_depth, color_type, 0, 0, 0]);
+                match transfer {
+                    Transfer::Gamma(_) => {
+                        assert_eq!(kinds, [b"IHDR", b"gAMA", b"IDAT", b"IEND"]);
+                        // The gamma of INV_GAMMA, times 100000
+                        assert_eq!(chunks[1].1, 45000u32.to_be_bytes());
+                    }
+                    Transfer::Srgb => {
+                        assert_eq!(kinds, [b"IHDR", b"sRGB", b"IDAT", b"IEND"]);
+                        assert_eq!(chunks[1].1, [0]);
+                    }
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,146 @@
+use crate::graphics::Color;
+use crate::graphics::INV_GAMMA;
+use crate::graphics::Image;
+use crate::tonemap::DisplayTransform;
+use crate::tonemap::luminance;
+use std::fs::File;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+// Writes a color PPM or a grayscale PGM, binary or as plain text, with 8 or 16 bits per
+// sample. The lines go from the top of the image down.
+pub fn save_ppm(image: &Image, file_name: &str, gray: bool, bit_depth: u8, ascii: bool, display: &DisplayTransform) -> io::Result<()> {
+    let magic = match (gray, ascii) {
+        (false, false) => "P6",
+        (false, true) => "P3",
+        (true, false) => "P5",
+        (true, true) => "P2",
+    };
+    let max_value: u16 = if bit_depth == 16 { 65535 } else { 255 };
+    let mut data = format!("{}\n{} {}\n{}\n", magic, image.width, image.height, max_value).into_bytes();
+    let encode = display.encoder(image);
+    for y in (0..image.height).rev() {
+        let mut samples = vec![];
+        for x in 0..image.width {
+            let color = image.pixel(x, y);
+            if gray {
+                let value = luminance(color);
+                samples.push(encode(Color { red: value, green: value, blue: value }).red);
+            } else {
+                let color = encode(color);
+                samples.extend_from_slice(&[color.red, color.green, color.blue]);
+            }
+        }
+        let samples = samples.into_iter().map(|value| (value.clamp(0.0, 1.0) * max_value as f32).round() as u16);
+        if ascii {
+            // Plain files shouldn't have lines longer than 70 characters
+            let mut line = String::new();
+            for sample in samples {
+                let text = sample.to_string();
+                if !line.is_empty() && line.len() + 1 + text.len() > 70 {
+                    data.extend_from_slice(line.as_bytes());
+                    data.push(b'\n');
+                    line.clear();
+                }
+                if !line.is_empty() {
+                    line.push(' ');
+                }
+                line.push_str(&text);
+            }
+            data.extend_from_slice(line.as_bytes());
+            data.push(b'\n');
+        } else if max_value > 255 {
+            samples.for_each(|sample| data.extend_from_slice(&sample.to_be_bytes()));
+        } else {
+            data.extend(samples.map(|sample| sample as u8));
+        }
+    }
+    File::create(file_name)?.write_all(&data)
+}
+
+// Position in the bytes of a file, for reading the whitespace separated numbers of the
+// header and of plain files
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl Reader<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.position < self.bytes.len() {
+            match self.bytes[self.position] {
+                b'#' => {
+                    while self.position < self.bytes.len() && self.bytes[self.position] != b'\n' {
+                        self.position += 1;
+                    }
+                }
+                byte if byte.is_ascii_whitespace() => self.position += 1,
+                _ => return,
+            }
+        }
+    }
+
+    fn number(&mut self) -> Option<u32> {
+        self.skip_whitespace();
+        let start = self.position;
+        while self.position < self.bytes.len() && self.bytes[self.position].is_ascii_digit() {
+            self.position += 1;
+        }
+        std::str::from_utf8(&self.bytes[start..self.position]).ok()?.parse().ok()
+    }
+}
+
+// Reads PPM and PGM files, binary or plain, converting the colors back to linear
+pub fn load_ppm(file_name: &str) -> io::Result<Image> {
+    let mut bytes = vec![];
+    File::open(file_name)?.read_to_end(&mut bytes)?;
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message);
+    let (gray, ascii) = match bytes.get(0..2) {
+        Some(b"P2") => (true, true),
+        Some(b"P3") => (false, true),
+        Some(b"P5") => (true, false),
+        Some(b"P6") => (false, false),
+        _ => return Err(invalid("not a PPM or PGM file")),
+    };
+    let mut reader = Reader { bytes: &bytes, position: 2 };
+    let mut header = || reader.number().ok_or_else(|| invalid("invalid header"));
+    let (width, height, max_value) = (header()? as usize, header()? as usize, header()?);
+    if width == 0 || height == 0 || width * height > 1 << 28 {
+        return Err(invalid("unsupported image size"))
+    }
+    if max_value == 0 || max_value > 65535 {
+        return Err(invalid("maximum value out of range"))
+    }
+
+    let channels = if gray { 1 } else { 3 };
+    let count = width * height * channels;
+    let samples: Vec<u32> = if ascii {
+        (0..count).map(|_| reader.number()).collect::<Option<_>>().ok_or_else(|| invalid("truncated file"))?
+    } else {
+        // A single whitespace character separates the header from the data
+        let start = reader.position + 1;
+        let sample_bytes = if max_value > 255 { 2 } else { 1 };
+        let data = bytes.get(start..start + count * sample_bytes).ok_or_else(|| invalid("truncated file"))?;
+        if sample_bytes == 2 {
+            data.chunks_exact(2).map(|pair| u16::from_be_bytes([pair[0], pair[1]]) as u32).collect()
+        } else {
+            data.iter().map(|&byte| byte as u32).collect()
+        }
+    };
+
+    let to_linear = |sample: u32| (sample.min(max_value) as f32 / max_value as f32).powf(1.0 / INV_GAMMA);
+    let mut image = Image::new(width, height);
+    for (line, line_samples) in samples.chunks_exact(width * channels).enumerate() {
+        let colors = line_samples.chunks_exact(channels).map(|pixel| {
+            if gray {
+                let value = to_linear(pixel[0]);
+                Color { red: value, green: value, blue: value }
+            } else {
+                Color { red: to_linear(pixel[0]), green: to_linear(pixel[1]), blue: to_linear(pixel[2]) }
+            }
+        }).collect();
+        image.set_line(colors, vec![1.0; width], height - 1 - line);
+    }
+    Ok(image)
+}
@@ -0,0 +1,109 @@
+use crate::aov::Layers;
+use std::io::Write;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+// State of a render after a tile is finished
+pub struct Progress {
+    // Adaptive and progressive renders take several passes over the image, others a
+    // single one
+    pub pass: usize,
+    pub multipass: bool,
+    // Tiles of the pass
+    pub completed_tiles: usize,
+    pub tiles: usize,
+    pub elapsed: Duration,
+    // Time left in the pass, estimated from the tiles finished so far
+    pub remaining: Duration,
+    // Rays traced per second since the start of the render, camera rays and bounces as
+    // well as shadow rays
+    pub rays_per_second: f64,
+}
+
+pub struct RenderSummary {
+    pub elapsed: Duration,
+    pub multipass: bool,
+    pub average_samples_per_pixel: f64,
+    pub rays_per_second: f64,
+    // Whether the render was cancelled before it finished
+    pub cancelled: bool,
+}
+
+// Told how a render goes. The progress comes from the rendering threads, so observers
+// must be shareable between threads.
+pub trait RenderObserver: Sync {
+    // Renders on distributed workers give their number, the threads of this process then
+    // being idle
+    fn started(&self, _threads: usize, _workers: usize) {}
+    fn progress(&self, _progress: &Progress) {}
+    // The snapshot gives the image so far, which takes a while to put together
+    fn pass_finished(&self, _pass: usize, _snapshot: &dyn Fn() -> Layers) {}
+    fn finished(&self, _summary: &RenderSummary) {}
+    // Something went wrong without stopping the render
+    fn warning(&self, _message: &str) {}
+}
+
+// Observer that ignores everything
+pub struct Silent;
+
+impl RenderObserver for Silent {}
+
+// Prints the progress on a single line of the terminal
+pub struct ConsoleProgress;
+
+impl RenderObserver for ConsoleProgress {
+    fn started(&self, threads: usize, workers: usize) {
+        if workers > 0 {
+            println!("Rendering on {} workers", workers);
+        } else {
+            println!("Rendering on {} threads", threads);
+        }
+    }
+
+    fn progress(&self, progress: &Progress) {
+        let pass = if progress.multipass { format!("Pass {}: completed", progress.pass) } else { "Completed".to_string() };
+        print!("\r{} {} / {} tiles, {:.2} Mrays/s, {:.0} s elapsed, {:.0} s left   ",
+            pass, progress.completed_tiles, progress.tiles, progress.rays_per_second * 1e-6,
+            progress.elapsed.as_secs_f64(), progress.remaining.as_secs_f64().ceil());
+        std::io::stdout().flush().unwrap();
+    }
+
+    fn pass_finished(&self, _pass: usize, _snapshot: &dyn Fn() -> Layers) {
+        println!();
+    }
+
+    fn finished(&self, summary: &RenderSummary) {
+        let verb = if summary.cancelled { "Cancelled" } else { "Finished" };
+        print!("{} after {:.1} seconds", verb, summary.elapsed.as_secs_f64());
+        if summary.multipass {
+            print!(", {:.1} samples per pixel on average", summary.average_samples_per_pixel);
+        }
+        println!(", {:.2} Mrays/s", summary.rays_per_second * 1e-6);
+    }
+
+    // On a line of its own, after the progress
+    fn warning(&self, message: &str) {
+        println!();
+        eprintln!("Warning: {}", message);
+    }
+}
+
+// Shared flag that stops a render at the next tile. The render then returns the image
+// rendered so far, with the pixels it didn't reach black and uncovered.
+#[derive(Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+}
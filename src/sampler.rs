@@ -0,0 +1,324 @@
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+
+// Source of the random numbers of a render. Each sample of a pixel is a point with many
+// dimensions, which the camera, materials and lights take one or two at a time.
+// Samplers other than the independent one spread the samples of a pixel more evenly than
+// random numbers would, which lowers the noise at a given number of samples.
+pub trait Sampler {
+    // Starts the sample with the given index in the pixel
+    fn start_sample(&mut self, x: usize, y: usize, index: usize);
+    // Next dimension of the sample, in [0, 1)
+    fn next_1d(&mut self) -> f32;
+    // Next two dimensions, spread evenly as a pair
+    fn next_2d(&mut self) -> (f32, f32);
+}
+
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub enum SamplerKind {
+    // Uncorrelated random numbers
+    Independent,
+    // Random points in a grid of cells, one cell per sample
+    Stratified,
+    // Halton sequence, with its digits randomly permuted in each pixel
+    Halton,
+    // Sobol sequence with Owen scrambling, shuffled in each dimension
+    Sobol,
+}
+
+impl SamplerKind {
+    pub fn from_name(name: &str) -> Option<SamplerKind> {
+        match name {
+            "independent" => Some(SamplerKind::Independent),
+            "stratified" => Some(SamplerKind::Stratified),
+            "halton" => Some(SamplerKind::Halton),
+            "sobol" => Some(SamplerKind::Sobol),
+            _ => None,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            SamplerKind::Independent => "independent",
+            SamplerKind::Stratified => "stratified",
+            SamplerKind::Halton => "halton",
+            SamplerKind::Sobol => "sobol",
+        }
+    }
+
+    pub fn create(&self, seed: u64, samples_per_pixel: usize) -> Box<dyn Sampler> {
+        let state = SampleState { seed, pixel_seed: seed, index: 0, dimension: 0 };
+        match self {
+            SamplerKind::Independent => Box::new(Independent::new(seed)),
+            SamplerKind::Stratified => Box::new(Stratified { state, samples_per_pixel: samples_per_pixel.max(1) }),
+            SamplerKind::Halton => Box::new(Halton { state }),
+            SamplerKind::Sobol => Box::new(Sobol { state }),
+        }
+    }
+}
+
+// Derives independent seeds from a base seed, using the SplitMix64 finalizer
+fn mix_seed(seed: u64, stream: u64) -> u64 {
+    let mut z = seed.wrapping_add(stream.wrapping_add(1).wrapping_mul(0x9E3779B97F4A7C15));
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+    z ^ (z >> 31)
+}
+
+fn hash(seed: u64, value: u64) -> u32 {
+    (mix_seed(seed, value) >> 32) as u32
+}
+
+// Uniform in [0, 1) from the top bits, which stays below 1 unlike dividing all of them
+fn to_unit(bits: u32) -> f32 {
+    (bits >> 8) as f32 / (1 << 24) as f32
+}
+
+// Each pixel gets its own random stream, so the image doesn't depend on the order the
+// pixels are rendered in
+fn pixel_seed(seed: u64, x: usize, y: usize) -> u64 {
+    mix_seed(mix_seed(seed, y as u64), x as u64)
+}
+
+pub struct Independent {
+    seed: u64,
+    rng: StdRng,
+}
+
+impl Independent {
+    fn new(seed: u64) -> Independent {
+        Independent { seed, rng: StdRng::seed_from_u64(seed) }
+    }
+}
+
+impl Sampler for Independent {
+    // Each sample has its own stream, so the image doesn't depend on which thread
+    // renders it
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.rng = StdRng::seed_from_u64(mix_seed(pixel_seed(self.seed, x, y), index as u64));
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        self.rng.gen()
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.rng.gen(), self.rng.gen())
+    }
+}
+
+// Where the other samplers are in the current sample
+struct SampleState {
+    seed: u64,
+    pixel_seed: u64,
+    index: usize,
+    dimension: u64,
+}
+
+impl SampleState {
+    fn start(&mut self, x: usize, y: usize, index: usize) {
+        self.pixel_seed = pixel_seed(self.seed, x, y);
+        self.index = index;
+        self.dimension = 0;
+    }
+
+    // Seed of the next dimension in the pixel, the same for all its samples
+    fn next_dimension(&mut self) -> u64 {
+        self.dimension += 1;
+        mix_seed(self.pixel_seed, self.dimension)
+    }
+
+    // Independent random number for the current sample
+    fn random(&self, dimension_seed: u64) -> f32 {
+        to_unit(hash(dimension_seed, self.index as u64))
+    }
+}
+
+// Pseudo-random permutation of 0..length, picked by the seed, from Andrew Kensler's
+// "Correlated Multi-Jittered Sampling"
+fn permute(mut i: u32, length: u32, seed: u32) -> u32 {
+    let mut w = length - 1;
+    w |= w >> 1;
+    w |= w >> 2;
+    w |= w >> 4;
+    w |= w >> 8;
+    w |= w >> 16;
+    loop {
+        i ^= seed;
+        i = i.wrapping_mul(0xe170893d);
+        i ^= seed >> 16;
+        i ^= (i & w) >> 4;
+        i ^= seed >> 8;
+        i = i.wrapping_mul(0x0929eb3f);
+        i ^= seed >> 23;
+        i ^= (i & w) >> 1;
+        i = i.wrapping_mul(1 | seed >> 27);
+        i = i.wrapping_mul(0x6935fa69);
+        i ^= (i & w) >> 11;
+        i = i.wrapping_mul(0x74dcb303);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0x9e501cc3);
+        i ^= (i & w) >> 2;
+        i = i.wrapping_mul(0xc860a3df);
+        i &= w;
+        i ^= i >> 5;
+        if i < length {
+            return i.wrapping_add(seed) % length
+        }
+    }
+}
+
+pub struct Stratified {
+    state: SampleState,
+    samples_per_pixel: usize,
+}
+
+impl Stratified {
+    // Cell of the current sample among the given number, in a different order in each
+    // dimension so that the dimensions aren't correlated
+    fn cell(&self, cells: usize, dimension_seed: u64) -> usize {
+        permute((self.state.index % cells) as u32, cells as u32, hash(dimension_seed, u64::MAX)) as usize
+    }
+}
+
+impl Sampler for Stratified {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension_seed = self.state.next_dimension();
+        let cells = self.samples_per_pixel;
+        let cell = self.cell(cells, dimension_seed);
+        ((cell as f32 + self.state.random(dimension_seed)) / cells as f32).min(1.0 - f32::EPSILON)
+    }
+
+    // Cells of a grid that is as square as possible, with at least as many cells as
+    // samples
+    fn next_2d(&mut self) -> (f32, f32) {
+        let dimension_seed = self.state.next_dimension();
+        let columns = (self.samples_per_pixel as f32).sqrt() as usize;
+        let rows = self.samples_per_pixel.div_ceil(columns);
+        let cell = self.cell(columns * rows, dimension_seed);
+        let jitter_x = self.state.random(dimension_seed);
+        let jitter_y = self.state.random(mix_seed(dimension_seed, 1));
+        let x = ((cell % columns) as f32 + jitter_x) / columns as f32;
+        let y = ((cell / columns) as f32 + jitter_y) / rows as f32;
+        (x.min(1.0 - f32::EPSILON), y.min(1.0 - f32::EPSILON))
+    }
+}
+
+// Bases of the dimensions of the Halton sequence, beyond which the dimensions are
+// independent random numbers
+const PRIMES: [u32; 32] = [
+    2, 3, 5, 7, 11, 13, 17, 19, 23, 29, 31, 37, 41, 43, 47, 53,
+    59, 61, 67, 71, 73, 79, 83, 89, 97, 101, 103, 107, 109, 113, 127, 131,
+];
+
+// The digits of the index in the base, mirrored around the point, with each digit
+// position permuted differently. Without the permutations, the few first indices only
+// cover a small part of [0, 1) in the large bases.
+fn scrambled_radical_inverse(base: u32, mut index: u32, seed: u64) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+    let mut position = 0;
+    // The zeros past the last digit of the index are permuted too, down to the precision
+    // of the result
+    while factor > 1e-8 {
+        let digit = permute(index % base, base, hash(seed, position));
+        result += digit as f64 * factor;
+        index /= base;
+        factor *= inverse_base;
+        position += 1;
+    }
+    (result as f32).min(1.0 - f32::EPSILON)
+}
+
+pub struct Halton {
+    state: SampleState,
+}
+
+impl Sampler for Halton {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        let dimension = self.state.dimension as usize;
+        let dimension_seed = self.state.next_dimension();
+        let Some(&base) = PRIMES.get(dimension) else {
+            return self.state.random(dimension_seed)
+        };
+        // The permutations are the same for all the samples of the pixel, and differ
+        // between pixels so that they don't repeat the same pattern
+        scrambled_radical_inverse(base, self.state.index as u32, dimension_seed)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        (self.next_1d(), self.next_1d())
+    }
+}
+
+// Hash that only changes the bits of a value into higher bits, as an Owen scrambling
+// does for reversed bits, from Samuli Laine and Tero Karras
+fn laine_karras_permutation(mut x: u32, seed: u32) -> u32 {
+    x = x.wrapping_add(seed);
+    x ^= x.wrapping_mul(0x6c50b47c);
+    x ^= x.wrapping_mul(0xb82f1e52);
+    x ^= x.wrapping_mul(0xc7afe638);
+    x ^= x.wrapping_mul(0x8d22f6e6);
+    x
+}
+
+fn owen_scramble(x: u32, seed: u32) -> u32 {
+    laine_karras_permutation(x.reverse_bits(), seed).reverse_bits()
+}
+
+// The first two dimensions of the Sobol sequence, as fractions of 2^32
+fn sobol(index: u32) -> (u32, u32) {
+    let first = index.reverse_bits();
+    let mut second = 0;
+    let mut direction = 1 << 31;
+    let mut bits = index;
+    while bits != 0 {
+        if bits & 1 != 0 {
+            second ^= direction;
+        }
+        bits >>= 1;
+        direction ^= direction >> 1;
+    }
+    (first, second)
+}
+
+// Every pair of dimensions uses the first two dimensions of the Sobol sequence, with the
+// order of the samples shuffled and the values scrambled differently, as in Brent
+// Burley's "Practical Hash-based Owen Scrambling"
+pub struct Sobol {
+    state: SampleState,
+}
+
+impl Sobol {
+    fn next_pair(&mut self) -> (u32, u32) {
+        let dimension_seed = self.state.next_dimension();
+        let index = owen_scramble(self.state.index as u32, hash(dimension_seed, 0));
+        let (x, y) = sobol(index);
+        (owen_scramble(x, hash(dimension_seed, 1)), owen_scramble(y, hash(dimension_seed, 2)))
+    }
+}
+
+impl Sampler for Sobol {
+    fn start_sample(&mut self, x: usize, y: usize, index: usize) {
+        self.state.start(x, y, index);
+    }
+
+    fn next_1d(&mut self) -> f32 {
+        to_unit(self.next_pair().0)
+    }
+
+    fn next_2d(&mut self) -> (f32, f32) {
+        let (x, y) = self.next_pair();
+        (to_unit(x), to_unit(y))
+    }
+}
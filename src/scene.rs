@@ -1,22 +1,114 @@
+use crate::bvh::Aabb;
+use crate::bvh::Bvh;
 use crate::geometry::Ray;
 use crate::geometry::Vec3;
 use crate::graphics::Color;
 use crate::graphics::BLACK;
+use crate::graphics::WHITE;
+use crate::lights::Light;
+use crate::sampler::Sampler;
+use std::sync::Arc;
+use std::sync::OnceLock;
 
 pub struct HitRecord {
     pub t: f32,
     pub hit_point: Vec3,
+    // Points out of the surface, whichever side the ray came from
     pub normal: Vec3,
+    // Surface coordinates of the hit point, for looking up textures
+    pub u: f32,
+    pub v: f32,
+    // Derivatives of the hit point by the surface coordinates, tangent to the surface
+    pub dpdu: Vec3,
+    pub dpdv: Vec3,
+    // Whether the ray hit the outer side of the surface, the side the normal points to
+    pub front_face: bool,
+}
+
+// Point picked on a light, with the density of picking its direction from the origin
+pub struct LightSample {
+    pub hit_record: HitRecord,
+    pub pdf: f32,
 }
 
 pub trait Hittable {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord>;
+    // Shapes that scatter rays at random inside them, like media, take random numbers
+    // from the sampler
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, sampler: &mut dyn Sampler) -> Option<HitRecord>;
+    fn bounding_box(&self) -> Aabb;
     // TODO: separate hittable from shape
     fn contains(&self, point: Vec3) -> bool;
+
+    // Picks a point on the shape as seen from the origin, for sampling its light. Shapes
+    // that don't support it are only lit by rays that happen to hit them.
+    fn sample_towards(&self, _origin: Vec3, _sampler: &mut dyn Sampler) -> Option<LightSample> {
+        None
+    }
+
+    // Density of sample_towards picking the direction, per unit solid angle
+    fn pdf_towards(&self, _origin: Vec3, _direction: Vec3) -> f32 {
+        0.0
+    }
 }
 
 pub trait Material {
-    fn scatter(&self, ray: &Ray, hit_record: &HitRecord) -> Option<(Color, Ray)>;
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)>;
+
+    // Light given off at the hit point towards the ray origin
+    fn emitted(&self, _ray: &Ray, _hit_record: &HitRecord) -> Color {
+        BLACK
+    }
+
+    // Objects with emissive materials are added to the lights of the scene
+    fn is_emissive(&self) -> bool {
+        false
+    }
+
+    // For materials with a known BSDF, which can be lit by sampling the lights: the BSDF
+    // times the cosine for scattering towards the direction, and the density of scatter
+    // picking it
+    fn evaluate(&self, _ray: &Ray, _hit_record: &HitRecord, _direction: Vec3) -> Option<(Color, f32)> {
+        None
+    }
+
+    // Color of the surface at the hit point, white for materials without one
+    fn albedo(&self, _hit_record: &HitRecord) -> Color {
+        WHITE
+    }
+
+    // Normal the surface is shaded with, which bumps can tilt away from the geometry
+    fn shading_normal(&self, hit_record: &HitRecord) -> Vec3 {
+        hit_record.normal
+    }
+}
+
+// Material that can be given to several objects
+pub type SharedMaterial = Arc<dyn Material + Send + Sync>;
+
+impl<M: Material + ?Sized> Material for Arc<M> {
+    fn scatter(&self, ray: &Ray, hit_record: &HitRecord, sampler: &mut dyn Sampler) -> Option<(Color, Ray)> {
+        (**self).scatter(ray, hit_record, sampler)
+    }
+
+    fn emitted(&self, ray: &Ray, hit_record: &HitRecord) -> Color {
+        (**self).emitted(ray, hit_record)
+    }
+
+    fn is_emissive(&self) -> bool {
+        (**self).is_emissive()
+    }
+
+    fn evaluate(&self, ray: &Ray, hit_record: &HitRecord, direction: Vec3) -> Option<(Color, f32)> {
+        (**self).evaluate(ray, hit_record, direction)
+    }
+
+    fn albedo(&self, hit_record: &HitRecord) -> Color {
+        (**self).albedo(hit_record)
+    }
+
+    fn shading_normal(&self, hit_record: &HitRecord) -> Vec3 {
+        (**self).shading_normal(hit_record)
+    }
 }
 
 // TODO: switch to take ray as input
@@ -25,11 +117,24 @@ pub type ColorMap = dyn Fn(Vec3) -> Color + Sync;
 pub struct SceneObject {
     pub shape: Box<dyn Hittable + Sync>,
     pub material: Box<dyn Material + Sync>,
+    // Identify the object and its material in the object and material id outputs,
+    // starting at 1
+    pub id: usize,
+    pub material_id: usize,
 }
 
 pub struct Scene {
     pub sky: Box<ColorMap>,
     objects: Vec<SceneObject>,
+    // Indices of the objects with emissive materials
+    area_lights: Vec<usize>,
+    lights: Vec<Box<dyn Light + Sync>>,
+    // Materials given to several objects, by their material id
+    shared_materials: Vec<(SharedMaterial, usize)>,
+    material_count: usize,
+    // Built by the first ray cast into the scene, once all objects are added
+    bvh: OnceLock<Bvh>,
+    linear_scan: bool,
 }
 
 impl Scene {
@@ -37,6 +142,12 @@ impl Scene {
         Scene {
             sky: Box::new(|_| BLACK),
             objects: vec![],
+            area_lights: vec![],
+            lights: vec![],
+            shared_materials: vec![],
+            material_count: 0,
+            bvh: OnceLock::new(),
+            linear_scan: false,
         }
     }
 
@@ -45,17 +156,98 @@ impl Scene {
         shape: impl Hittable + 'static + Sync,
         material: impl Material + 'static + Sync,
     ) {
-        self.objects.push(SceneObject {
-            shape: Box::new(shape),
-            material: Box::new(material),
-        });
+        self.add_boxed_object(Box::new(shape), Box::new(material));
+    }
+
+    // The object gets a material id of its own
+    pub fn add_boxed_object(&mut self, shape: Box<dyn Hittable + Sync>, material: Box<dyn Material + Sync>) {
+        self.material_count += 1;
+        self.push_object(shape, material, self.material_count);
+    }
+
+    // Objects sharing a material get the same material id
+    pub fn add_shared_object(&mut self, shape: Box<dyn Hittable + Sync>, material: SharedMaterial) {
+        let known = self.shared_materials.iter().find(|(shared, _)| Arc::ptr_eq(shared, &material));
+        let material_id = match known {
+            Some((_, material_id)) => *material_id,
+            None => {
+                self.material_count += 1;
+                self.shared_materials.push((material.clone(), self.material_count));
+                self.material_count
+            }
+        };
+        self.push_object(shape, Box::new(material), material_id);
+    }
+
+    fn push_object(&mut self, shape: Box<dyn Hittable + Sync>, material: Box<dyn Material + Sync>, material_id: usize) {
+        if material.is_emissive() {
+            self.area_lights.push(self.objects.len());
+        }
+        let id = self.objects.len() + 1;
+        self.objects.push(SceneObject { shape, material, id, material_id });
+        self.bvh = OnceLock::new();
+    }
+
+    // Picks one of the lights uniformly and a point on it
+    pub fn sample_light(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<(&SceneObject, LightSample)> {
+        if self.area_lights.is_empty() {
+            return None
+        }
+        let index = ((sampler.next_1d() * self.area_lights.len() as f32) as usize).min(self.area_lights.len() - 1);
+        let object = &self.objects[self.area_lights[index]];
+        let mut sample = object.shape.sample_towards(origin, sampler)?;
+        sample.pdf /= self.area_lights.len() as f32;
+        Some((object, sample))
+    }
+
+    // Density of sample_light picking the direction towards the given light
+    pub fn light_pdf(&self, light: &SceneObject, origin: Vec3, direction: Vec3) -> f32 {
+        if self.area_lights.is_empty() {
+            return 0.0
+        }
+        light.shape.pdf_towards(origin, direction) / self.area_lights.len() as f32
+    }
+
+    pub fn add_light(&mut self, light: impl Light + 'static + Sync) {
+        self.lights.push(Box::new(light));
+    }
+
+    // Point, spot and directional lights
+    pub fn lights(&self) -> &[Box<dyn Light + Sync>] {
+        &self.lights
+    }
+
+    pub fn object_count(&self) -> usize {
+        self.objects.len()
+    }
+
+    // Has first_hit test all objects instead of going through the BVH, for comparing them
+    pub fn set_linear_scan(&mut self, linear_scan: bool) {
+        self.linear_scan = linear_scan;
     }
 
-    pub fn first_hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<(&SceneObject, HitRecord)> {
+    fn bvh(&self) -> &Bvh {
+        self.bvh.get_or_init(|| {
+            let boxes: Vec<Aabb> = self.objects.iter().map(|object| object.shape.bounding_box()).collect();
+            Bvh::build(&boxes)
+        })
+    }
+
+    pub fn first_hit(&self, ray: &Ray, tmin: f32, tmax: f32, sampler: &mut dyn Sampler) -> Option<(&SceneObject, HitRecord)> {
         let mut closest: Option<(&SceneObject, HitRecord)> = None;
+        if !self.linear_scan {
+            self.bvh().traverse(ray, tmin, tmax, |index, closest_distance| {
+                let object = &self.objects[index];
+                let hit_record = object.shape.hit(ray, tmin, closest_distance, sampler)?;
+                let t = hit_record.t;
+                closest = Some((object, hit_record));
+                Some(t)
+            });
+            return closest
+        }
         let mut closest_distance = tmax;
         for object in self.objects.iter() {
-            if let Some(hit_record) = object.shape.hit(ray, tmin, closest_distance) {
+            if let Some(hit_record) = object.shape.hit(ray, tmin, closest_distance, sampler) {
                 closest_distance = hit_record.t;
                 closest = Some((object, hit_record));
             }
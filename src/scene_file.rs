@@ -0,0 +1,794 @@
+// Text format describing a scene and the camera looking at it. A file is a sequence of
+// items, each a keyword followed by a block of "key values..." settings in braces:
+//
+//   camera { lookfrom -2 2 -1  lookat 0 0 1  up 0 1 0  fov 20  defocus 10 }
+//   image { width 400  aspect_ratio 16/9 }
+//   render { samples_per_pixel 100  adaptive_threshold 0.02  max_depth 50  sampler sobol }
+//   render { samples_per_pixel 1024  progressive true  seed 1 }
+//   render { samples_per_pixel 4096  time_budget 30  target_noise 0.01 }
+//   sky { top 0.5 0.7 1  bottom 1 1 1 }
+//   texture tiles checker { even 0.2 0.3 0.1  odd 0.9 0.9 0.9  scale 0.5 }
+//   material ground opaque { albedo tiles  polish 0  bump tiles 0.01 }
+//   material lamp light { color 1 0.9 0.8  power 4 }
+//   sphere { center 0 -100.5 1  radius 100  material ground }
+//   spot_light { position 0 3 0  direction 0 -1 0  angle 30  falloff 5  intensity 10 }
+//
+// Colors of materials are given either as three numbers or as the name of a texture.
+// Point, spot and sun lights only light opaque materials with polish 0.
+// Comments start with '#' and run to the end of the line.
+
+use crate::camera::Bearings;
+use crate::camera::Camera;
+use crate::camera::ImageSettings;
+use crate::camera::RenderSettings;
+use crate::geometry::Vec3;
+use crate::graphics::Color;
+use crate::graphics::Image;
+use crate::lights::DirectionalLight;
+use crate::lights::PointLight;
+use crate::lights::SpotLight;
+use crate::material::Bump;
+use crate::material::DiffuseLight;
+use crate::material::Gas;
+use crate::material::Opaque;
+use crate::material::Transparent;
+use crate::obj::load_obj;
+use crate::sampler::SamplerKind;
+use crate::scene::Hittable;
+use crate::scene::Scene;
+use crate::scene::SharedMaterial;
+use crate::shapes::Medium;
+use crate::shapes::Sphere;
+use crate::shapes::Triangle;
+use crate::texture::Checker;
+use crate::texture::ImageTexture;
+use crate::texture::Marble;
+use crate::texture::Noise;
+use crate::texture::Perlin;
+use crate::texture::SharedTexture;
+use crate::texture::Turbulence;
+use crate::texture::Wood;
+use crate::tiles::TileOrder;
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub file: PathBuf,
+    // Line and column are zero when the error is not related to a position in the file
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.line == 0 {
+            write!(f, "{}: {}", self.file.display(), self.message)
+        } else {
+            write!(f, "{}:{}:{}: {}", self.file.display(), self.line, self.column, self.message)
+        }
+    }
+}
+
+impl std::error::Error for SceneError {}
+
+impl From<SceneError> for std::io::Error {
+    fn from(error: SceneError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, error.to_string())
+    }
+}
+
+pub struct SceneDescription {
+    pub scene: Scene,
+    pub bearings: Bearings,
+    pub image_settings: ImageSettings,
+    pub render_settings: RenderSettings,
+}
+
+impl SceneDescription {
+    pub fn camera(&self) -> Camera {
+        Camera::new(self.bearings, self.image_settings, self.render_settings)
+    }
+}
+
+#[derive(PartialEq)]
+enum TokenKind {
+    Word(String),
+    Number(f32),
+    Text(String),
+    OpenBrace,
+    CloseBrace,
+    End,
+}
+
+struct Token {
+    kind: TokenKind,
+    // As written in the file, for integers that an f32 can't hold exactly
+    text: String,
+    line: usize,
+    column: usize,
+}
+
+impl fmt::Display for TokenKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TokenKind::Word(word) => write!(f, "'{}'", word),
+            TokenKind::Number(x) => write!(f, "number {}", x),
+            TokenKind::Text(text) => write!(f, "\"{}\"", text),
+            TokenKind::OpenBrace => write!(f, "'{{'"),
+            TokenKind::CloseBrace => write!(f, "'}}'"),
+            TokenKind::End => write!(f, "end of file"),
+        }
+    }
+}
+
+// Errors from the tokenizer and parser, positioned by line and column
+type ParseResult<T> = Result<T, (usize, usize, String)>;
+
+fn parse_number(text: &str) -> Option<f32> {
+    // Allow fractions such as 16/9
+    match text.split_once('/') {
+        Some((numerator, denominator)) => Some(numerator.parse::<f32>().ok()? / denominator.parse::<f32>().ok()?),
+        None => text.parse().ok(),
+    }
+}
+
+fn tokenize(text: &str) -> ParseResult<Vec<Token>> {
+    let mut tokens = vec![];
+    for (line_index, line) in text.lines().enumerate() {
+        let line_number = line_index + 1;
+        let chars: Vec<char> = line.chars().collect();
+        let mut i = 0;
+        while i < chars.len() {
+            let c = chars[i];
+            let column = i + 1;
+            let start = i;
+            if c == '#' {
+                break
+            }
+            if c.is_whitespace() {
+                i += 1;
+                continue
+            }
+            let kind = if c == '{' {
+                i += 1;
+                TokenKind::OpenBrace
+            } else if c == '}' {
+                i += 1;
+                TokenKind::CloseBrace
+            } else if c == '"' {
+                i += 1;
+                while i < chars.len() && chars[i] != '"' {
+                    i += 1;
+                }
+                if i == chars.len() {
+                    return Err((line_number, column, "unterminated string".to_string()))
+                }
+                i += 1;
+                TokenKind::Text(chars[start + 1..i - 1].iter().collect())
+            } else if c.is_ascii_digit() || c == '-' || c == '+' || c == '.' {
+                while i < chars.len() && (chars[i].is_ascii_alphanumeric() || "+-./".contains(chars[i])) {
+                    i += 1;
+                }
+                let text: String = chars[start..i].iter().collect();
+                match parse_number(&text) {
+                    Some(x) => TokenKind::Number(x),
+                    None => return Err((line_number, column, format!("invalid number '{}'", text))),
+                }
+            } else if c.is_alphabetic() || c == '_' {
+                while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                    i += 1;
+                }
+                TokenKind::Word(chars[start..i].iter().collect())
+            } else {
+                return Err((line_number, column, format!("unexpected character '{}'", c)))
+            };
+            tokens.push(Token { kind, text: chars[start..i].iter().collect(), line: line_number, column });
+        }
+    }
+    let line = text.lines().count() + 1;
+    tokens.push(Token { kind: TokenKind::End, text: String::new(), line, column: 1 });
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    directory: &'a Path,
+    materials: HashMap<String, SharedMaterial>,
+    textures: HashMap<String, SharedTexture>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> &Token {
+        &self.tokens[self.position]
+    }
+
+    fn next(&mut self) -> &Token {
+        let token = &self.tokens[self.position];
+        if token.kind != TokenKind::End {
+            self.position += 1;
+        }
+        token
+    }
+
+    fn error<T>(token: &Token, message: String) -> ParseResult<T> {
+        Err((token.line, token.column, message))
+    }
+
+    fn expect_word(&mut self) -> ParseResult<(String, usize, usize)> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Word(word) => Ok((word.clone(), token.line, token.column)),
+            other => Self::error(token, format!("expected a name, found {}", other)),
+        }
+    }
+
+    fn expect_number(&mut self) -> ParseResult<f32> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Number(x) => Ok(x),
+            ref other => Self::error(token, format!("expected a number, found {}", other)),
+        }
+    }
+
+    fn expect_positive(&mut self) -> ParseResult<f32> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Number(x) if x > 0.0 && x.is_finite() => Ok(x),
+            ref other => Self::error(token, format!("expected a positive number, found {}", other)),
+        }
+    }
+
+    fn expect_count(&mut self) -> ParseResult<usize> {
+        let token = self.next();
+        match token.kind {
+            TokenKind::Number(x) if x >= 1.0 && x.fract() == 0.0 => Ok(x as usize),
+            ref other => Self::error(token, format!("expected a positive integer, found {}", other)),
+        }
+    }
+
+    fn expect_bool(&mut self) -> ParseResult<bool> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Word(word) if word == "true" => Ok(true),
+            TokenKind::Word(word) if word == "false" => Ok(false),
+            other => Self::error(token, format!("expected true or false, found {}", other)),
+        }
+    }
+
+    // Read from the text of the number, as seeds go beyond the integers of an f32
+    fn expect_seed(&mut self) -> ParseResult<u64> {
+        let token = self.next();
+        match (&token.kind, token.text.parse()) {
+            (TokenKind::Number(_), Ok(seed)) => Ok(seed),
+            (other, _) => Self::error(token, format!("expected a non-negative integer, found {}", other)),
+        }
+    }
+
+    fn expect_vec3(&mut self) -> ParseResult<Vec3> {
+        Ok(Vec3(self.expect_number()?, self.expect_number()?, self.expect_number()?))
+    }
+
+    fn expect_color(&mut self) -> ParseResult<Color> {
+        Ok(Color { red: self.expect_number()?, green: self.expect_number()?, blue: self.expect_number()? })
+    }
+
+    fn expect_text(&mut self) -> ParseResult<String> {
+        let token = self.next();
+        match &token.kind {
+            TokenKind::Text(text) => Ok(text.clone()),
+            other => Self::error(token, format!("expected a quoted string, found {}", other)),
+        }
+    }
+
+    // Either a color or the name of a texture
+    fn expect_texture(&mut self) -> ParseResult<SharedTexture> {
+        if let TokenKind::Number(_) = self.peek().kind {
+            return Ok(Arc::new(self.expect_color()?))
+        }
+        let (name, line, column) = self.expect_word()?;
+        match self.textures.get(&name) {
+            Some(texture) => Ok(texture.clone()),
+            None => Err((line, column, format!("unknown texture '{}'", name))),
+        }
+    }
+
+    fn expect_material(&mut self) -> ParseResult<SharedMaterial> {
+        let (name, line, column) = self.expect_word()?;
+        match self.materials.get(&name) {
+            Some(material) => Ok(material.clone()),
+            None => Err((line, column, format!("unknown material '{}'", name))),
+        }
+    }
+
+    // Parses "{ key values... }", handing each key to parse_setting which returns
+    // false for unknown keys
+    fn parse_block(
+        &mut self,
+        mut parse_setting: impl FnMut(&mut Self, &str) -> ParseResult<bool>,
+    ) -> ParseResult<()> {
+        let token = self.next();
+        if token.kind != TokenKind::OpenBrace {
+            return Self::error(token, format!("expected '{{', found {}", token.kind))
+        }
+        loop {
+            if self.peek().kind == TokenKind::CloseBrace {
+                self.next();
+                return Ok(())
+            }
+            let (key, line, column) = self.expect_word()?;
+            if !parse_setting(self, &key)? {
+                return Err((line, column, format!("unknown setting '{}'", key)))
+            }
+        }
+    }
+
+    fn parse_bearings(&mut self) -> ParseResult<Bearings> {
+        let (mut lookfrom, mut lookat, mut up, mut fov_degrees) = (None, None, None, None);
+        let mut defocus_degrees = 0.0;
+        self.parse_block(|parser, key| {
+            match key {
+                "lookfrom" => lookfrom = Some(parser.expect_vec3()?),
+                "lookat" => lookat = Some(parser.expect_vec3()?),
+                "up" => up = Some(parser.expect_vec3()?),
+                "fov" => fov_degrees = Some(parser.expect_number()?),
+                "defocus" => defocus_degrees = parser.expect_number()?,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        Ok(Bearings {
+            lookfrom: self.required(lookfrom, "lookfrom", "camera")?,
+            lookat: self.required(lookat, "lookat", "camera")?,
+            up: up.unwrap_or(Vec3(0.0, 1.0, 0.0)),
+            fov_degrees: self.required(fov_degrees, "fov", "camera")?,
+            defocus_degrees,
+        })
+    }
+
+    // Reports a missing setting at the closing brace of the block
+    fn required<T>(&self, value: Option<T>, key: &str, block: &str) -> ParseResult<T> {
+        let token = &self.tokens[self.position - 1];
+        value.ok_or_else(|| (token.line, token.column, format!("missing '{}' in {}", key, block)))
+    }
+
+    fn parse_material(&mut self) -> ParseResult<(String, SharedMaterial)> {
+        let (name, _, _) = self.expect_word()?;
+        let (kind, line, column) = self.expect_word()?;
+        let material: SharedMaterial = match kind.as_str() {
+            "opaque" => {
+                let mut material = Opaque { albedo: Arc::new(Color { red: 0.8, green: 0.8, blue: 0.8 }), polish: 0.0, bump: None };
+                self.parse_block(|parser, key| {
+                    match key {
+                        "albedo" => material.albedo = parser.expect_texture()?,
+                        "polish" => material.polish = parser.expect_number()?,
+                        "bump" => material.bump = Some(Bump { height: parser.expect_texture()?, scale: parser.expect_number()? }),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Arc::new(material)
+            }
+            "transparent" => {
+                let mut material = Transparent { refraction_index: 1.5 };
+                self.parse_block(|parser, key| {
+                    match key {
+                        "refraction_index" => material.refraction_index = parser.expect_number()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Arc::new(material)
+            }
+            "gas" => {
+                let mut material = Gas { albedo: Arc::new(Color { red: 1.0, green: 1.0, blue: 1.0 }), isotropy: 1.0 };
+                self.parse_block(|parser, key| {
+                    match key {
+                        "albedo" => material.albedo = parser.expect_texture()?,
+                        "isotropy" => material.isotropy = parser.expect_number()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Arc::new(material)
+            }
+            "light" => {
+                let mut material = DiffuseLight { color: Arc::new(Color { red: 1.0, green: 1.0, blue: 1.0 }), power: 1.0 };
+                self.parse_block(|parser, key| {
+                    match key {
+                        "color" => material.color = parser.expect_texture()?,
+                        "power" => material.power = parser.expect_number()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Arc::new(material)
+            }
+            _ => return Err((line, column, format!("unknown material type '{}'", kind))),
+        };
+        Ok((name, material))
+    }
+
+    fn parse_texture(&mut self) -> ParseResult<(String, SharedTexture)> {
+        let (name, _, _) = self.expect_word()?;
+        let (kind, line, column) = self.expect_word()?;
+        let white = Color { red: 1.0, green: 1.0, blue: 1.0 };
+        let texture: SharedTexture = match kind.as_str() {
+            "checker" => {
+                let (mut even, mut odd) = (None, None);
+                let mut scale = 1.0;
+                self.parse_block(|parser, key| {
+                    match key {
+                        "even" => even = Some(parser.expect_texture()?),
+                        "odd" => odd = Some(parser.expect_texture()?),
+                        "scale" => scale = parser.expect_number()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Arc::new(Checker {
+                    even: self.required(even, "even", "checker")?,
+                    odd: self.required(odd, "odd", "checker")?,
+                    scale,
+                })
+            }
+            "noise" | "turbulence" | "marble" | "wood" => {
+                let mut color = white;
+                let mut dark = Color { red: 0.3, green: 0.15, blue: 0.05 };
+                let mut scale = 1.0;
+                let mut seed = 0;
+                self.parse_block(|parser, key| {
+                    match (kind.as_str(), key) {
+                        ("wood", "light") => color = parser.expect_color()?,
+                        ("wood", "dark") => dark = parser.expect_color()?,
+                        ("noise" | "turbulence" | "marble", "color") => color = parser.expect_color()?,
+                        (_, "scale") => scale = parser.expect_number()?,
+                        (_, "seed") => seed = parser.expect_seed()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                let perlin = Perlin::new(seed);
+                match kind.as_str() {
+                    "noise" => Arc::new(Noise { perlin, color, scale }),
+                    "turbulence" => Arc::new(Turbulence { perlin, color, scale }),
+                    "marble" => Arc::new(Marble { perlin, color, scale }),
+                    _ => Arc::new(Wood { perlin, light: color, dark, scale }),
+                }
+            }
+            "image" => {
+                let mut file = None;
+                self.parse_block(|parser, key| {
+                    match key {
+                        "file" => file = Some(parser.expect_text()?),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                let file = self.directory.join(self.required(file, "file", "image")?);
+                let image = Image::load(&file.to_string_lossy())
+                    .map_err(|error| (line, column, format!("can't read {}: {}", file.display(), error)))?;
+                Arc::new(ImageTexture { image })
+            }
+            _ => return Err((line, column, format!("unknown texture type '{}'", kind))),
+        };
+        Ok((name, texture))
+    }
+
+    // Parses the block of a shape, with an optional material setting
+    fn parse_shape(&mut self, kind: &str) -> ParseResult<(Box<dyn Hittable + Send + Sync>, Option<SharedMaterial>)> {
+        let mut material = None;
+        let shape: Box<dyn Hittable + Send + Sync> = match kind {
+            "sphere" => {
+                let (mut center, mut radius) = (None, None);
+                self.parse_block(|parser, key| {
+                    match key {
+                        "center" => center = Some(parser.expect_vec3()?),
+                        "radius" => radius = Some(parser.expect_number()?),
+                        "material" => material = Some(parser.expect_material()?),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Box::new(Sphere {
+                    center: self.required(center, "center", "sphere")?,
+                    radius: self.required(radius, "radius", "sphere")?,
+                })
+            }
+            "triangle" => {
+                let mut vertices = None;
+                self.parse_block(|parser, key| {
+                    match key {
+                        "vertices" => vertices = Some([parser.expect_vec3()?, parser.expect_vec3()?, parser.expect_vec3()?]),
+                        "material" => material = Some(parser.expect_material()?),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Box::new(Triangle { vertices: self.required(vertices, "vertices", "triangle")? })
+            }
+            "medium" => {
+                let (mut density, mut shape) = (None, None);
+                self.parse_block(|parser, key| {
+                    match key {
+                        "density" => density = Some(parser.expect_number()?),
+                        "material" => material = Some(parser.expect_material()?),
+                        "sphere" | "triangle" | "medium" => shape = Some(parser.parse_shape(key)?.0),
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?;
+                Box::new(Medium {
+                    shape: self.required(shape, "shape", "medium")?,
+                    density: self.required(density, "density", "medium")?,
+                })
+            }
+            _ => unreachable!(),
+        };
+        Ok((shape, material))
+    }
+
+    fn parse_sky(&mut self, scene: &mut Scene) -> ParseResult<()> {
+        let (mut top, mut bottom) = (None, None);
+        self.parse_block(|parser, key| {
+            match key {
+                "color" => {
+                    let color = parser.expect_color()?;
+                    top = Some(color);
+                    bottom = Some(color);
+                }
+                "top" => top = Some(parser.expect_color()?),
+                "bottom" => bottom = Some(parser.expect_color()?),
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let top = self.required(top, "top", "sky")?;
+        let bottom = self.required(bottom, "bottom", "sky")?;
+        scene.sky = Box::new(move |direction: Vec3| Color::mix(bottom, top, 0.5 * (direction.1 + 1.0)));
+        Ok(())
+    }
+
+    fn parse_light(&mut self, kind: &str, scene: &mut Scene) -> ParseResult<()> {
+        let mut position = None;
+        let mut direction = None;
+        let mut color = Color { red: 1.0, green: 1.0, blue: 1.0 };
+        let mut intensity = 1.0;
+        let mut angle_degrees = None;
+        let mut falloff_degrees = 0.0;
+        let mut angular_diameter_degrees = 0.0;
+        self.parse_block(|parser, key| {
+            match (kind, key) {
+                (_, "color") => color = parser.expect_color()?,
+                (_, "intensity") => intensity = parser.expect_number()?,
+                ("point_light" | "spot_light", "position") => position = Some(parser.expect_vec3()?),
+                ("spot_light" | "sun", "direction") => direction = Some(parser.expect_vec3()?.normalize()),
+                ("spot_light", "angle") => angle_degrees = Some(parser.expect_number()?),
+                ("spot_light", "falloff") => falloff_degrees = parser.expect_number()?,
+                ("sun", "angular_diameter") => angular_diameter_degrees = parser.expect_number()?,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        match kind {
+            "point_light" => scene.add_light(PointLight {
+                position: self.required(position, "position", kind)?,
+                color,
+                intensity,
+            }),
+            "spot_light" => scene.add_light(SpotLight {
+                position: self.required(position, "position", kind)?,
+                direction: self.required(direction, "direction", kind)?,
+                color,
+                intensity,
+                angle_degrees: self.required(angle_degrees, "angle", kind)?,
+                falloff_degrees,
+            }),
+            _ => scene.add_light(DirectionalLight {
+                direction: self.required(direction, "direction", kind)?,
+                color,
+                intensity,
+                angular_diameter_degrees,
+            }),
+        }
+        Ok(())
+    }
+
+    fn parse_mesh(&mut self, scene: &mut Scene) -> ParseResult<()> {
+        let mut file = None;
+        let mut scale = 1.0;
+        let mut offset = Vec3(0.0, 0.0, 0.0);
+        let (line, column) = (self.peek().line, self.peek().column);
+        self.parse_block(|parser, key| {
+            match key {
+                "file" => file = Some(parser.expect_text()?),
+                "scale" => scale = parser.expect_number()?,
+                "translate" => offset = parser.expect_vec3()?,
+                _ => return Ok(false),
+            }
+            Ok(true)
+        })?;
+        let file = self.required(file, "file", "mesh")?;
+        load_obj(scene, &self.directory.join(file), scale, offset)
+            .map_err(|error| (line, column, error.to_string()))
+    }
+
+    fn parse_file(&mut self) -> ParseResult<SceneDescription> {
+        let mut scene = Scene::new();
+        let mut bearings = None;
+        let mut image_settings = ImageSettings { image_width: 400, aspect_ratio: 16.0 / 9.0 };
+        let mut render_settings = RenderSettings::default();
+        loop {
+            if self.peek().kind == TokenKind::End {
+                break
+            }
+            let (keyword, line, column) = self.expect_word()?;
+            match keyword.as_str() {
+                "camera" => bearings = Some(self.parse_bearings()?),
+                "image" => self.parse_block(|parser, key| {
+                    match key {
+                        "width" => image_settings.image_width = parser.expect_count()?,
+                        "aspect_ratio" => image_settings.aspect_ratio = parser.expect_positive()?,
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?,
+                "render" => self.parse_block(|parser, key| {
+                    match key {
+                        "samples_per_pixel" => render_settings.samples_per_pixel = parser.expect_count()?,
+                        "adaptive_threshold" => render_settings.adaptive_threshold = Some(parser.expect_number()?),
+                        "min_samples_per_pixel" => render_settings.min_samples_per_pixel = parser.expect_count()?,
+                        "progressive" => render_settings.progressive = parser.expect_bool()?,
+                        "time_budget" => {
+                            render_settings.time_budget = Some(parser.expect_number()? as f64);
+                            render_settings.progressive = true;
+                        }
+                        "target_noise" => {
+                            render_settings.target_noise = Some(parser.expect_number()?);
+                            render_settings.progressive = true;
+                        }
+                        "max_depth" => render_settings.max_depth = parser.expect_count()?,
+                        "tile_size" => render_settings.tile_size = parser.expect_count()?,
+                        "tile_order" => {
+                            let (name, line, column) = parser.expect_word()?;
+                            render_settings.tile_order = TileOrder::from_name(&name)
+                                .ok_or((line, column, format!("unknown tile order '{}'", name)))?;
+                        }
+                        "seed" => render_settings.seed = Some(parser.expect_seed()?),
+                        "sampler" => {
+                            let (name, line, column) = parser.expect_word()?;
+                            render_settings.sampler = SamplerKind::from_name(&name)
+                                .ok_or((line, column, format!("unknown sampler '{}'", name)))?;
+                        }
+                        _ => return Ok(false),
+                    }
+                    Ok(true)
+                })?,
+                "sky" => self.parse_sky(&mut scene)?,
+                "material" => {
+                    let (name, material) = self.parse_material()?;
+                    if self.materials.insert(name.clone(), material).is_some() {
+                        return Err((line, column, format!("material '{}' is defined twice", name)))
+                    }
+                }
+                "texture" => {
+                    let (name, texture) = self.parse_texture()?;
+                    if self.textures.insert(name.clone(), texture).is_some() {
+                        return Err((line, column, format!("texture '{}' is defined twice", name)))
+                    }
+                }
+                "sphere" | "triangle" | "medium" => {
+                    let (shape, material) = self.parse_shape(&keyword)?;
+                    let material = self.required(material, "material", &keyword)?;
+                    scene.add_shared_object(shape, material);
+                }
+                "mesh" => self.parse_mesh(&mut scene)?,
+                "point_light" | "spot_light" | "sun" => self.parse_light(&keyword, &mut scene)?,
+                _ => return Err((line, column, format!("unknown item '{}'", keyword))),
+            }
+        }
+        let Some(bearings) = bearings else {
+            return Err((0, 0, "missing camera".to_string()))
+        };
+        Ok(SceneDescription { scene, bearings, image_settings, render_settings })
+    }
+}
+
+pub fn load_scene(path: &Path) -> Result<SceneDescription, SceneError> {
+    let text = fs::read_to_string(path)
+        .map_err(|io_error| SceneError { file: path.to_path_buf(), line: 0, column: 0, message: io_error.to_string() })?;
+    parse_scene(&text, path)
+}
+
+// Parses the text of a scene file, with meshes found next to the path
+fn parse_scene(text: &str, path: &Path) -> Result<SceneDescription, SceneError> {
+    let error = |(line, column, message)| SceneError { file: path.to_path_buf(), line, column, message };
+    let mut parser = Parser {
+        tokens: tokenize(text).map_err(error)?,
+        position: 0,
+        directory: path.parent().unwrap_or(Path::new("")),
+        materials: HashMap::new(),
+        textures: HashMap::new(),
+    };
+    parser.parse_file().map_err(error)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CAMERA: &str = "camera { lookfrom 0 0 -1  lookat 0 0 0  fov 40 }\n";
+
+    fn parse(text: &str) -> Result<SceneDescription, SceneError> {
+        parse_scene(text, Path::new("test.scene"))
+    }
+
+    fn error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("parsed {:?}", text),
+            Err(error) => error.to_string(),
+        }
+    }
+
+    #[test]
+    fn settings() {
+        let description = parse(&format!("{}image {{ width 64  aspect_ratio 16/9 }}  # wide\nrender {{ max_depth 5 }}", CAMERA)).unwrap();
+        assert_eq!(description.image_settings.image_width, 64);
+        assert_eq!(description.image_settings.aspect_ratio, 16.0 / 9.0);
+        assert_eq!(description.render_settings.max_depth, 5);
+        assert_eq!(description.render_settings.samples_per_pixel, RenderSettings::default().samples_per_pixel);
+        assert_eq!(description.bearings.fov_degrees, 40.0);
+    }
+
+    #[test]
+    fn positioned_errors() {
+        assert_eq!(error("sky { color 1 1 1 }"), "test.scene: missing camera");
+        assert_eq!(error(&format!("{}cube {{ }}", CAMERA)), "test.scene:2:1: unknown item 'cube'");
+        assert_eq!(error("image { width 10  height 5 }"), "test.scene:1:19: unknown setting 'height'");
+        assert_eq!(error("image {\n  width 1x0 }"), "test.scene:2:9: invalid number '1x0'");
+        assert_eq!(error("image { width @ }"), "test.scene:1:15: unexpected character '@'");
+        assert_eq!(error("mesh { file \"teapot.obj }"), "test.scene:1:13: unterminated string");
+        assert_eq!(error("image { width 10"), "test.scene:2:1: expected a name, found end of file");
+        assert_eq!(error("image width 10"), "test.scene:1:7: expected '{', found 'width'");
+        // Missing settings are reported at the end of their block
+        assert_eq!(error("camera { lookat 0 0 0  fov 20 }"), "test.scene:1:31: missing 'lookfrom' in camera");
+        assert_eq!(error("sphere { center 0 0 0  radius 1  material chalk }"), "test.scene:1:43: unknown material 'chalk'");
+        assert_eq!(error("material a opaque { }\nmaterial a gas { }"), "test.scene:2:1: material 'a' is defined twice");
+        assert_eq!(error("material a metal { }"), "test.scene:1:12: unknown material type 'metal'");
+    }
+
+    #[test]
+    fn bad_values() {
+        assert_eq!(error("image { width -3 }"), "test.scene:1:15: expected a positive integer, found number -3");
+        assert_eq!(error("image { width 2.5 }"), "test.scene:1:15: expected a positive integer, found number 2.5");
+        assert_eq!(error("render { samples_per_pixel 0 }"), "test.scene:1:28: expected a positive integer, found number 0");
+        assert_eq!(error("render { max_depth ten }"), "test.scene:1:20: expected a positive integer, found 'ten'");
+        assert_eq!(error("render { progressive 1 }"), "test.scene:1:22: expected true or false, found number 1");
+        assert_eq!(error("camera { fov wide }"), "test.scene:1:14: expected a number, found 'wide'");
+        for aspect_ratio in ["0", "-1.5", "1/0", "0/0"] {
+            let message = error(&format!("image {{ aspect_ratio {} }}", aspect_ratio));
+            assert!(message.starts_with("test.scene:1:22: expected a positive number, found number"), "{}", message);
+        }
+    }
+
+    #[test]
+    fn seeds() {
+        assert_eq!(parse(CAMERA).unwrap().render_settings.seed, None);
+        // Beyond 2^24, where f32 numbers skip integers, and up to the largest u64
+        for (text, seed) in [("123456789", 123456789), ("16777217", 16777217), ("18446744073709551615", u64::MAX)] {
+            let description = parse(&format!("{}render {{ seed {} }}", CAMERA, text)).unwrap();
+            assert_eq!(description.render_settings.seed, Some(seed), "seed {}", text);
+        }
+        for text in ["1.5", "-1", "1e3", "16/4", "18446744073709551616"] {
+            let message = error(&format!("render {{ seed {} }}", text));
+            assert!(message.starts_with("test.scene:1:15: expected a non-negative integer, found number"), "{}", message);
+        }
+        assert_eq!(error("render { seed random }"), "test.scene:1:15: expected a non-negative integer, found 'random'");
+    }
+}
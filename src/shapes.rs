@@ -1,8 +1,16 @@
+use crate::bvh::Aabb;
+use crate::bvh::Bvh;
 use crate::geometry::Ray;
 use crate::geometry::Vec3;
+use crate::geometry::cross_product;
 use crate::geometry::dot;
+use crate::geometry::orthonormal_basis;
+use crate::sampler::Sampler;
 use crate::scene::HitRecord;
 use crate::scene::Hittable;
+use crate::scene::LightSample;
+use std::f32::consts::PI;
+use std::f32::consts::TAU;
 
 pub struct Sphere {
     pub center: Vec3,
@@ -10,7 +18,46 @@ pub struct Sphere {
 }
 
 impl Hittable for Sphere {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        self.intersect(ray, tmin, tmax)
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        // Radius may be negative for hollow spheres
+        let r = self.radius.abs();
+        Aabb {
+            min: self.center - Vec3(r, r, r),
+            max: self.center + Vec3(r, r, r),
+        }
+    }
+
+    fn contains(&self, point: Vec3) -> bool {
+        (point - self.center).norm2() < self.radius * self.radius
+    }
+
+    // Samples the cone of directions from the origin to the sphere
+    fn sample_towards(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let (axis, cos_max, pdf) = self.cone_towards(origin)?;
+        let (s, t) = sampler.next_2d();
+        let cos_theta = 1.0 - s * (1.0 - cos_max);
+        let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+        let phi = TAU * t;
+        let (u, v) = orthonormal_basis(axis);
+        let direction = sin_theta * phi.cos() * u + sin_theta * phi.sin() * v + cos_theta * axis;
+        let hit_record = self.intersect(&Ray { origin, direction }, 0.0, f32::INFINITY)?;
+        Some(LightSample { hit_record, pdf })
+    }
+
+    fn pdf_towards(&self, origin: Vec3, direction: Vec3) -> f32 {
+        match self.cone_towards(origin) {
+            Some((axis, cos_max, pdf)) if dot(direction, axis) >= cos_max => pdf,
+            _ => 0.0,
+        }
+    }
+}
+
+impl Sphere {
+    fn intersect(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
         let oc = ray.origin - self.center;
         let a = ray.direction.norm2();
         let b = dot(oc, ray.direction);
@@ -28,11 +75,39 @@ impl Hittable for Sphere {
         }
         let hit_point = ray.at(t);
         let normal = (hit_point - self.center) / self.radius;
-        Some(HitRecord{ t, hit_point, normal })
+        let (u, v, dpdu, dpdv) = self.surface_coordinates(hit_point);
+        let front_face = dot(ray.direction, normal) < 0.0;
+        Some(HitRecord{ t, hit_point, normal, u, v, dpdu, dpdv, front_face })
     }
 
-    fn contains(&self, point: Vec3) -> bool {
-        (point - self.center).norm2() < self.radius * self.radius
+    // Longitude and latitude of the point, both scaled to [0, 1] with v going up from the
+    // bottom pole, and the tangents along them
+    fn surface_coordinates(&self, point: Vec3) -> (f32, f32, Vec3, Vec3) {
+        let radius = self.radius.abs();
+        let direction = (point - self.center) / radius;
+        let theta = (-direction.1).clamp(-1.0, 1.0).acos();
+        let phi = (-direction.2).atan2(direction.0);
+        let (sin_theta, cos_theta) = theta.sin_cos();
+        let (sin_phi, cos_phi) = phi.sin_cos();
+        let dpdu = (TAU * radius) * Vec3(-sin_theta * sin_phi, 0.0, -sin_theta * cos_phi);
+        let dpdv = (PI * radius) * Vec3(cos_theta * cos_phi, sin_theta, -cos_theta * sin_phi);
+        ((phi + PI) / TAU, theta / PI, dpdu, dpdv)
+    }
+
+    // Axis and cosine of the half angle of the cone of directions from the origin to the
+    // sphere, and the uniform density of directions in it
+    fn cone_towards(&self, origin: Vec3) -> Option<(Vec3, f32, f32)> {
+        let to_center = self.center - origin;
+        let distance2 = to_center.norm2();
+        let sin2_max = self.radius * self.radius / distance2;
+        if sin2_max >= 1.0 {
+            // Inside the sphere
+            return None
+        }
+        let cos_max = (1.0 - sin2_max).sqrt();
+        // 1 - cos_max, avoiding cancellation for far spheres
+        let solid_angle = TAU * sin2_max / (1.0 + cos_max);
+        Some((to_center / distance2.sqrt(), cos_max, 1.0 / solid_angle))
     }
 }
 
@@ -42,33 +117,388 @@ pub struct Medium {
 }
 
 impl Hittable for Medium {
-    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<HitRecord> {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, sampler: &mut dyn Sampler) -> Option<HitRecord> {
         let entry_time = if self.shape.contains(ray.at(tmin)) {
             tmin
         } else {
-            match self.shape.hit(ray, tmin, tmax) {
+            match self.shape.hit(ray, tmin, tmax, sampler) {
                 None => { return None }
                 Some(entry) => { entry.t }
             }
         };
-        let exit_time = match self.shape.hit(ray, entry_time, tmax) {
+        let exit_time = match self.shape.hit(ray, entry_time, tmax, sampler) {
             None => tmax,
             Some(exit) => exit.t,
         };
-        let free_path = -(1.0 - rand::random::<f32>()).ln() / self.density;
+        let free_path = -(1.0 - sampler.next_1d()).ln() / self.density;
         let t = entry_time + free_path;
         if entry_time + free_path < exit_time - entry_time {
             Some(HitRecord {
                 t,
                 hit_point: ray.at(t),
                 normal: ray.direction,
+                // Inside the medium there is no surface
+                u: 0.0,
+                v: 0.0,
+                dpdu: Vec3(0.0, 0.0, 0.0),
+                dpdv: Vec3(0.0, 0.0, 0.0),
+                front_face: true,
             })
         } else {
             None
         }
     }
 
+    fn bounding_box(&self) -> Aabb {
+        self.shape.bounding_box()
+    }
+
+    fn contains(&self, _: Vec3) -> bool {
+        false
+    }
+}
+
+// Ray transformed so that it points along the z axis, as in "Watertight Ray/Triangle
+// Intersection" by Woop, Benthin and Wald. Neighbouring triangles compute the same edge
+// functions for a shared edge, so rays cannot slip through the mesh between them.
+struct ShearedRay {
+    origin: Vec3,
+    kx: usize,
+    ky: usize,
+    kz: usize,
+    sx: f32,
+    sy: f32,
+    sz: f32,
+}
+
+impl ShearedRay {
+    fn new(ray: &Ray) -> ShearedRay {
+        let d = ray.direction;
+        let kz = if d.0.abs() > d.1.abs() && d.0.abs() > d.2.abs() { 0 } else if d.1.abs() > d.2.abs() { 1 } else { 2 };
+        let mut kx = (kz + 1) % 3;
+        let mut ky = (kx + 1) % 3;
+        if d.axis(kz) < 0.0 {
+            // Preserve the winding of the triangles
+            std::mem::swap(&mut kx, &mut ky);
+        }
+        ShearedRay {
+            origin: ray.origin,
+            kx,
+            ky,
+            kz,
+            sx: d.axis(kx) / d.axis(kz),
+            sy: d.axis(ky) / d.axis(kz),
+            sz: 1.0 / d.axis(kz),
+        }
+    }
+
+    // Returns the distance and barycentric coordinates of the hit
+    fn intersect(&self, v0: Vec3, v1: Vec3, v2: Vec3, tmin: f32, tmax: f32) -> Option<(f32, [f32; 3])> {
+        let a = v0 - self.origin;
+        let b = v1 - self.origin;
+        let c = v2 - self.origin;
+        let ax = a.axis(self.kx) - self.sx * a.axis(self.kz);
+        let ay = a.axis(self.ky) - self.sy * a.axis(self.kz);
+        let bx = b.axis(self.kx) - self.sx * b.axis(self.kz);
+        let by = b.axis(self.ky) - self.sy * b.axis(self.kz);
+        let cx = c.axis(self.kx) - self.sx * c.axis(self.kz);
+        let cy = c.axis(self.ky) - self.sy * c.axis(self.kz);
+
+        let mut u = cx * by - cy * bx;
+        let mut v = ax * cy - ay * cx;
+        let mut w = bx * ay - by * ax;
+        if u == 0.0 || v == 0.0 || w == 0.0 {
+            // Edge hit, recompute in double precision to decide consistently
+            u = (cx as f64 * by as f64 - cy as f64 * bx as f64) as f32;
+            v = (ax as f64 * cy as f64 - ay as f64 * cx as f64) as f32;
+            w = (bx as f64 * ay as f64 - by as f64 * ax as f64) as f32;
+        }
+        if (u < 0.0 || v < 0.0 || w < 0.0) && (u > 0.0 || v > 0.0 || w > 0.0) {
+            return None
+        }
+        let det = u + v + w;
+        if det == 0.0 {
+            return None
+        }
+
+        let az = self.sz * a.axis(self.kz);
+        let bz = self.sz * b.axis(self.kz);
+        let cz = self.sz * c.axis(self.kz);
+        let t = (u * az + v * bz + w * cz) / det;
+        if t <= tmin || tmax <= t {
+            return None
+        }
+        Some((t, [u / det, v / det, w / det]))
+    }
+}
+
+// Density per unit solid angle at the origin, of picking the point uniformly by area
+fn area_to_solid_angle_pdf(area: f32, origin: Vec3, point: Vec3, normal: Vec3) -> f32 {
+    let to_point = point - origin;
+    let distance2 = to_point.norm2();
+    let cosine = dot(normal, to_point).abs() / distance2.sqrt();
+    if cosine < 1e-6 {
+        return 0.0
+    }
+    distance2 / (area * cosine)
+}
+
+// Barycentric coordinates of a uniformly distributed point in a triangle
+fn sample_triangle((s, r): (f32, f32)) -> [f32; 3] {
+    let s = s.sqrt();
+    [1.0 - s, s * (1.0 - r), s * r]
+}
+
+fn barycentric_point([v0, v1, v2]: [Vec3; 3], barycentric: [f32; 3]) -> Vec3 {
+    barycentric[0] * v0 + barycentric[1] * v1 + barycentric[2] * v2
+}
+
+fn triangle_area(v0: Vec3, v1: Vec3, v2: Vec3) -> f32 {
+    0.5 * cross_product(v1 - v0, v2 - v0).norm()
+}
+
+// Normal of the side from which the vertices are seen in counter-clockwise order
+fn triangle_normal(v0: Vec3, v1: Vec3, v2: Vec3) -> Vec3 {
+    // cross_product is the left-handed cross product, matching the camera coordinates
+    cross_product(v1 - v0, v2 - v0).normalize()
+}
+
+// Texture coordinates of the vertices of triangles that have none, making the surface
+// coordinates barycentric
+const BARYCENTRIC_TEXCOORDS: [[f32; 2]; 3] = [[0.0, 0.0], [1.0, 0.0], [0.0, 1.0]];
+
+// Surface coordinates of the point with the given barycentric coordinates, interpolated
+// from the texture coordinates of the vertices, and the tangents along them
+fn triangle_surface(vertices: [Vec3; 3], texcoords: [[f32; 2]; 3], barycentric: [f32; 3]) -> (f32, f32, Vec3, Vec3) {
+    let [v0, v1, v2] = vertices;
+    let [t0, t1, t2] = texcoords;
+    let u = barycentric[0] * t0[0] + barycentric[1] * t1[0] + barycentric[2] * t2[0];
+    let v = barycentric[0] * t0[1] + barycentric[1] * t1[1] + barycentric[2] * t2[1];
+    let (du02, dv02) = (t0[0] - t2[0], t0[1] - t2[1]);
+    let (du12, dv12) = (t1[0] - t2[0], t1[1] - t2[1]);
+    let determinant = du02 * dv12 - dv02 * du12;
+    if determinant.abs() < 1e-12 {
+        // Degenerate texture coordinates, any tangents will do
+        let (dpdu, dpdv) = orthonormal_basis(triangle_normal(v0, v1, v2));
+        return (u, v, dpdu, dpdv)
+    }
+    let (dp02, dp12) = (v0 - v2, v1 - v2);
+    let dpdu = (dv12 * dp02 - dv02 * dp12) / determinant;
+    let dpdv = (du02 * dp12 - du12 * dp02) / determinant;
+    (u, v, dpdu, dpdv)
+}
+
+pub struct Triangle {
+    pub vertices: [Vec3; 3],
+}
+
+impl Hittable for Triangle {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        let [v0, v1, v2] = self.vertices;
+        let (t, barycentric) = ShearedRay::new(ray).intersect(v0, v1, v2, tmin, tmax)?;
+        let normal = triangle_normal(v0, v1, v2);
+        let (u, v, dpdu, dpdv) = triangle_surface(self.vertices, BARYCENTRIC_TEXCOORDS, barycentric);
+        Some(HitRecord {
+            t,
+            hit_point: ray.at(t),
+            normal,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            front_face: dot(ray.direction, normal) < 0.0,
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        let [v0, v1, v2] = self.vertices;
+        Aabb::empty().include(v0).include(v1).include(v2)
+    }
+
     fn contains(&self, _: Vec3) -> bool {
         false
     }
+
+    fn sample_towards(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let [v0, v1, v2] = self.vertices;
+        let barycentric = sample_triangle(sampler.next_2d());
+        let point = barycentric_point(self.vertices, barycentric);
+        let normal = triangle_normal(v0, v1, v2);
+        let pdf = area_to_solid_angle_pdf(triangle_area(v0, v1, v2), origin, point, normal);
+        if pdf == 0.0 {
+            return None
+        }
+        let (u, v, dpdu, dpdv) = triangle_surface(self.vertices, BARYCENTRIC_TEXCOORDS, barycentric);
+        let hit_record = HitRecord {
+            t: (point - origin).norm(),
+            hit_point: point,
+            normal,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            front_face: dot(point - origin, normal) < 0.0,
+        };
+        Some(LightSample { hit_record, pdf })
+    }
+
+    fn pdf_towards(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let [v0, v1, v2] = self.vertices;
+        let ray = Ray { origin, direction };
+        match ShearedRay::new(&ray).intersect(v0, v1, v2, 0.0, f32::INFINITY) {
+            Some((t, _)) => area_to_solid_angle_pdf(triangle_area(v0, v1, v2), origin, ray.at(t), triangle_normal(v0, v1, v2)),
+            None => 0.0,
+        }
+    }
+}
+
+// Triangles sharing vertex buffers, with an internal BVH over the triangles
+pub struct TriangleMesh {
+    vertices: Vec<Vec3>,
+    // Per-vertex normals for smooth shading, same indexing as the vertices
+    normals: Option<Vec<Vec3>>,
+    // Per-vertex texture coordinates, the surface coordinates are barycentric without them
+    texcoords: Option<Vec<[f32; 2]>>,
+    triangles: Vec<[u32; 3]>,
+    // Running sum of the triangle areas, for sampling points on the mesh
+    cumulative_areas: Vec<f32>,
+    bvh: Bvh,
+}
+
+impl TriangleMesh {
+    pub fn new(vertices: Vec<Vec3>, triangles: Vec<[u32; 3]>, normals: Option<Vec<Vec3>>, texcoords: Option<Vec<[f32; 2]>>) -> TriangleMesh {
+        if let Some(normals) = &normals {
+            assert_eq!(normals.len(), vertices.len());
+        }
+        if let Some(texcoords) = &texcoords {
+            assert_eq!(texcoords.len(), vertices.len());
+        }
+        let boxes: Vec<Aabb> = triangles.iter().map(|triangle| {
+            triangle.iter().fold(Aabb::empty(), |acc, &index| acc.include(vertices[index as usize]))
+        }).collect();
+        let bvh = Bvh::build(&boxes);
+        let mut total_area = 0.0;
+        let cumulative_areas = triangles.iter().map(|triangle| {
+            let [v0, v1, v2] = triangle.map(|vertex| vertices[vertex as usize]);
+            total_area += triangle_area(v0, v1, v2);
+            total_area
+        }).collect();
+        TriangleMesh { vertices, normals, texcoords, triangles, cumulative_areas, bvh }
+    }
+
+    fn triangle_vertices(&self, index: usize) -> [Vec3; 3] {
+        self.triangles[index].map(|vertex| self.vertices[vertex as usize])
+    }
+
+    fn triangle_texcoords(&self, index: usize) -> [[f32; 2]; 3] {
+        match &self.texcoords {
+            Some(texcoords) => self.triangles[index].map(|vertex| texcoords[vertex as usize]),
+            None => BARYCENTRIC_TEXCOORDS,
+        }
+    }
+
+    fn total_area(&self) -> f32 {
+        self.cumulative_areas.last().copied().unwrap_or(0.0)
+    }
+
+    // Index, distance and barycentric coordinates of the closest triangle hit by the ray
+    fn closest_triangle(&self, ray: &Ray, tmin: f32, tmax: f32) -> Option<(usize, f32, [f32; 3])> {
+        let sheared_ray = ShearedRay::new(ray);
+        let mut closest = None;
+        self.bvh.traverse(ray, tmin, tmax, |index, closest_distance| {
+            let [v0, v1, v2] = self.triangle_vertices(index);
+            let (t, barycentric) = sheared_ray.intersect(v0, v1, v2, tmin, closest_distance)?;
+            closest = Some((index, t, barycentric));
+            Some(t)
+        });
+        closest
+    }
+}
+
+impl Hittable for TriangleMesh {
+    fn hit(&self, ray: &Ray, tmin: f32, tmax: f32, _sampler: &mut dyn Sampler) -> Option<HitRecord> {
+        let (index, t, barycentric) = self.closest_triangle(ray, tmin, tmax)?;
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        let geometric_normal = triangle_normal(v0, v1, v2);
+        let normal = match &self.normals {
+            None => geometric_normal,
+            Some(normals) => {
+                let [n0, n1, n2] = self.triangles[index].map(|vertex| normals[vertex as usize]);
+                let normal = (barycentric[0] * n0 + barycentric[1] * n1 + barycentric[2] * n2).normalize();
+                // Keep the shading normal on the same side as the surface
+                if dot(normal, geometric_normal) < 0.0 { -normal } else { normal }
+            }
+        };
+        let (u, v, dpdu, dpdv) = triangle_surface([v0, v1, v2], self.triangle_texcoords(index), barycentric);
+        Some(HitRecord {
+            t,
+            hit_point: ray.at(t),
+            normal,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            front_face: dot(ray.direction, geometric_normal) < 0.0,
+        })
+    }
+
+    fn bounding_box(&self) -> Aabb {
+        self.bvh.bounds()
+    }
+
+    // Only meaningful for closed meshes, counts crossings of a ray leaving the point
+    fn contains(&self, point: Vec3) -> bool {
+        let ray = Ray { origin: point, direction: Vec3(1.0, 0.0, 0.0) };
+        let sheared_ray = ShearedRay::new(&ray);
+        let mut crossings = 0;
+        self.bvh.traverse(&ray, 0.0, f32::INFINITY, |index, _| {
+            let [v0, v1, v2] = self.triangle_vertices(index);
+            if sheared_ray.intersect(v0, v1, v2, 0.0, f32::INFINITY).is_some() {
+                crossings += 1;
+            }
+            None
+        });
+        crossings % 2 == 1
+    }
+
+    // Picks a triangle with probability proportional to its area, then a point in it
+    fn sample_towards(&self, origin: Vec3, sampler: &mut dyn Sampler) -> Option<LightSample> {
+        let total_area = self.total_area();
+        if total_area <= 0.0 {
+            return None
+        }
+        let target = sampler.next_1d() * total_area;
+        let index = self.cumulative_areas.partition_point(|&area| area <= target).min(self.triangles.len() - 1);
+        let [v0, v1, v2] = self.triangle_vertices(index);
+        let barycentric = sample_triangle(sampler.next_2d());
+        let point = barycentric_point([v0, v1, v2], barycentric);
+        let normal = triangle_normal(v0, v1, v2);
+        let pdf = area_to_solid_angle_pdf(total_area, origin, point, normal);
+        if pdf == 0.0 {
+            return None
+        }
+        let (u, v, dpdu, dpdv) = triangle_surface([v0, v1, v2], self.triangle_texcoords(index), barycentric);
+        let hit_record = HitRecord {
+            t: (point - origin).norm(),
+            hit_point: point,
+            normal,
+            u,
+            v,
+            dpdu,
+            dpdv,
+            front_face: dot(point - origin, normal) < 0.0,
+        };
+        Some(LightSample { hit_record, pdf })
+    }
+
+    fn pdf_towards(&self, origin: Vec3, direction: Vec3) -> f32 {
+        let ray = Ray { origin, direction };
+        match self.closest_triangle(&ray, 0.0, f32::INFINITY) {
+            Some((index, t, _)) => {
+                let [v0, v1, v2] = self.triangle_vertices(index);
+                area_to_solid_angle_pdf(self.total_area(), origin, ray.at(t), triangle_normal(v0, v1, v2))
+            }
+            None => 0.0,
+        }
+    }
 }
@@ -0,0 +1,210 @@
+use crate::geometry::Vec3;
+use crate::geometry::dot;
+use crate::graphics::Color;
+use crate::graphics::Image;
+use rand::Rng;
+use rand::SeedableRng;
+use rand::rngs::StdRng;
+use std::sync::Arc;
+
+// Color varying over a surface, looked up by the surface coordinates or the position
+pub trait Texture {
+    fn value(&self, u: f32, v: f32, point: Vec3) -> Color;
+}
+
+// Texture that can be given to several materials
+pub type SharedTexture = Arc<dyn Texture + Send + Sync>;
+
+// A color is a constant texture
+impl Texture for Color {
+    fn value(&self, _u: f32, _v: f32, _point: Vec3) -> Color {
+        *self
+    }
+}
+
+impl<T: Texture + ?Sized> Texture for Arc<T> {
+    fn value(&self, u: f32, v: f32, point: Vec3) -> Color {
+        (**self).value(u, v, point)
+    }
+}
+
+// Alternating cubes of two textures filling space
+pub struct Checker {
+    pub even: SharedTexture,
+    pub odd: SharedTexture,
+    // Side of the cubes
+    pub scale: f32,
+}
+
+impl Texture for Checker {
+    fn value(&self, u: f32, v: f32, point: Vec3) -> Color {
+        let cell = point / self.scale;
+        let cell = cell.0.floor() as i64 + cell.1.floor() as i64 + cell.2.floor() as i64;
+        if cell % 2 == 0 {
+            self.even.value(u, v, point)
+        } else {
+            self.odd.value(u, v, point)
+        }
+    }
+}
+
+const PERLIN_POINTS: usize = 256;
+
+// Perlin gradient noise, smooth pseudo-random values in [-1, 1]
+pub struct Perlin {
+    gradients: Vec<Vec3>,
+    permutations: [Vec<usize>; 3],
+}
+
+impl Perlin {
+    // The same seed always gives the same noise
+    pub fn new(seed: u64) -> Perlin {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let gradients = (0..PERLIN_POINTS).map(|_| {
+            loop {
+                let vec = Vec3(rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0), rng.gen_range(-1.0..1.0));
+                let norm2 = vec.norm2();
+                if norm2 > 1e-4 && norm2 < 1.0 {
+                    return vec / norm2.sqrt()
+                }
+            }
+        }).collect();
+        let mut permutation = || {
+            let mut values: Vec<usize> = (0..PERLIN_POINTS).collect();
+            for i in (1..PERLIN_POINTS).rev() {
+                values.swap(i, rng.gen_range(0..=i));
+            }
+            values
+        };
+        let permutations = [permutation(), permutation(), permutation()];
+        Perlin { gradients, permutations }
+    }
+
+    pub fn noise(&self, point: Vec3) -> f32 {
+        let floor = Vec3(point.0.floor(), point.1.floor(), point.2.floor());
+        let fraction = point - floor;
+        let (i, j, k) = (floor.0 as i64, floor.1 as i64, floor.2 as i64);
+        // Hermite smoothing of the interpolation weights
+        let smooth = |x: f32| x * x * (3.0 - 2.0 * x);
+        let (su, sv, sw) = (smooth(fraction.0), smooth(fraction.1), smooth(fraction.2));
+        let mask = PERLIN_POINTS as i64 - 1;
+        let mut sum = 0.0;
+        for di in 0..2 {
+            for dj in 0..2 {
+                for dk in 0..2 {
+                    let index = self.permutations[0][((i + di) & mask) as usize]
+                        ^ self.permutations[1][((j + dj) & mask) as usize]
+                        ^ self.permutations[2][((k + dk) & mask) as usize];
+                    let offset = fraction - Vec3(di as f32, dj as f32, dk as f32);
+                    let weight = (if di == 1 { su } else { 1.0 - su })
+                        * (if dj == 1 { sv } else { 1.0 - sv })
+                        * (if dk == 1 { sw } else { 1.0 - sw });
+                    sum += weight * dot(self.gradients[index], offset);
+                }
+            }
+        }
+        sum
+    }
+
+    // Sum of octaves of the absolute noise, each of double frequency and half amplitude
+    pub fn turbulence(&self, point: Vec3, octaves: usize) -> f32 {
+        let mut sum = 0.0;
+        let mut point = point;
+        let mut weight = 1.0;
+        for _ in 0..octaves {
+            sum += weight * self.noise(point).abs();
+            weight *= 0.5;
+            point = point * 2.0;
+        }
+        sum
+    }
+}
+
+const TURBULENCE_OCTAVES: usize = 7;
+
+// Color modulated by smooth noise
+pub struct Noise {
+    pub perlin: Perlin,
+    pub color: Color,
+    // Frequency of the noise
+    pub scale: f32,
+}
+
+impl Texture for Noise {
+    fn value(&self, _u: f32, _v: f32, point: Vec3) -> Color {
+        self.color.scale(0.5 * (1.0 + self.perlin.noise(self.scale * point)))
+    }
+}
+
+// Color modulated by turbulence, with a rougher look than plain noise
+pub struct Turbulence {
+    pub perlin: Perlin,
+    pub color: Color,
+    pub scale: f32,
+}
+
+impl Texture for Turbulence {
+    fn value(&self, _u: f32, _v: f32, point: Vec3) -> Color {
+        let turbulence = self.perlin.turbulence(self.scale * point, TURBULENCE_OCTAVES);
+        self.color.scale(turbulence.min(1.0))
+    }
+}
+
+// Veins along the z axis, distorted by turbulence
+pub struct Marble {
+    pub perlin: Perlin,
+    pub color: Color,
+    pub scale: f32,
+}
+
+impl Texture for Marble {
+    fn value(&self, _u: f32, _v: f32, point: Vec3) -> Color {
+        let phase = self.scale * point.2 + 10.0 * self.perlin.turbulence(point, TURBULENCE_OCTAVES);
+        self.color.scale(0.5 * (1.0 + phase.sin()))
+    }
+}
+
+// Rings around the y axis, distorted by noise
+pub struct Wood {
+    pub perlin: Perlin,
+    pub light: Color,
+    pub dark: Color,
+    // Number of rings per unit length
+    pub scale: f32,
+}
+
+impl Texture for Wood {
+    fn value(&self, _u: f32, _v: f32, point: Vec3) -> Color {
+        let radius = (point.0 * point.0 + point.2 * point.2).sqrt();
+        let rings = self.scale * radius + 0.5 * self.perlin.noise(4.0 * point);
+        let t = rings - rings.floor();
+        Color::mix(self.light, self.dark, t * t)
+    }
+}
+
+// Image mapped onto the surface coordinates, with (0, 0) at the bottom left corner,
+// the first line of the image
+pub struct ImageTexture {
+    pub image: Image,
+}
+
+impl Texture for ImageTexture {
+    fn value(&self, u: f32, v: f32, _point: Vec3) -> Color {
+        if self.image.width == 0 || self.image.height == 0 {
+            return Color { red: 0.0, green: 1.0, blue: 1.0 }
+        }
+        // Bilinear interpolation between pixel centers, repeating the image
+        let x = u.rem_euclid(1.0) * self.image.width as f32 - 0.5;
+        let y = v.rem_euclid(1.0) * self.image.height as f32 - 0.5;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (fx, fy) = (x - x0, y - y0);
+        let pixel = |x: f32, y: f32| {
+            let x = (x as i64).rem_euclid(self.image.width as i64) as usize;
+            let y = (y as i64).rem_euclid(self.image.height as i64) as usize;
+            self.image.pixel(x, y)
+        };
+        let bottom = Color::mix(pixel(x0, y0), pixel(x0 + 1.0, y0), fx);
+        let top = Color::mix(pixel(x0, y0 + 1.0), pixel(x0 + 1.0, y0 + 1.0), fx);
+        Color::mix(bottom, top, fy)
+    }
+}
@@ -0,0 +1,90 @@
+// Square pieces of the image that the threads render one at a time. Tiles even out the
+// work better than lines, and their pixels see nearby parts of the scene.
+#[derive(Copy, Clone)]
+pub struct Tile {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Tile {
+    // Coordinates of the pixels in the image, line by line
+    pub fn pixels(&self) -> impl Iterator<Item=(usize, usize)> {
+        let tile = *self;
+        (tile.y..tile.y + tile.height).flat_map(move |y| (tile.x..tile.x + tile.width).map(move |x| (x, y)))
+    }
+}
+
+// Order in which the tiles are rendered
+#[derive(Copy, Clone, PartialEq)]
+pub enum TileOrder {
+    // Rows of tiles from the bottom of the image
+    Scanline,
+    // Rings of tiles around the center, which usually has the subject
+    Spiral,
+    // Along a Hilbert curve, each tile next to the previous one
+    Hilbert,
+}
+
+impl TileOrder {
+    pub fn from_name(name: &str) -> Option<TileOrder> {
+        match name {
+            "scanline" => Some(TileOrder::Scanline),
+            "spiral" => Some(TileOrder::Spiral),
+            "hilbert" => Some(TileOrder::Hilbert),
+            _ => None,
+        }
+    }
+}
+
+// Distance of the point along the Hilbert curve filling a square of the given size, a
+// power of two
+fn hilbert_index(size: usize, mut x: usize, mut y: usize) -> usize {
+    let mut index = 0;
+    let mut half = size / 2;
+    while half > 0 {
+        let right = (x & half != 0) as usize;
+        let top = (y & half != 0) as usize;
+        index += half * half * ((3 * right) ^ top);
+        // Turns the quadrant so that the curve enters and leaves it like the whole square
+        if top == 0 {
+            if right == 1 {
+                x = size - 1 - x;
+                y = size - 1 - y;
+            }
+            std::mem::swap(&mut x, &mut y);
+        }
+        half /= 2;
+    }
+    index
+}
+
+// Tiles covering the image, the ones at the right and top edges smaller if the image
+// isn't a whole number of tiles
+pub fn split_into_tiles(width: usize, height: usize, tile_size: usize, order: TileOrder) -> Vec<Tile> {
+    let (columns, rows) = (width.div_ceil(tile_size), height.div_ceil(tile_size));
+    let mut cells: Vec<(usize, usize)> = (0..rows).flat_map(|row| (0..columns).map(move |column| (column, row))).collect();
+    match order {
+        TileOrder::Scanline => {}
+        TileOrder::Spiral => {
+            let (center_x, center_y) = (0.5 * (columns - 1) as f32, 0.5 * (rows - 1) as f32);
+            let ring = |column: usize, row: usize| {
+                let (dx, dy) = (column as f32 - center_x, row as f32 - center_y);
+                (dx.abs().max(dy.abs()) + 0.5) as usize
+            };
+            let angle = |column: usize, row: usize| (row as f32 - center_y).atan2(column as f32 - center_x);
+            cells.sort_by(|&(c1, r1), &(c2, r2)| {
+                ring(c1, r1).cmp(&ring(c2, r2)).then(angle(c1, r1).total_cmp(&angle(c2, r2)))
+            });
+        }
+        TileOrder::Hilbert => {
+            let size = columns.max(rows).next_power_of_two();
+            cells.sort_by_key(|&(column, row)| hilbert_index(size, column, row));
+        }
+    }
+    cells.into_iter().map(|(column, row)| {
+        let (x, y) = (column * tile_size, row * tile_size);
+        Tile { x, y, width: tile_size.min(width - x), height: tile_size.min(height - y) }
+    }).collect()
+}
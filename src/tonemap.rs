@@ -0,0 +1,122 @@
+use crate::graphics::Color;
+use crate::graphics::Image;
+
+// Maps the unbounded radiance of each channel into [0, 1]
+#[derive(Copy, Clone, PartialEq)]
+pub enum ToneMap {
+    // Values above 1 are cut off
+    Clip,
+    Reinhard,
+    // Reinhard with a white point that maps to 1, by default the brightest value in the
+    // image
+    ExtendedReinhard { white: Option<f32> },
+    // Fit of the ACES filmic curve by Krzysztof Narkowicz
+    Aces,
+    // Filmic curve of John Hable, from Uncharted 2
+    Hable,
+}
+
+// Encoding of the tone mapped values for display
+#[derive(Copy, Clone, PartialEq)]
+pub enum Transfer {
+    // Power of the value
+    Gamma(f32),
+    // Exact sRGB curve, linear near black
+    Srgb,
+}
+
+#[derive(Copy, Clone)]
+pub struct DisplayTransform {
+    // Brightness scale as a power of two, on top of the automatic exposure
+    pub exposure_stops: f32,
+    // Scale the image so that its log-average luminance is middle gray
+    pub auto_exposure: bool,
+    pub tone_map: ToneMap,
+    pub transfer: Transfer,
+}
+
+// Luminance of linear Rec. 709 colors
+pub fn luminance(color: Color) -> f32 {
+    0.2126 * color.red + 0.7152 * color.green + 0.0722 * color.blue
+}
+
+fn hable_curve(x: f32) -> f32 {
+    const A: f32 = 0.15;
+    const B: f32 = 0.50;
+    const C: f32 = 0.10;
+    const D: f32 = 0.20;
+    const E: f32 = 0.02;
+    const F: f32 = 0.30;
+    (x * (A * x + C * B) + D * E) / (x * (A * x + B) + D * F) - E / F
+}
+
+fn srgb_encode(x: f32) -> f32 {
+    if x <= 0.0031308 {
+        12.92 * x
+    } else {
+        1.055 * x.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+impl DisplayTransform {
+    // Function from the linear colors of the image to display values in [0, 1]
+    pub fn encoder<'a>(&'a self, image: &Image) -> impl Fn(Color) -> Color + 'a {
+        let scale = self.exposure_scale(image);
+        let white = match self.tone_map {
+            ToneMap::ExtendedReinhard { white: Some(white) } => white,
+            ToneMap::ExtendedReinhard { white: None } => {
+                let brightest = (0..image.height)
+                    .flat_map(|y| (0..image.width).map(move |x| image.pixel(x, y)))
+                    .map(|color| color.red.max(color.green).max(color.blue))
+                    .fold(0.0f32, f32::max);
+                (scale * brightest).max(1.0)
+            }
+            _ => 1.0,
+        };
+        move |color| self.encode(color, scale, white)
+    }
+
+    // Factor applied to the radiance before tone mapping
+    fn exposure_scale(&self, image: &Image) -> f32 {
+        let mut scale = self.exposure_stops.exp2();
+        if self.auto_exposure {
+            const MIDDLE_GRAY: f32 = 0.18;
+            // Keeps black pixels from taking the log to minus infinity
+            const DELTA: f32 = 1e-4;
+            let count = (image.width * image.height).max(1);
+            let log_sum: f64 = (0..image.height)
+                .flat_map(|y| (0..image.width).map(move |x| (x, y)))
+                .map(|(x, y)| ((DELTA + luminance(image.pixel(x, y)).max(0.0)) as f64).ln())
+                .sum();
+            let log_average = (log_sum / count as f64).exp() as f32;
+            scale *= MIDDLE_GRAY / log_average;
+        }
+        scale
+    }
+
+    fn encode(&self, color: Color, scale: f32, white: f32) -> Color {
+        let map = |x: f32| {
+            let x = (scale * x).max(0.0);
+            let mapped = match self.tone_map {
+                ToneMap::Clip => x,
+                ToneMap::Reinhard => x / (1.0 + x),
+                ToneMap::ExtendedReinhard { .. } => x * (1.0 + x / (white * white)) / (1.0 + x),
+                ToneMap::Aces => {
+                    let x = 0.6 * x;
+                    x * (2.51 * x + 0.03) / (x * (2.43 * x + 0.59) + 0.14)
+                }
+                ToneMap::Hable => {
+                    const EXPOSURE_BIAS: f32 = 2.0;
+                    const LINEAR_WHITE: f32 = 11.2;
+                    hable_curve(EXPOSURE_BIAS * x) / hable_curve(LINEAR_WHITE)
+                }
+            };
+            let mapped = mapped.clamp(0.0, 1.0);
+            match self.transfer {
+                Transfer::Gamma(inverse_gamma) => mapped.powf(inverse_gamma),
+                Transfer::Srgb => srgb_encode(mapped),
+            }
+        };
+        Color { red: map(color.red), green: map(color.green), blue: map(color.blue) }
+    }
+}
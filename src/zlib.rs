@@ -0,0 +1,501 @@
+// Compression into the zlib format (RFC 1950) wrapping deflate (RFC 1951), as used by
+// PNG. Finds repeated strings with hash chains and writes each block with Huffman codes
+// fitted to it.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+const WINDOW_SIZE: usize = 32768;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 258;
+// Longest hash chain followed when looking for a match, trading speed for size
+const MAX_CHAIN: usize = 128;
+const HASH_BITS: usize = 15;
+// Symbols per block, so that the codes follow changes in the data
+const BLOCK_SYMBOLS: usize = 1 << 16;
+
+const END_OF_BLOCK: usize = 256;
+const LITERAL_LENGTH_CODES: usize = 286;
+const DISTANCE_CODES: usize = 30;
+const CODE_LENGTH_CODES: usize = 19;
+const MAX_CODE_BITS: usize = 15;
+const MAX_CODE_LENGTH_BITS: usize = 7;
+
+const LENGTH_BASE: [u16; 29] = [
+    3, 4, 5, 6, 7, 8, 9, 10, 11, 13, 15, 17, 19, 23, 27, 31,
+    35, 43, 51, 59, 67, 83, 99, 115, 131, 163, 195, 227, 258,
+];
+const LENGTH_EXTRA_BITS: [u8; 29] = [
+    0, 0, 0, 0, 0, 0, 0, 0, 1, 1, 1, 1, 2, 2, 2, 2,
+    3, 3, 3, 3, 4, 4, 4, 4, 5, 5, 5, 5, 0,
+];
+const DISTANCE_BASE: [u16; 30] = [
+    1, 2, 3, 4, 5, 7, 9, 13, 17, 25, 33, 49, 65, 97, 129, 193,
+    257, 385, 513, 769, 1025, 1537, 2049, 3073, 4097, 6145, 8193, 12289, 16385, 24577,
+];
+const DISTANCE_EXTRA_BITS: [u8; 30] = [
+    0, 0, 0, 0, 1, 1, 2, 2, 3, 3, 4, 4, 5, 5, 6, 6,
+    7, 7, 8, 8, 9, 9, 10, 10, 11, 11, 12, 12, 13, 13,
+];
+// Order in which the lengths of the code length codes are stored
+const CODE_LENGTH_ORDER: [usize; 19] = [16, 17, 18, 0, 8, 7, 9, 6, 10, 5, 11, 4, 12, 3, 13, 2, 14, 1, 15];
+
+enum Symbol {
+    Literal(u8),
+    Match { length: usize, distance: usize },
+}
+
+struct BitWriter {
+    bytes: Vec<u8>,
+    buffer: u64,
+    count: usize,
+}
+
+impl BitWriter {
+    // Bits go into the bytes starting from the least significant one
+    fn write(&mut self, value: u32, bits: usize) {
+        self.buffer |= (value as u64) << self.count;
+        self.count += bits;
+        while self.count >= 8 {
+            self.bytes.push(self.buffer as u8);
+            self.buffer >>= 8;
+            self.count -= 8;
+        }
+    }
+
+    // Huffman codes are stored starting from their most significant bit
+    fn write_code(&mut self, code: u16, bits: u8) {
+        let reversed = code.reverse_bits() >> (16 - bits as u32);
+        self.write(reversed as u32, bits as usize);
+    }
+
+    fn finish(mut self) -> Vec<u8> {
+        if self.count > 0 {
+            self.bytes.push(self.buffer as u8);
+        }
+        self.bytes
+    }
+}
+
+fn length_code(length: usize) -> usize {
+    LENGTH_BASE.partition_point(|&base| base as usize <= length) - 1
+}
+
+fn distance_code(distance: usize) -> usize {
+    DISTANCE_BASE.partition_point(|&base| base as usize <= distance) - 1
+}
+
+fn hash(data: &[u8], position: usize) -> usize {
+    let value = (data[position] as usize) << 16 | (data[position + 1] as usize) << 8 | data[position + 2] as usize;
+    (value.wrapping_mul(2654435761) >> 8) & ((1 << HASH_BITS) - 1)
+}
+
+// Replaces strings seen in the last window by references back to them
+fn find_matches(data: &[u8]) -> Vec<Symbol> {
+    let mut symbols = vec![];
+    let mut head = vec![usize::MAX; 1 << HASH_BITS];
+    let mut previous = vec![usize::MAX; WINDOW_SIZE];
+    let insert = |position: usize, head: &mut Vec<usize>, previous: &mut Vec<usize>| {
+        if position + MIN_MATCH <= data.len() {
+            let h = hash(data, position);
+            previous[position % WINDOW_SIZE] = head[h];
+            head[h] = position;
+        }
+    };
+    let mut position = 0;
+    while position < data.len() {
+        let mut best_length = 0;
+        let mut best_distance = 0;
+        if position + MIN_MATCH <= data.len() {
+            let max_length = MAX_MATCH.min(data.len() - position);
+            let mut candidate = head[hash(data, position)];
+            let mut chain = 0;
+            while candidate != usize::MAX && position - candidate <= WINDOW_SIZE && chain < MAX_CHAIN {
+                let length = data[candidate..candidate + max_length].iter()
+                    .zip(&data[position..position + max_length])
+                    .take_while(|(a, b)| a == b)
+                    .count();
+                if length > best_length {
+                    best_length = length;
+                    best_distance = position - candidate;
+                    if length == max_length {
+                        break
+                    }
+                }
+                let next = previous[candidate % WINDOW_SIZE];
+                // Entries overwritten in the ring buffer point forward, ending the chain
+                if next >= candidate {
+                    break
+                }
+                candidate = next;
+                chain += 1;
+            }
+        }
+        if best_length >= MIN_MATCH {
+            symbols.push(Symbol::Match { length: best_length, distance: best_distance });
+            for p in position..position + best_length {
+                insert(p, &mut head, &mut previous);
+            }
+            position += best_length;
+        } else {
+            symbols.push(Symbol::Literal(data[position]));
+            insert(position, &mut head, &mut previous);
+            position += 1;
+        }
+    }
+    symbols
+}
+
+// Lengths of a Huffman code for the symbol frequencies, no longer than max_bits. Unused
+// symbols get length zero.
+fn code_lengths(frequencies: &[u32], max_bits: usize) -> Vec<u8> {
+    let mut frequencies = frequencies.to_vec();
+    loop {
+        let lengths = unlimited_code_lengths(&frequencies);
+        if lengths.iter().all(|&length| length as usize <= max_bits) {
+            return lengths
+        }
+        // Flatten the distribution until the tree is shallow enough
+        for frequency in frequencies.iter_mut().filter(|frequency| **frequency > 0) {
+            *frequency = (*frequency / 2).max(1);
+        }
+    }
+}
+
+fn unlimited_code_lengths(frequencies: &[u32]) -> Vec<u8> {
+    let mut lengths = vec![0; frequencies.len()];
+    let used: Vec<usize> = (0..frequencies.len()).filter(|&symbol| frequencies[symbol] > 0).collect();
+    if used.len() == 1 {
+        lengths[used[0]] = 1;
+        return lengths
+    }
+    // Nodes are leaves followed by internal nodes, merging the two lightest each time
+    let mut weights: Vec<u64> = used.iter().map(|&symbol| frequencies[symbol] as u64).collect();
+    let mut parents = vec![usize::MAX; used.len()];
+    let mut queue: BinaryHeap<Reverse<(u64, usize)>> =
+        (0..used.len()).map(|node| Reverse((weights[node], node))).collect();
+    while queue.len() > 1 {
+        let Reverse((weight1, node1)) = queue.pop().unwrap();
+        let Reverse((weight2, node2)) = queue.pop().unwrap();
+        let parent = weights.len();
+        weights.push(weight1 + weight2);
+        parents.push(usize::MAX);
+        parents[node1] = parent;
+        parents[node2] = parent;
+        queue.push(Reverse((weight1 + weight2, parent)));
+    }
+    for (leaf, &symbol) in used.iter().enumerate() {
+        let mut depth = 0;
+        let mut node = leaf;
+        while parents[node] != usize::MAX {
+            node = parents[node];
+            depth += 1;
+        }
+        lengths[symbol] = depth.min(255) as u8;
+    }
+    lengths
+}
+
+// Canonical codes for the lengths, as defined by deflate
+fn canonical_codes(lengths: &[u8]) -> Vec<u16> {
+    let mut length_counts = [0u16; MAX_CODE_BITS + 1];
+    for &length in lengths {
+        length_counts[length as usize] += 1;
+    }
+    length_counts[0] = 0;
+    let mut next_code = [0u16; MAX_CODE_BITS + 2];
+    for bits in 1..=MAX_CODE_BITS {
+        next_code[bits + 1] = (next_code[bits] + length_counts[bits]) << 1;
+    }
+    lengths.iter().map(|&length| {
+        if length == 0 {
+            return 0
+        }
+        let code = next_code[length as usize];
+        next_code[length as usize] += 1;
+        code
+    }).collect()
+}
+
+// Run-length encoding of the concatenated code lengths, as pairs of a code length symbol
+// and the value of its extra bits
+fn encode_lengths(lengths: &[u8]) -> Vec<(usize, u32)> {
+    let mut encoded = vec![];
+    let mut i = 0;
+    while i < lengths.len() {
+        let length = lengths[i];
+        let run = lengths[i..].iter().take_while(|&&other| other == length).count();
+        if length == 0 && run >= 3 {
+            let run = run.min(138);
+            if run <= 10 {
+                encoded.push((17, run as u32 - 3));
+            } else {
+                encoded.push((18, run as u32 - 11));
+            }
+            i += run;
+        } else if length != 0 && run >= 4 {
+            // The first one is written as is, and the repeats after it
+            let run = (run - 1).min(6);
+            encoded.push((length as usize, 0));
+            encoded.push((16, run as u32 - 3));
+            i += run + 1;
+        } else {
+            encoded.push((length as usize, 0));
+            i += 1;
+        }
+    }
+    encoded
+}
+
+fn write_block(writer: &mut BitWriter, symbols: &[Symbol], last: bool) {
+    let mut literal_frequencies = [0u32; LITERAL_LENGTH_CODES];
+    let mut distance_frequencies = [0u32; DISTANCE_CODES];
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Literal(byte) => literal_frequencies[byte as usize] += 1,
+            Symbol::Match { length, distance } => {
+                literal_frequencies[257 + length_code(length)] += 1;
+                distance_frequencies[distance_code(distance)] += 1;
+            }
+        }
+    }
+    literal_frequencies[END_OF_BLOCK] = 1;
+    if distance_frequencies.iter().all(|&frequency| frequency == 0) {
+        // At least one distance code is needed even when no strings repeat
+        distance_frequencies[0] = 1;
+    }
+    let literal_lengths = code_lengths(&literal_frequencies, MAX_CODE_BITS);
+    let distance_lengths = code_lengths(&distance_frequencies, MAX_CODE_BITS);
+    let literal_codes = canonical_codes(&literal_lengths);
+    let distance_codes = canonical_codes(&distance_lengths);
+
+    // Trailing unused codes don't need to be stored
+    let literal_count = 257.max(literal_lengths.iter().rposition(|&length| length > 0).unwrap() + 1);
+    let distance_count = distance_lengths.iter().rposition(|&length| length > 0).unwrap() + 1;
+    let all_lengths = [&literal_lengths[..literal_count], &distance_lengths[..distance_count]].concat();
+    let encoded_lengths = encode_lengths(&all_lengths);
+    let mut length_frequencies = [0u32; CODE_LENGTH_CODES];
+    for &(symbol, _) in &encoded_lengths {
+        length_frequencies[symbol] += 1;
+    }
+    let length_lengths = code_lengths(&length_frequencies, MAX_CODE_LENGTH_BITS);
+    let length_codes = canonical_codes(&length_lengths);
+    let length_count = 4.max(CODE_LENGTH_ORDER.iter().rposition(|&symbol| length_lengths[symbol] > 0).unwrap() + 1);
+
+    writer.write(last as u32, 1);
+    writer.write(2, 2); // Dynamic Huffman codes
+    writer.write((literal_count - 257) as u32, 5);
+    writer.write((distance_count - 1) as u32, 5);
+    writer.write((length_count - 4) as u32, 4);
+    for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+        writer.write(length_lengths[symbol] as u32, 3);
+    }
+    for &(symbol, extra) in &encoded_lengths {
+        writer.write_code(length_codes[symbol], length_lengths[symbol]);
+        match symbol {
+            16 => writer.write(extra, 2),
+            17 => writer.write(extra, 3),
+            18 => writer.write(extra, 7),
+            _ => {}
+        }
+    }
+
+    for symbol in symbols {
+        match *symbol {
+            Symbol::Literal(byte) => writer.write_code(literal_codes[byte as usize], literal_lengths[byte as usize]),
+            Symbol::Match { length, distance } => {
+                let code = length_code(length);
+                writer.write_code(literal_codes[257 + code], literal_lengths[257 + code]);
+                writer.write((length - LENGTH_BASE[code] as usize) as u32, LENGTH_EXTRA_BITS[code] as usize);
+                let code = distance_code(distance);
+                writer.write_code(distance_codes[code], distance_lengths[code]);
+                writer.write((distance - DISTANCE_BASE[code] as usize) as u32, DISTANCE_EXTRA_BITS[code] as usize);
+            }
+        }
+    }
+    writer.write_code(literal_codes[END_OF_BLOCK], literal_lengths[END_OF_BLOCK]);
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    const MODULUS: u32 = 65521;
+    let (mut a, mut b) = (1u32, 0u32);
+    // Sums of this many bytes can't overflow before taking the modulus
+    for chunk in data.chunks(5552) {
+        for &byte in chunk {
+            a += byte as u32;
+            b += a;
+        }
+        a %= MODULUS;
+        b %= MODULUS;
+    }
+    b << 16 | a
+}
+
+pub fn compress(data: &[u8]) -> Vec<u8> {
+    let mut writer = BitWriter { bytes: vec![0x78, 0x9c], buffer: 0, count: 0 };
+    let symbols = find_matches(data);
+    if symbols.is_empty() {
+        write_block(&mut writer, &symbols, true);
+    }
+    let block_count = symbols.len().div_ceil(BLOCK_SYMBOLS);
+    for (i, block) in symbols.chunks(BLOCK_SYMBOLS).enumerate() {
+        write_block(&mut writer, block, i + 1 == block_count);
+    }
+    let mut bytes = writer.finish();
+    bytes.extend_from_slice(&adler32(data).to_be_bytes());
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        position: usize,
+    }
+
+    impl BitReader<'_> {
+        fn read(&mut self, bits: usize) -> u32 {
+            let mut value = 0;
+            for i in 0..bits {
+                let bit = self.bytes[self.position / 8] >> (self.position % 8) & 1;
+                value |= (bit as u32) << i;
+                self.position += 1;
+            }
+            value
+        }
+    }
+
+    // Decodes a canonical Huffman code a bit at a time, as RFC 1951 describes it
+    struct Decoder {
+        counts: [i32; MAX_CODE_BITS + 1],
+        symbols: Vec<usize>,
+    }
+
+    impl Decoder {
+        fn new(lengths: &[u8]) -> Decoder {
+            let mut counts = [0; MAX_CODE_BITS + 1];
+            for &length in lengths.iter().filter(|&&length| length > 0) {
+                counts[length as usize] += 1;
+            }
+            let mut symbols: Vec<usize> = (0..lengths.len()).filter(|&symbol| lengths[symbol] > 0).collect();
+            symbols.sort_by_key(|&symbol| lengths[symbol]);
+            Decoder { counts, symbols }
+        }
+
+        fn decode(&self, reader: &mut BitReader) -> usize {
+            let (mut code, mut first, mut index) = (0, 0, 0);
+            for bits in 1..=MAX_CODE_BITS {
+                code |= reader.read(1) as i32;
+                if code - first < self.counts[bits] {
+                    return self.symbols[(index + code - first) as usize]
+                }
+                index += self.counts[bits];
+                first = (first + self.counts[bits]) << 1;
+                code <<= 1;
+            }
+            panic!("invalid Huffman code")
+        }
+    }
+
+    // Independent decoder for the dynamic Huffman blocks that compress writes
+    fn decompress(bytes: &[u8]) -> Vec<u8> {
+        assert_eq!(bytes[0] & 0x0f, 8, "deflate compression");
+        assert_eq!((bytes[0] as u32 * 256 + bytes[1] as u32) % 31, 0, "header check bits");
+        let mut reader = BitReader { bytes: &bytes[2..bytes.len() - 4], position: 0 };
+        let mut output: Vec<u8> = vec![];
+        loop {
+            let last = reader.read(1) == 1;
+            assert_eq!(reader.read(2), 2, "dynamic Huffman block");
+            let literal_count = reader.read(5) as usize + 257;
+            let distance_count = reader.read(5) as usize + 1;
+            let length_count = reader.read(4) as usize + 4;
+            let mut length_lengths = [0; CODE_LENGTH_CODES];
+            for &symbol in &CODE_LENGTH_ORDER[..length_count] {
+                length_lengths[symbol] = reader.read(3) as u8;
+            }
+            let length_decoder = Decoder::new(&length_lengths);
+            let mut lengths = vec![];
+            while lengths.len() < literal_count + distance_count {
+                match length_decoder.decode(&mut reader) {
+                    16 => {
+                        let previous = *lengths.last().unwrap();
+                        let run = 3 + reader.read(2) as usize;
+                        lengths.extend(std::iter::repeat_n(previous, run));
+                    }
+                    17 => lengths.extend(std::iter::repeat_n(0, 3 + reader.read(3) as usize)),
+                    18 => lengths.extend(std::iter::repeat_n(0, 11 + reader.read(7) as usize)),
+                    length => lengths.push(length as u8),
+                }
+            }
+            assert_eq!(lengths.len(), literal_count + distance_count);
+            let literals = Decoder::new(&lengths[..literal_count]);
+            let distances = Decoder::new(&lengths[literal_count..]);
+            loop {
+                let symbol = literals.decode(&mut reader);
+                if symbol < 256 {
+                    output.push(symbol as u8);
+                    continue
+                }
+                if symbol == END_OF_BLOCK {
+                    break
+                }
+                let code = symbol - 257;
+                let length = LENGTH_BASE[code] as usize + reader.read(LENGTH_EXTRA_BITS[code] as usize) as usize;
+                let code = distances.decode(&mut reader);
+                let distance = DISTANCE_BASE[code] as usize + reader.read(DISTANCE_EXTRA_BITS[code] as usize) as usize;
+                for _ in 0..length {
+                    output.push(output[output.len() - distance]);
+                }
+            }
+            if last {
+                break
+            }
+        }
+        let checksum = u32::from_be_bytes(bytes[bytes.len() - 4..].try_into().unwrap());
+        assert_eq!(checksum, adler32(&output), "checksum");
+        output
+    }
+
+    // Bytes without any repetition for the matcher to find
+    fn noise(count: usize) -> Vec<u8> {
+        let mut state = 12345u32;
+        (0..count).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 24) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn adler32_vectors() {
+        assert_eq!(adler32(b""), 1);
+        assert_eq!(adler32(b"abc"), 0x024d0127);
+        assert_eq!(adler32(b"Wikipedia"), 0x11e60398);
+        // Long enough for the sums to wrap around the modulus
+        assert_eq!(adler32(&[255; 100000]), 0x149a302c);
+    }
+
+    #[test]
+    fn round_trip() {
+        let text: String = (0..20000).map(|i| format!("{} ", i % 997)).collect();
+        let inputs = [
+            vec![],
+            b"a".to_vec(),
+            b"abcabcabcabcabcabc".to_vec(),
+            vec![0; 100000],
+            text.into_bytes(),
+            // More symbols than fit in one block
+            noise(200000),
+        ];
+        for input in inputs {
+            assert_eq!(decompress(&compress(&input)), input, "{} bytes", input.len());
+        }
+    }
+
+    #[test]
+    fn repetition_compresses() {
+        assert!(compress(&[7; 100000]).len() < 1000);
+        assert!(compress(&noise(10000)).len() < 10100);
+    }
+}
@@ -0,0 +1,35 @@
+use std::path::PathBuf;
+use std::process::Command;
+use std::process::Output;
+
+// The raytracer built along with the tests
+pub fn raytracer() -> Command {
+    let mut command = Command::new(env!("CARGO_BIN_EXE_simple-raytracer"));
+    command.current_dir(env!("CARGO_MANIFEST_DIR"));
+    command
+}
+
+// Path of a file of the test in the temporary directory, unique to this process
+pub fn temporary_file(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("simple-raytracer-{}-{}", std::process::id(), name))
+}
+
+// Renders a small image of the cornell box with the arguments and returns the bytes of
+// the bmp file
+pub fn render(name: &str, arguments: &[&str]) -> Vec<u8> {
+    let output = temporary_file(&format!("{}.bmp", name));
+    let result = raytracer()
+        .args(["scenes/cornell.scene", "--quiet", "--width", "48", "--samples", "4", "--seed", "7", "--output"])
+        .arg(&output)
+        .args(arguments)
+        .output()
+        .unwrap();
+    assert_succeeded(&result);
+    let bytes = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    bytes
+}
+
+pub fn assert_succeeded(output: &Output) {
+    assert!(output.status.success(), "the raytracer failed: {}", String::from_utf8_lossy(&output.stderr));
+}
@@ -0,0 +1,23 @@
+mod common;
+
+use common::render;
+
+// The random numbers of a sample only depend on the seed, the pixel and the sample, so
+// how the tiles are shared between threads must not change the image
+#[test]
+fn threads_and_tile_orders_give_the_same_image() {
+    for sampler in ["independent", "sobol"] {
+        let reference = render(&format!("{}-reference", sampler), &["--sampler", sampler, "--threads", "1", "--tile-order", "scanline"]);
+        for (threads, order, size) in [("2", "hilbert", "16"), ("3", "spiral", "5"), ("4", "scanline", "7")] {
+            let name = format!("{}-{}-{}-{}", sampler, threads, order, size);
+            let image = render(&name, &["--sampler", sampler, "--threads", threads, "--tile-order", order, "--tile-size", size]);
+            assert!(image == reference, "{} sampler: {} threads and {} order of {} pixel tiles give another image",
+                sampler, threads, order, size);
+        }
+    }
+}
+
+#[test]
+fn seeds_give_different_images() {
+    assert!(render("seed-7", &[]) != render("seed-8", &["--seed", "8"]));
+}
@@ -0,0 +1,70 @@
+mod common;
+
+use common::assert_succeeded;
+use common::raytracer;
+use common::render;
+use common::temporary_file;
+use std::io::Read;
+use std::process::Stdio;
+
+#[test]
+fn workers_give_the_same_image() {
+    assert!(render("workers", &["--workers", "2"]) == render("single", &[]));
+}
+
+// Processes whose parent is the process
+#[cfg(target_os = "linux")]
+fn children(parent: u32) -> Vec<u32> {
+    let mut children = Vec::new();
+    for entry in std::fs::read_dir("/proc").unwrap().flatten() {
+        let Ok(pid) = entry.file_name().to_string_lossy().parse::<u32>() else { continue };
+        let Ok(stat) = std::fs::read_to_string(entry.path().join("stat")) else { continue };
+        // The name in parentheses may hold spaces, the state and the parent follow it
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 1..].split_whitespace().collect();
+        if fields[1] == parent.to_string() {
+            children.push(pid);
+        }
+    }
+    children
+}
+
+// A worker dying halfway through leaves its tiles to the coordinator, which still
+// renders the whole image
+#[cfg(target_os = "linux")]
+#[test]
+fn killed_worker_leaves_its_tiles_here() {
+    let arguments = ["--width", "96", "--samples", "8", "--tile-size", "4"];
+    let output = temporary_file("killed-worker.bmp");
+    let mut coordinator = raytracer()
+        .args(["scenes/cornell.scene", "--seed", "7", "--workers", "2", "--output"])
+        .arg(&output)
+        .args(arguments)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+
+    // Wait for the first tiles to come back, the progress being on a single line
+    let mut stdout = coordinator.stdout.take().unwrap();
+    let mut printed = Vec::new();
+    let mut byte = [0];
+    while !String::from_utf8_lossy(&printed).contains("Completed") {
+        assert!(stdout.read(&mut byte).unwrap() == 1, "the render ended before any progress: {}", String::from_utf8_lossy(&printed));
+        printed.push(byte[0]);
+    }
+    let workers = children(coordinator.id());
+    assert!(workers.len() == 2, "expected 2 workers, found {:?}", workers);
+    let killed = std::process::Command::new("kill").args(["-KILL", &workers[0].to_string()]).status().unwrap();
+    assert!(killed.success());
+
+    stdout.read_to_end(&mut printed).unwrap();
+    let result = coordinator.wait_with_output().unwrap();
+    assert_succeeded(&result);
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("failed, rendering its tiles here instead"), "no warning about the worker: {}", stderr);
+    assert!(String::from_utf8_lossy(&printed).contains("Finished"));
+
+    let image = std::fs::read(&output).unwrap();
+    std::fs::remove_file(&output).unwrap();
+    assert!(image == render("killed-worker-reference", &arguments));
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use common::raytracer;
+use common::render;
+use common::temporary_file;
+
+// A render stopped by its timeout keeps the exact state of its pixels in the checkpoint,
+// so going on from there must give the image of a render that was never stopped
+#[test]
+fn resumed_renders_match_uninterrupted_ones() {
+    let plain: &[&str] = &["--samples", "16"];
+    let adaptive: &[&str] = &["--samples", "16", "--adaptive", "0.05", "--min-samples", "4"];
+    for (name, settings) in [("plain", plain), ("adaptive", adaptive)] {
+        let checkpoint = temporary_file(&format!("{}.checkpoint", name));
+        let checkpoint_path = checkpoint.to_str().unwrap();
+        let uninterrupted = render(&format!("{}-uninterrupted", name), settings);
+        let interrupted = render(&format!("{}-interrupted", name),
+            &[settings, &["--timeout", "0.3", "--checkpoint", checkpoint_path]].concat());
+        assert!(interrupted != uninterrupted, "the {} render finished before its timeout", name);
+        let resumed = render(&format!("{}-resumed", name), &[settings, &["--resume", checkpoint_path]].concat());
+        assert!(resumed == uninterrupted, "the resumed {} render gives another image", name);
+        // The finished render left nothing to do, so stopping it at once loses nothing
+        let finished = render(&format!("{}-finished", name), &[settings, &["--resume", checkpoint_path, "--timeout", "0.001"]].concat());
+        std::fs::remove_file(&checkpoint).unwrap();
+        assert!(finished == uninterrupted, "the finished {} render didn't keep its pixels", name);
+    }
+}
+
+#[test]
+fn checkpoints_of_other_settings_are_rejected() {
+    let checkpoint = temporary_file("other-settings.checkpoint");
+    let checkpoint_path = checkpoint.to_str().unwrap();
+    render("other-settings", &["--checkpoint", checkpoint_path]);
+    let output = temporary_file("other-settings-resumed.bmp");
+    let result = raytracer()
+        .args(["scenes/cornell.scene", "--quiet", "--width", "48", "--samples", "8", "--seed", "7", "--output"])
+        .arg(&output)
+        .args(["--resume", checkpoint_path])
+        .output()
+        .unwrap();
+    std::fs::remove_file(&checkpoint).unwrap();
+    assert!(!result.status.success(), "the raytracer resumed a render of 4 samples with 8");
+    let stderr = String::from_utf8_lossy(&result.stderr);
+    assert!(stderr.contains("was saved by a render with other settings"), "{}", stderr);
+    assert!(!output.exists());
+}